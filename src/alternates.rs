@@ -0,0 +1,329 @@
+//! Sharing objects between repositories with a common history (e.g. forks of
+//! the same project) using a shared object store and git's alternates
+//! mechanism, so that history is only saved once.
+//!
+//! Every repository's refs are copied into the store under
+//! `refs/backups/<dest-dir>/`, which keeps the objects a repository borrows
+//! reachable even after they've been removed from the repository itself.
+//! That means deleting one repository from the backup root never breaks the
+//! others, and its objects only disappear from the store once nothing else
+//! needs them.
+
+use crate::bundle;
+use crate::git;
+use failure::{Error, ResultExt};
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+/// Where each repository's refs are kept in the shared store.
+const NAMESPACE: &str = "refs/backups/";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AlternatesConfig {
+    /// Should repositories borrow objects from a shared object store?
+    pub enabled: bool,
+    /// The shared object store (a bare repository), relative to the backup
+    /// root.
+    pub store: PathBuf,
+}
+
+impl AlternatesConfig {
+    pub const DESCRIPTION: &'static str =
+        "Store objects shared by several repositories (e.g. forks) only once.";
+    pub const FIELDS: &'static [(&'static str, &'static str)] = &[
+        (
+            "enabled",
+            "Should repositories borrow objects from a shared object store \
+             (using \"git clone --reference\" and alternates)? Disabling this \
+             later copies the borrowed objects back into each repository.",
+        ),
+        (
+            "store",
+            "The shared object store, a bare repository relative to the root.",
+        ),
+    ];
+}
+
+impl Default for AlternatesConfig {
+    fn default() -> AlternatesConfig {
+        AlternatesConfig {
+            enabled: false,
+            store: PathBuf::from(".repo-backup/objects.git"),
+        }
+    }
+}
+
+fn store_path(cfg: &AlternatesConfig, root: &Path) -> Result<PathBuf, Error> {
+    bundle::absolute(&root.join(&cfg.store))
+}
+
+fn store_exists(store: &Path) -> bool {
+    store.join("objects").is_dir()
+}
+
+/// Get the shared object store, creating it if necessary.
+pub(crate) fn ensure_store(cfg: &AlternatesConfig, root: &Path) -> Result<PathBuf, Error> {
+    let store = store_path(cfg, root)?;
+
+    if !store_exists(&store) {
+        fs::create_dir_all(&store)
+            .with_context(|_| format!("Unable to create \"{}\"", store.display()))?;
+        git::init_bare(&store)?;
+    }
+
+    Ok(store)
+}
+
+/// Copy a repository's objects into the shared store and make sure the
+/// repository borrows from it.
+///
+/// Objects which are already in the store get removed from the repository
+/// when it is first attached or `just_cloned` (later fetches only add a
+/// handful of objects, so it isn't worth repacking every time). Returns
+/// whether the repository was repacked.
+pub(crate) fn share(
+    cfg: &AlternatesConfig,
+    root: &Path,
+    dest_dir: &Path,
+    just_cloned: bool,
+) -> Result<bool, Error> {
+    let store = ensure_store(cfg, root)?;
+    let repo_dir = bundle::absolute(&root.join(dest_dir))?;
+
+    let refspec = format!("+refs/*:{}{}/*", NAMESPACE, encode(dest_dir));
+    git::fetch_refs(&store, &repo_dir, &refspec)?;
+
+    let objects = store.join("objects");
+    let mut alternates = read_alternates(&repo_dir)?;
+    let attached = alternates.iter().any(|alt| same_file(alt, &objects));
+
+    if !attached {
+        alternates.push(objects);
+        write_alternates(&repo_dir, &alternates)?;
+    }
+
+    if attached && !just_cloned {
+        return Ok(false);
+    }
+
+    git::repack(&repo_dir, true)?;
+    Ok(true)
+}
+
+/// Stop a repository from borrowing objects from the shared store, copying
+/// everything it needs back into the repository first. Returns whether the
+/// repository was using the store.
+pub(crate) fn dissociate(
+    cfg: &AlternatesConfig,
+    root: &Path,
+    dest_dir: &Path,
+) -> Result<bool, Error> {
+    let objects = store_path(cfg, root)?.join("objects");
+    let repo_dir = root.join(dest_dir);
+
+    let (ours, others): (Vec<_>, Vec<_>) = read_alternates(&repo_dir)?
+        .into_iter()
+        .partition(|alt| same_file(alt, &objects));
+
+    if ours.is_empty() {
+        return Ok(false);
+    }
+
+    git::repack(&repo_dir, false)?;
+    write_alternates(&repo_dir, &others)?;
+
+    Ok(true)
+}
+
+/// Forget about repositories which have been removed from the backup root,
+/// then let git throw away any objects nobody uses anymore. Returns the
+/// repositories which were forgotten.
+pub(crate) fn prune_store(cfg: &AlternatesConfig, root: &Path) -> Result<Vec<PathBuf>, Error> {
+    let store = store_path(cfg, root)?;
+    let mut forgotten = BTreeSet::new();
+
+    if !store_exists(&store) {
+        return Ok(Vec::new());
+    }
+
+    for name in git::ref_names(&store, NAMESPACE)? {
+        let dest_dir = match repo_for_ref(&name) {
+            Some(d) => d,
+            None => continue,
+        };
+
+        if !root.join(&dest_dir).join(".git").is_dir() {
+            git::delete_ref(&store, &name)?;
+            forgotten.insert(dest_dir);
+        }
+    }
+
+    if !forgotten.is_empty() {
+        git::gc(&store, true)?;
+    }
+
+    Ok(forgotten.into_iter().collect())
+}
+
+fn alternates_file(repo_dir: &Path) -> PathBuf {
+    repo_dir
+        .join(".git")
+        .join("objects")
+        .join("info")
+        .join("alternates")
+}
+
+/// Read the object directories a repository borrows from.
+fn read_alternates(repo_dir: &Path) -> Result<Vec<PathBuf>, Error> {
+    let path = alternates_file(repo_dir);
+
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+
+    let contents = fs::read_to_string(&path)
+        .with_context(|_| format!("Unable to read \"{}\"", path.display()))?;
+    let objects_dir = repo_dir.join(".git").join("objects");
+
+    Ok(contents
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        // relative entries are relative to the objects directory
+        .map(|line| objects_dir.join(line))
+        .collect())
+}
+
+fn write_alternates(repo_dir: &Path, alternates: &[PathBuf]) -> Result<(), Error> {
+    let path = alternates_file(repo_dir);
+
+    if alternates.is_empty() {
+        if path.exists() {
+            fs::remove_file(&path)
+                .with_context(|_| format!("Unable to remove \"{}\"", path.display()))?;
+        }
+        return Ok(());
+    }
+
+    let mut contents = String::new();
+    for alt in alternates {
+        contents.push_str(&alt.display().to_string());
+        contents.push('\n');
+    }
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|_| format!("Unable to create \"{}\"", parent.display()))?;
+    }
+    fs::write(&path, contents)
+        .with_context(|_| format!("Unable to write to \"{}\"", path.display()))?;
+
+    Ok(())
+}
+
+fn same_file(left: &Path, right: &Path) -> bool {
+    match (fs::canonicalize(left), fs::canonicalize(right)) {
+        (Ok(l), Ok(r)) => l == r,
+        _ => left == right,
+    }
+}
+
+/// Turn a repository's path into a single ref name component (slashes would
+/// make it impossible to tell where the path ends).
+fn encode(dest_dir: &Path) -> String {
+    let path: Vec<_> = dest_dir
+        .components()
+        .filter_map(|c| match c {
+            Component::Normal(n) => Some(n.to_string_lossy()),
+            _ => None,
+        })
+        .collect();
+
+    path.join("/").replace('%', "%25").replace('/', "%2F")
+}
+
+fn decode(component: &str) -> Option<PathBuf> {
+    let mut decoded = String::new();
+    let mut rest = component;
+
+    while let Some(ix) = rest.find('%') {
+        decoded.push_str(&rest[..ix]);
+        match rest.get(ix + 1..ix + 3) {
+            Some("25") => decoded.push('%'),
+            Some("2F") => decoded.push('/'),
+            _ => return None,
+        }
+        rest = &rest[ix + 3..];
+    }
+    decoded.push_str(rest);
+
+    Some(PathBuf::from(decoded))
+}
+
+/// Figure out which repository a ref in the shared store belongs to.
+fn repo_for_ref(name: &str) -> Option<PathBuf> {
+    if !name.starts_with(NAMESPACE) {
+        return None;
+    }
+
+    let component = name[NAMESPACE.len()..].split('/').next()?;
+    decode(component)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repository_paths_round_trip_through_ref_names() {
+        let inputs = vec!["github.com/foo/bar", "github.com/foo/bar.wiki", "a%2Fb/c"];
+
+        for input in inputs {
+            let name = format!("{}{}/heads/master", NAMESPACE, encode(Path::new(input)));
+
+            assert_eq!(repo_for_ref(&name).unwrap(), Path::new(input));
+        }
+
+        assert!(repo_for_ref("refs/heads/master").is_none());
+    }
+
+    #[test]
+    fn share_objects_and_then_dissociate() {
+        require_program!("git");
+
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path();
+        let cfg = AlternatesConfig {
+            enabled: true,
+            ..Default::default()
+        };
+        let first = Path::new("github.com/first/repo-backup");
+        let second = Path::new("github.com/second/repo-backup");
+        let store = ensure_store(&cfg, root).unwrap();
+
+        for dest_dir in &[first, second] {
+            git::do_clone(
+                &root.join(dest_dir),
+                env!("CARGO_MANIFEST_DIR"),
+                Some(&store),
+            )
+            .unwrap();
+            assert!(share(&cfg, root, dest_dir, true).unwrap());
+        }
+        assert!(!share(&cfg, root, second, false).unwrap());
+
+        // removing a repository must not break the ones borrowing from the
+        // store
+        fs::remove_dir_all(root.join(first)).unwrap();
+        let forgotten = prune_store(&cfg, root).unwrap();
+        assert_eq!(forgotten, vec![first.to_path_buf()]);
+        assert!(!git::refs(&root.join(second)).unwrap().is_empty());
+
+        assert!(dissociate(&cfg, root, second).unwrap());
+        assert!(!alternates_file(&root.join(second)).exists());
+        fs::remove_dir_all(&store).unwrap();
+        git::fsck(&root.join(second)).unwrap();
+    }
+}
@@ -0,0 +1,377 @@
+//! Packing each repository into a compressed tarball which is encrypted for
+//! one or more [age] recipients, so archives can be kept on storage you don't
+//! trust.
+//!
+//! Only the archives are encrypted. Everything else under the backup root
+//! (checkouts, bundles, snapshots, etc.) is stored in plain text.
+//!
+//! [age]: https://age-encryption.org/
+
+use crate::bundle::{self, BundleConfig};
+use crate::git;
+use crate::state::RepoState;
+use failure::{Error, ResultExt};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::de::{Deserialize, Deserializer, Error as DeError};
+use serde::ser::{Serialize, Serializer};
+use std::fmt::{self, Debug, Formatter};
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ArchiveConfig {
+    /// Should an encrypted archive be created for each repository?
+    pub enabled: bool,
+    /// Where archives are saved, relative to the backup root.
+    pub dir: PathBuf,
+    /// The public keys archives are encrypted for.
+    pub recipients: Vec<Recipient>,
+    /// What should go in each archive.
+    pub input: ArchiveInput,
+}
+
+impl ArchiveConfig {
+    pub const DESCRIPTION: &'static str =
+        "Pack each repository into a compressed tarball encrypted with age.";
+    pub const FIELDS: &'static [(&'static str, &'static str)] = &[
+        (
+            "enabled",
+            "Should an encrypted archive be created for each repository?",
+        ),
+        (
+            "dir",
+            "Where archives are saved (relative to the root, or an absolute \
+             path). Each archive mirrors its repository's backup path, e.g. \
+             \"archives/github.com/foo/bar.tar.gz.age\". Nothing else under \
+             the root is encrypted, so only this directory is safe to keep on \
+             untrusted storage.",
+        ),
+        (
+            "recipients",
+            "The age public keys (\"age1...\") archives are encrypted for. Any \
+             of the matching private keys can decrypt them.",
+        ),
+        (
+            "input",
+            "What to archive, either \"repository\" (the whole checkout) or \
+             \"bundle\" (the repository's git bundle, which requires \
+             [general.bundle] to be enabled). Repositories which borrow objects \
+             via [general.alternates] can only be archived as bundles.",
+        ),
+    ];
+}
+
+impl Default for ArchiveConfig {
+    fn default() -> ArchiveConfig {
+        ArchiveConfig {
+            enabled: false,
+            dir: PathBuf::from("archives"),
+            recipients: Vec::new(),
+            input: ArchiveInput::Repository,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ArchiveInput {
+    Repository,
+    Bundle,
+}
+
+/// An age X25519 public key.
+#[derive(Clone)]
+pub struct Recipient {
+    source: String,
+    key: age::x25519::Recipient,
+}
+
+impl Recipient {
+    pub fn new(source: &str) -> Result<Recipient, Error> {
+        let key = source
+            .trim()
+            .parse::<age::x25519::Recipient>()
+            .map_err(failure::err_msg)?;
+
+        Ok(Recipient {
+            source: source.trim().to_string(),
+            key,
+        })
+    }
+}
+
+impl PartialEq for Recipient {
+    fn eq(&self, other: &Recipient) -> bool {
+        self.source == other.source
+    }
+}
+
+impl Debug for Recipient {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Recipient").field(&self.source).finish()
+    }
+}
+
+impl Serialize for Recipient {
+    fn serialize<S: Serializer>(&self, ser: S) -> Result<S::Ok, S::Error> {
+        self.source.serialize(ser)
+    }
+}
+
+impl<'de> Deserialize<'de> for Recipient {
+    fn deserialize<D: Deserializer<'de>>(de: D) -> Result<Recipient, D::Error> {
+        let source = String::deserialize(de)?;
+        Recipient::new(&source)
+            .map_err(|e| D::Error::custom(format!("invalid recipient \"{}\": {}", source, e)))
+    }
+}
+
+/// Where the archive for a repository should be saved.
+pub(crate) fn archive_path(cfg: &ArchiveConfig, root: &Path, dest_dir: &Path) -> PathBuf {
+    let archive = root.join(&cfg.dir).join(dest_dir);
+
+    let mut name = archive
+        .file_name()
+        .map(|n| n.to_os_string())
+        .unwrap_or_default();
+    name.push(".tar.gz.age");
+
+    archive.with_file_name(name)
+}
+
+/// Create an encrypted archive for the repository at `dest_dir`, returning
+/// the archive's location if a new one was created.
+///
+/// Archives are only recreated when something changed. When archiving
+/// bundles, `bundle_changed` says whether the bundle was recreated during this
+/// run, otherwise the repository's refs are compared with the ones it had when
+/// it was last archived.
+pub(crate) fn export(
+    cfg: &ArchiveConfig,
+    bundle_cfg: &BundleConfig,
+    root: &Path,
+    dest_dir: &Path,
+    bundle_changed: bool,
+) -> Result<Option<PathBuf>, Error> {
+    if cfg.recipients.is_empty() {
+        return Err(failure::err_msg(
+            "Refusing to create an archive without any recipients",
+        ));
+    }
+
+    let archive = archive_path(cfg, root, dest_dir);
+    let name = dest_dir
+        .file_name()
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("repo"));
+
+    let (input, refs) = match cfg.input {
+        ArchiveInput::Repository => {
+            let repo_dir = root.join(dest_dir);
+            if repo_dir.join(".git/objects/info/alternates").exists() {
+                return Err(failure::err_msg(
+                    "The repository borrows objects from elsewhere so its archive \
+                     would be incomplete, archive the bundle instead",
+                ));
+            }
+
+            let refs = git::refs(&repo_dir)?;
+            if archive.exists() && RepoState::load(root, dest_dir)?.archived_refs == refs {
+                return Ok(None);
+            }
+
+            (Input::Directory(repo_dir), Some(refs))
+        }
+        ArchiveInput::Bundle => {
+            if !bundle_cfg.enabled {
+                return Err(failure::err_msg(
+                    "Archiving bundles requires [general.bundle] to be enabled",
+                ));
+            }
+            if !bundle_changed && archive.exists() {
+                return Ok(None);
+            }
+
+            (
+                Input::File(bundle::bundle_path(bundle_cfg, root, dest_dir)),
+                None,
+            )
+        }
+    };
+
+    let mut partial = archive.clone().into_os_string();
+    partial.push(".part");
+    let partial = PathBuf::from(partial);
+
+    if let Some(parent) = archive.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|_| format!("Unable to create \"{}\"", parent.display()))?;
+    }
+
+    if let Err(e) = write_archive(&cfg.recipients, &input, &name, &partial) {
+        let _ = fs::remove_file(&partial);
+        return Err(e);
+    }
+
+    fs::rename(&partial, &archive)
+        .with_context(|_| format!("Unable to save \"{}\"", archive.display()))?;
+
+    if let Some(refs) = refs {
+        let mut state = RepoState::load(root, dest_dir)?;
+        state.archived_refs = refs;
+        state.save(root, dest_dir)?;
+    }
+
+    Ok(Some(archive))
+}
+
+enum Input {
+    Directory(PathBuf),
+    File(PathBuf),
+}
+
+/// Write a gzipped tarball containing `input` (saved as `name`), encrypting
+/// it on the fly so the plaintext never touches the disk.
+fn write_archive(
+    recipients: &[Recipient],
+    input: &Input,
+    name: &Path,
+    dest: &Path,
+) -> Result<(), Error> {
+    let f =
+        File::create(dest).with_context(|_| format!("Unable to create \"{}\"", dest.display()))?;
+
+    let keys = recipients
+        .iter()
+        .map(|r| Box::new(r.key.clone()) as Box<dyn age::Recipient>)
+        .collect();
+    let encrypted = age::Encryptor::with_recipients(keys)
+        .wrap_output(f)
+        .context("Unable to start encrypting")?;
+
+    let mut tarball = tar::Builder::new(GzEncoder::new(encrypted, Compression::default()));
+
+    let added = match *input {
+        Input::Directory(ref dir) => tarball.append_dir_all(name, dir),
+        Input::File(ref path) => {
+            let mut file_name = name.as_os_str().to_os_string();
+            file_name.push(".bundle");
+            tarball.append_path_with_name(path, file_name)
+        }
+    };
+    added.context("Unable to add to the archive")?;
+
+    let compressed = tarball
+        .into_inner()
+        .context("Unable to finish the archive")?;
+    let encrypted = compressed
+        .finish()
+        .context("Unable to finish compressing")?;
+    let f = encrypted.finish().context("Unable to finish encrypting")?;
+    f.sync_all()
+        .with_context(|_| format!("Unable to flush \"{}\"", dest.display()))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use age::x25519::Identity;
+    use flate2::read::GzDecoder;
+    use std::iter;
+
+    #[test]
+    fn invalid_recipients_are_rejected() {
+        let got: Result<ArchiveConfig, _> = toml::from_str("recipients = ['ssh-rsa AAAA']");
+
+        assert!(got.is_err());
+    }
+
+    #[test]
+    fn archives_mirror_the_backup_layout() {
+        let cfg = ArchiveConfig::default();
+
+        let got = archive_path(&cfg, Path::new("/srv"), Path::new("github.com/foo/bar"));
+
+        assert_eq!(
+            got,
+            Path::new("/srv/archives/github.com/foo/bar.tar.gz.age")
+        );
+    }
+
+    #[test]
+    fn encrypt_and_decrypt_a_repository() {
+        require_program!("git");
+
+        let temp = tempfile::tempdir().unwrap();
+        let repo = temp.path().join("github.com").join("foo").join("bar");
+        fs::create_dir_all(&repo).unwrap();
+        fs::write(repo.join("README.md"), "Hello, World!").unwrap();
+
+        let identity = Identity::generate();
+        let recipient = Recipient::new(&identity.to_public().to_string()).unwrap();
+        let cfg = ArchiveConfig {
+            enabled: true,
+            recipients: vec![recipient],
+            ..Default::default()
+        };
+
+        let archive = export(
+            &cfg,
+            &BundleConfig::default(),
+            temp.path(),
+            Path::new("github.com/foo/bar"),
+            false,
+        )
+        .unwrap()
+        .unwrap();
+
+        let decryptor = match age::Decryptor::new(File::open(&archive).unwrap()).unwrap() {
+            age::Decryptor::Recipients(d) => d,
+            _ => unreachable!(),
+        };
+        let reader = decryptor
+            .decrypt(iter::once(&identity as &dyn age::Identity))
+            .unwrap();
+        let mut tarball = tar::Archive::new(GzDecoder::new(reader));
+        let names: Vec<PathBuf> = tarball
+            .entries()
+            .unwrap()
+            .map(|entry| entry.unwrap().path().unwrap().into_owned())
+            .collect();
+        assert!(names.contains(&PathBuf::from("bar/README.md")));
+    }
+
+    #[test]
+    fn only_archive_a_repository_when_its_refs_change() {
+        require_program!("git");
+
+        let temp = tempfile::tempdir().unwrap();
+        let dest_dir = Path::new("github.com/foo/bar");
+        fs::create_dir_all(temp.path().join(dest_dir)).unwrap();
+        let identity = Identity::generate();
+        let cfg = ArchiveConfig {
+            enabled: true,
+            recipients: vec![Recipient::new(&identity.to_public().to_string()).unwrap()],
+            ..Default::default()
+        };
+        let bundle_cfg = BundleConfig::default();
+
+        let first = export(&cfg, &bundle_cfg, temp.path(), dest_dir, false).unwrap();
+        assert!(first.is_some());
+        let unchanged = export(&cfg, &bundle_cfg, temp.path(), dest_dir, false).unwrap();
+        assert!(unchanged.is_none());
+
+        let mut state = RepoState::load(temp.path(), dest_dir).unwrap();
+        state
+            .archived_refs
+            .insert(String::from("0123456789 refs/heads/deleted"));
+        state.save(temp.path(), dest_dir).unwrap();
+
+        let changed = export(&cfg, &bundle_cfg, temp.path(), dest_dir, false).unwrap();
+        assert!(changed.is_some());
+    }
+}
@@ -2,10 +2,18 @@ use repo_backup;
 use shellexpand;
 #[macro_use]
 extern crate slog;
+use serde_json;
 use slog_async;
 use slog_term;
 use structopt;
 
+use chrono::Utc;
+use failure::Error;
+use repo_backup::filter::Pattern;
+use repo_backup::restore::RestoreOptions;
+use repo_backup::status::StatusFilter;
+use repo_backup::verify::Report;
+use repo_backup::Listed;
 use slog::{Drain, Level, Logger};
 use std::path::PathBuf;
 use std::process;
@@ -15,7 +23,31 @@ fn main() {
     let args = Args::from_args();
     let logger = initialize_logging(&args);
 
-    if let Err(e) = repo_backup::run(args.config_file(), &logger) {
+    if args.example_config {
+        match repo_backup::example_config() {
+            Ok(example) => {
+                print!("{}", example);
+                return;
+            }
+            Err(e) => {
+                eprintln!("Unable to generate an example config: {}", e);
+                process::exit(1);
+            }
+        }
+    }
+
+    let outcome = match args.cmd {
+        Some(Command::CheckConfig) => check_config(&args),
+        Some(Command::Snapshots) => list_snapshots(&args),
+        Some(Command::Restore(ref restore_args)) => restore(&args, restore_args, &logger),
+        Some(Command::Verify(ref verify_args)) => verify(&args, verify_args, &logger),
+        Some(Command::Status(ref status_args)) => status(&args, status_args),
+        Some(Command::List(ref list_args)) => list(&args, list_args, &logger),
+        Some(Command::Daemon) => repo_backup::daemon::daemon(args.config_file(), &logger),
+        None => repo_backup::run(args.config_file(), &logger),
+    };
+
+    if let Err(e) = outcome {
         error!(logger, "Error: {}", e);
         for cause in e.iter_causes() {
             warn!(logger, "Caused By: {}", cause);
@@ -42,8 +74,124 @@ pub struct Args {
         help = "Generate verbose output"
     )]
     verbosity: usize,
+    #[structopt(
+        long = "example-config",
+        help = "Print an example config file and exit"
+    )]
+    example_config: bool,
     #[structopt(help = "The config file", default_value = "~/.repo-backup.toml")]
     config: String,
+    #[structopt(subcommand)]
+    cmd: Option<Command>,
+}
+
+#[derive(Debug, Clone, StructOpt)]
+pub enum Command {
+    #[structopt(
+        name = "check-config",
+        about = "Check the config file for unknown sections, unknown fields and invalid values"
+    )]
+    CheckConfig,
+    #[structopt(name = "snapshots", about = "List the snapshots of every repository")]
+    Snapshots,
+    #[structopt(
+        name = "restore",
+        about = "Push backed up repositories to a new remote"
+    )]
+    Restore(RestoreArgs),
+    #[structopt(
+        name = "verify",
+        about = "Check every backed up repository for corrupt or missing objects"
+    )]
+    Verify(VerifyArgs),
+    #[structopt(
+        name = "status",
+        about = "Show when each repository was last backed up, and any errors"
+    )]
+    Status(StatusArgs),
+    #[structopt(
+        name = "list",
+        about = "List every repository the providers can see, without downloading anything"
+    )]
+    List(ListArgs),
+    #[structopt(
+        name = "daemon",
+        about = "Keep running, backing everything up on the schedule in [general.daemon]"
+    )]
+    Daemon,
+}
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct RestoreArgs {
+    #[structopt(
+        long = "repo",
+        help = "Only restore repositories matching this glob (or \"regex:...\"), e.g. \"github.com/foo/*\""
+    )]
+    repos: Vec<Pattern>,
+    #[structopt(
+        long = "remote",
+        help = "Where to push each repository, e.g. \"git@example.com:{owner}/{name}.git\" ({path} and {host} also work)"
+    )]
+    remote: Option<String>,
+    #[structopt(
+        long = "create",
+        help = "Create missing projects on this provider first (\"github\", \"gitlab\" or \"gitea\")"
+    )]
+    create: Option<String>,
+    #[structopt(
+        long = "owner",
+        help = "Push to this user, organisation or group instead"
+    )]
+    owner: Option<String>,
+    #[structopt(long = "private", help = "Make newly created projects private")]
+    private: bool,
+    #[structopt(
+        long = "prune",
+        help = "Delete branches and tags which only exist on the remote"
+    )]
+    prune: bool,
+    #[structopt(long = "dry-run", help = "Show where everything would be pushed")]
+    dry_run: bool,
+}
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct VerifyArgs {
+    #[structopt(
+        long = "repo",
+        help = "Only check repositories matching this glob (or \"regex:...\")"
+    )]
+    repos: Vec<Pattern>,
+    #[structopt(long = "json", help = "Print the report as JSON")]
+    json: bool,
+}
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct StatusArgs {
+    #[structopt(
+        long = "failed",
+        help = "Only show repositories whose last backup failed"
+    )]
+    failed: bool,
+    #[structopt(
+        long = "stale",
+        help = "Only show repositories which haven't been backed up in more than this many days"
+    )]
+    stale: Option<u64>,
+    #[structopt(long = "provider", help = "Only show repositories from this provider")]
+    provider: Option<String>,
+    #[structopt(long = "json", help = "Print the status as JSON")]
+    json: bool,
+}
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct ListArgs {
+    #[structopt(
+        long = "format",
+        default_value = "table",
+        raw(possible_values = r#"&["table", "json", "csv"]"#),
+        help = "How to print the list"
+    )]
+    format: String,
 }
 
 impl Args {
@@ -54,6 +202,304 @@ impl Args {
     }
 }
 
+fn check_config(args: &Args) -> Result<(), Error> {
+    let config_file = args.config_file();
+    let diagnostics = repo_backup::check_config(&config_file)?;
+
+    for diag in &diagnostics {
+        println!("{}: {}", config_file.display(), diag);
+    }
+
+    if diagnostics.is_empty() {
+        println!("{}: OK", config_file.display());
+        Ok(())
+    } else {
+        Err(failure::format_err!(
+            "Found {} problem(s) in the config",
+            diagnostics.len()
+        ))
+    }
+}
+
+fn list_snapshots(args: &Args) -> Result<(), Error> {
+    let cfg = repo_backup::load_config(args.config_file())?;
+    let snapshots = repo_backup::snapshot::list(&cfg.general.retention, &cfg.general.root)?;
+
+    let width = snapshots
+        .iter()
+        .map(|s| s.repo.display().to_string().len())
+        .max()
+        .unwrap_or(0)
+        .max("REPOSITORY".len());
+
+    println!(
+        "{:<width$}  {:<10}  {:>12}",
+        "REPOSITORY",
+        "DATE",
+        "SIZE",
+        width = width
+    );
+    for snapshot in &snapshots {
+        println!(
+            "{:<width$}  {}  {:>12}",
+            snapshot.repo.display().to_string(),
+            snapshot.date,
+            snapshot.size,
+            width = width
+        );
+    }
+
+    Ok(())
+}
+
+fn restore(args: &Args, restore_args: &RestoreArgs, logger: &Logger) -> Result<(), Error> {
+    let cfg = repo_backup::load_config(args.config_file())?;
+    let opts = RestoreOptions {
+        repos: restore_args.repos.clone(),
+        remote: restore_args.remote.clone(),
+        create: restore_args.create.clone(),
+        owner: restore_args.owner.clone(),
+        private: restore_args.private,
+        prune: restore_args.prune,
+        dry_run: restore_args.dry_run,
+    };
+
+    for restored in repo_backup::restore::restore(&cfg, &opts, logger)? {
+        println!("{} -> {}", restored.repo.display(), restored.url);
+    }
+
+    Ok(())
+}
+
+fn verify(args: &Args, verify_args: &VerifyArgs, logger: &Logger) -> Result<(), Error> {
+    let cfg = repo_backup::load_config(args.config_file())?;
+    let reports = repo_backup::verify::verify(&cfg, &verify_args.repos, logger)?;
+
+    if verify_args.json {
+        println!("{}", serde_json::to_string_pretty(&reports)?);
+    } else {
+        print_verify_report(&reports);
+    }
+
+    let unhealthy = reports.iter().filter(|r| !r.is_healthy()).count();
+
+    if unhealthy == 0 {
+        Ok(())
+    } else {
+        Err(failure::format_err!(
+            "Found problems in {} of {} repositories",
+            unhealthy,
+            reports.len()
+        ))
+    }
+}
+
+fn print_verify_report(reports: &[Report]) {
+    let width = reports
+        .iter()
+        .map(|r| r.repo.display().to_string().len())
+        .max()
+        .unwrap_or(0)
+        .max("REPOSITORY".len());
+
+    println!(
+        "{:<width$}  {:<7}  {:>7}  {:>7}  {:>8}  {:>8}  {:>3}",
+        "REPOSITORY",
+        "STATUS",
+        "CORRUPT",
+        "MISSING",
+        "DANGLING",
+        "WARNINGS",
+        "LFS",
+        width = width
+    );
+    for report in reports {
+        let status = if report.error.is_some() {
+            "ERROR"
+        } else if report.is_healthy() {
+            "OK"
+        } else {
+            "DAMAGED"
+        };
+
+        println!(
+            "{:<width$}  {:<7}  {:>7}  {:>7}  {:>8}  {:>8}  {:>3}",
+            report.repo.display().to_string(),
+            status,
+            report.corrupt.len(),
+            report.missing.len(),
+            report.dangling.len(),
+            report.warnings.len(),
+            report.lfs.len(),
+            width = width
+        );
+    }
+
+    for report in reports.iter().filter(|r| !r.is_healthy()) {
+        println!();
+        println!("{}:", report.repo.display());
+
+        let problems = report
+            .error
+            .iter()
+            .chain(&report.corrupt)
+            .chain(&report.missing)
+            .chain(&report.lfs);
+        for problem in problems {
+            println!("    {}", problem);
+        }
+    }
+}
+
+fn status(args: &Args, status_args: &StatusArgs) -> Result<(), Error> {
+    let cfg = repo_backup::load_config(args.config_file())?;
+    let filter = StatusFilter {
+        failed: status_args.failed,
+        stale_days: status_args.stale,
+        provider: status_args.provider.clone(),
+    };
+    let now = Utc::now();
+    let statuses = repo_backup::status::status(&cfg, &filter, now)?;
+
+    if status_args.json {
+        println!("{}", serde_json::to_string_pretty(&statuses)?);
+        return Ok(());
+    }
+
+    let width = statuses
+        .iter()
+        .map(|s| s.repo.display().to_string().len())
+        .max()
+        .unwrap_or(0)
+        .max("REPOSITORY".len());
+
+    println!(
+        "{:<width$}  {:<8}  {:<20}  {:>6}  {:>12}  {}",
+        "REPOSITORY",
+        "PROVIDER",
+        "LAST SUCCESS",
+        "AGE",
+        "SIZE",
+        "LAST ERROR",
+        width = width
+    );
+    for status in &statuses {
+        let last_success = status
+            .last_success
+            .map(|when| when.format("%Y-%m-%d %H:%M:%S").to_string())
+            .unwrap_or_else(|| String::from("never"));
+        let age = status
+            .age(now)
+            .map(format_age)
+            .unwrap_or_else(|| String::from("-"));
+        // the first line is normally enough to see what went wrong
+        let last_error = status
+            .last_error
+            .as_ref()
+            .and_then(|e| e.lines().next())
+            .unwrap_or("");
+
+        println!(
+            "{:<width$}  {:<8}  {:<20}  {:>6}  {:>12}  {}",
+            status.repo.display().to_string(),
+            status.provider.as_deref().unwrap_or("-"),
+            last_success,
+            age,
+            status.size,
+            last_error,
+            width = width
+        );
+    }
+
+    Ok(())
+}
+
+/// Format a duration the way a human would say it (e.g. `3d` or `5h`).
+fn format_age(age: chrono::Duration) -> String {
+    if age.num_days() > 0 {
+        format!("{}d", age.num_days())
+    } else if age.num_hours() > 0 {
+        format!("{}h", age.num_hours())
+    } else {
+        format!("{}m", age.num_minutes().max(0))
+    }
+}
+
+fn list(args: &Args, list_args: &ListArgs, logger: &Logger) -> Result<(), Error> {
+    let cfg = repo_backup::load_config(args.config_file())?;
+    let listed = repo_backup::list(&cfg, logger)?;
+
+    match list_args.format.as_str() {
+        "json" => println!("{}", serde_json::to_string_pretty(&listed)?),
+        "csv" => {
+            println!("provider,url,destination,decision,reason");
+            for item in &listed {
+                let fields = [
+                    item.provider.clone(),
+                    item.url.clone(),
+                    item.dest_dir.display().to_string(),
+                    item.decision.as_str().to_string(),
+                    item.reason.clone().unwrap_or_default(),
+                ];
+                let fields: Vec<_> = fields.iter().map(|f| csv_field(f)).collect();
+                println!("{}", fields.join(","));
+            }
+        }
+        _ => print_list(&listed),
+    }
+
+    Ok(())
+}
+
+fn print_list(listed: &[Listed]) {
+    let provider_width = column_width(listed, "PROVIDER", |l| l.provider.len());
+    let url_width = column_width(listed, "URL", |l| l.url.len());
+    let dest_width = column_width(listed, "DESTINATION", |l| {
+        l.dest_dir.display().to_string().len()
+    });
+
+    println!(
+        "{:<pw$}  {:<uw$}  {:<dw$}  {:<9}  {}",
+        "PROVIDER",
+        "URL",
+        "DESTINATION",
+        "DECISION",
+        "REASON",
+        pw = provider_width,
+        uw = url_width,
+        dw = dest_width
+    );
+    for item in listed {
+        println!(
+            "{:<pw$}  {:<uw$}  {:<dw$}  {:<9}  {}",
+            item.provider,
+            item.url,
+            item.dest_dir.display().to_string(),
+            item.decision.as_str(),
+            item.reason.as_deref().unwrap_or(""),
+            pw = provider_width,
+            uw = url_width,
+            dw = dest_width
+        );
+    }
+}
+
+fn column_width<F>(listed: &[Listed], header: &str, get: F) -> usize
+where
+    F: Fn(&Listed) -> usize,
+{
+    listed.iter().map(get).max().unwrap_or(0).max(header.len())
+}
+
+/// Quote a CSV field if it needs it.
+fn csv_field(field: &str) -> String {
+    if field.contains(|c: char| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
 fn initialize_logging(args: &Args) -> Logger {
     let level = match args.verbosity {
         0 => Level::Warning,
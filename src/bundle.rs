@@ -0,0 +1,147 @@
+//! Exporting each repository as a single-file `git bundle`, which is a lot
+//! easier to archive than a directory full of loose objects.
+
+use crate::git;
+use failure::{Error, ResultExt};
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BundleConfig {
+    /// Should a bundle be created for each repository?
+    pub enabled: bool,
+    /// Where bundles are saved, relative to the backup root.
+    pub dir: PathBuf,
+    /// Only recreate a bundle when the repository's refs have changed.
+    pub only_when_changed: bool,
+    /// Check each bundle with `git bundle verify` after it's created.
+    pub verify: bool,
+}
+
+impl BundleConfig {
+    pub const DESCRIPTION: &'static str =
+        "Export each repository as a single-file git bundle, for offline archival.";
+    pub const FIELDS: &'static [(&'static str, &'static str)] = &[
+        ("enabled", "Should a bundle be created for each repository?"),
+        (
+            "dir",
+            "Where bundles are saved (relative to the root). Each repository's \
+             bundle mirrors its backup path, e.g. \"bundles/github.com/foo/bar.bundle\".",
+        ),
+        (
+            "only_when_changed",
+            "Only recreate a bundle when the repository's branches or tags have \
+             changed since it was last created.",
+        ),
+        (
+            "verify",
+            "Check each new bundle with \"git bundle verify\" before replacing \
+             the previous one.",
+        ),
+    ];
+}
+
+impl Default for BundleConfig {
+    fn default() -> BundleConfig {
+        BundleConfig {
+            enabled: false,
+            dir: PathBuf::from("bundles"),
+            only_when_changed: true,
+            verify: true,
+        }
+    }
+}
+
+/// Where the bundle for a repository should be saved.
+pub(crate) fn bundle_path(cfg: &BundleConfig, root: &Path, dest_dir: &Path) -> PathBuf {
+    let bundle = root.join(&cfg.dir).join(dest_dir);
+
+    let mut name = bundle
+        .file_name()
+        .map(|n| n.to_os_string())
+        .unwrap_or_default();
+    name.push(".bundle");
+
+    bundle.with_file_name(name)
+}
+
+/// Create a bundle for the repository at `dest_dir`, returning the bundle's
+/// location if a new one was created.
+pub(crate) fn export(
+    cfg: &BundleConfig,
+    root: &Path,
+    dest_dir: &Path,
+) -> Result<Option<PathBuf>, Error> {
+    let repo_dir = root.join(dest_dir);
+    let bundle = absolute(&bundle_path(cfg, root, dest_dir))?;
+
+    let refs = git::refs(&repo_dir)?;
+    if refs.is_empty() {
+        // git refuses to create empty bundles
+        return Ok(None);
+    }
+
+    if cfg.only_when_changed && bundle.exists() && git::bundle_refs(&repo_dir, &bundle)? == refs {
+        return Ok(None);
+    }
+
+    let mut partial = bundle.clone().into_os_string();
+    partial.push(".part");
+    let partial = PathBuf::from(partial);
+
+    if let Some(parent) = bundle.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|_| format!("Unable to create \"{}\"", parent.display()))?;
+    }
+
+    git::create_bundle(&repo_dir, &partial)?;
+
+    if cfg.verify {
+        if let Err(e) = git::verify_bundle(&repo_dir, &partial) {
+            let _ = fs::remove_file(&partial);
+            return Err(e);
+        }
+    }
+
+    fs::rename(&partial, &bundle)
+        .with_context(|_| format!("Unable to save \"{}\"", bundle.display()))?;
+
+    Ok(Some(bundle))
+}
+
+/// git commands are run from inside the repository, so relative paths won't
+/// work.
+pub(crate) fn absolute(path: &Path) -> Result<PathBuf, Error> {
+    if path.is_absolute() {
+        Ok(path.to_path_buf())
+    } else {
+        let cwd = std::env::current_dir().context("Unable to get the current directory")?;
+        Ok(cwd.join(path))
+    }
+}
+
+/// The set of `"<hash> <ref>"` lines describing a repository's refs.
+pub(crate) type Refs = BTreeSet<String>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bundles_mirror_the_backup_layout() {
+        let cfg = BundleConfig::default();
+
+        let got = bundle_path(
+            &cfg,
+            Path::new("/srv"),
+            Path::new("github.com/foo/bar.wiki"),
+        );
+
+        assert_eq!(
+            got,
+            Path::new("/srv/bundles/github.com/foo/bar.wiki.bundle")
+        );
+    }
+}
@@ -1,7 +1,23 @@
-use serde::de::{Deserialize, DeserializeOwned, Deserializer, Error as DeError};
+use crate::alternates::AlternatesConfig;
+use crate::archive::{ArchiveConfig, ArchiveInput};
+use crate::bundle::BundleConfig;
+use crate::daemon::DaemonConfig;
+use crate::filter::FilterConfig;
+use crate::maintenance::MaintenanceConfig;
+use crate::metrics::MetricsConfig;
+use crate::migrate::MigrateConfig;
+use crate::notify::NotifyConfig;
+use crate::snapshot::RetentionConfig;
+use crate::upload::UploadConfig;
+use crate::webhook::WebhookConfig;
+use serde::de::{
+    Deserialize, DeserializeOwned, DeserializeSeed, Deserializer, Error as DeError, IgnoredAny,
+    MapAccess, Visitor,
+};
 use serde::ser::{Error as SerError, Serialize, Serializer};
 use std::collections::BTreeMap;
 use std::fmt::{self, Display, Formatter};
+use std::marker::PhantomData;
 use std::path::PathBuf;
 use toml::Value;
 
@@ -40,7 +56,7 @@ impl Display for ConfigError {
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
-#[serde(default)]
+#[serde(default, deny_unknown_fields)]
 pub struct General {
     /// The top-level directory all backups should be placed in.
     pub root: PathBuf,
@@ -50,7 +66,101 @@ pub struct General {
     ///
     /// A threshold of `0` means there's no limit.
     pub error_threshold: usize,
+    /// Exact paths (relative to `root`) of repositories which shouldn't be
+    /// backed up.
     pub blacklist: Vec<PathBuf>,
+    /// Filters applied to every repository, regardless of its provider.
+    pub filter: FilterConfig,
+    /// Exporting each repository as a git bundle.
+    pub bundle: BundleConfig,
+    /// Packing each repository into an encrypted archive.
+    pub archive: ArchiveConfig,
+    /// Uploading bundles and archives to object storage.
+    pub upload: UploadConfig,
+    /// Keeping dated snapshots of each repository.
+    pub retention: RetentionConfig,
+    /// Sharing objects between repositories with a common history.
+    pub alternates: AlternatesConfig,
+    /// Mirroring every repository into another forge.
+    pub migrate: MigrateConfig,
+    /// Cleaning up repositories after they're fetched.
+    pub maintenance: MaintenanceConfig,
+    /// Running backups on a schedule.
+    pub daemon: DaemonConfig,
+    /// Backing up repositories as soon as they're pushed to.
+    pub webhook: WebhookConfig,
+    /// Exposing Prometheus metrics.
+    pub metrics: MetricsConfig,
+    /// Telling somebody when a backup fails.
+    pub notify: NotifyConfig,
+}
+
+impl General {
+    pub const KEY: &'static str = "general";
+
+    /// Settings which are fine on their own but can't be used together.
+    pub(crate) fn conflicts(&self) -> Vec<String> {
+        let mut conflicts = Vec::new();
+
+        if self.archive.enabled
+            && self.archive.input == ArchiveInput::Repository
+            && self.alternates.enabled
+        {
+            conflicts.push(String::from(
+                "archives can't be made from repositories which borrow objects \
+                 from the shared object store, either disable alternates or set \
+                 archive.input = \"bundle\"",
+            ));
+        }
+
+        conflicts
+    }
+}
+
+impl Documented for General {
+    const DESCRIPTION: &'static str = "Settings which apply to the entire backup.";
+    const FIELDS: Fields = &[
+        (
+            "root",
+            "The top-level directory all backups should be placed in.",
+        ),
+        ("threads", "How many repositories to download in parallel."),
+        (
+            "error_threshold",
+            "The maximum number of errors allowed before declaring the entire \
+             backup as failed. A threshold of 0 means there's no limit.",
+        ),
+        (
+            "blacklist",
+            "Repositories to skip, given as their path relative to the root.",
+        ),
+        ("filter", FilterConfig::DESCRIPTION),
+        ("bundle", BundleConfig::DESCRIPTION),
+        ("archive", ArchiveConfig::DESCRIPTION),
+        ("upload", UploadConfig::DESCRIPTION),
+        ("retention", RetentionConfig::DESCRIPTION),
+        ("alternates", AlternatesConfig::DESCRIPTION),
+        ("migrate", MigrateConfig::DESCRIPTION),
+        ("maintenance", MaintenanceConfig::DESCRIPTION),
+        ("daemon", DaemonConfig::DESCRIPTION),
+        ("webhook", WebhookConfig::DESCRIPTION),
+        ("metrics", MetricsConfig::DESCRIPTION),
+        ("notify", NotifyConfig::DESCRIPTION),
+    ];
+    const NESTED: &'static [(&'static str, Fields)] = &[
+        ("filter", FilterConfig::FIELDS),
+        ("bundle", BundleConfig::FIELDS),
+        ("archive", ArchiveConfig::FIELDS),
+        ("upload", UploadConfig::FIELDS),
+        ("retention", RetentionConfig::FIELDS),
+        ("alternates", AlternatesConfig::FIELDS),
+        ("migrate", MigrateConfig::FIELDS),
+        ("maintenance", MaintenanceConfig::FIELDS),
+        ("daemon", DaemonConfig::FIELDS),
+        ("webhook", WebhookConfig::FIELDS),
+        ("metrics", MetricsConfig::FIELDS),
+        ("notify", NotifyConfig::FIELDS),
+    ];
 }
 
 impl Default for General {
@@ -60,6 +170,18 @@ impl Default for General {
             threads: num_cpus::get(),
             error_threshold: 0,
             blacklist: Vec::new(),
+            filter: FilterConfig::default(),
+            bundle: BundleConfig::default(),
+            archive: ArchiveConfig::default(),
+            upload: UploadConfig::default(),
+            retention: RetentionConfig::default(),
+            alternates: AlternatesConfig::default(),
+            migrate: MigrateConfig::default(),
+            maintenance: MaintenanceConfig::default(),
+            daemon: DaemonConfig::default(),
+            webhook: WebhookConfig::default(),
+            metrics: MetricsConfig::default(),
+            notify: NotifyConfig::default(),
         }
     }
 }
@@ -89,6 +211,346 @@ impl<'de> Deserialize<'de> for Config {
     }
 }
 
+/// Descriptions for each field in a table, keyed by name.
+pub(crate) type Fields = &'static [(&'static str, &'static str)];
+
+/// A config section which knows how to describe itself, used when generating
+/// an example config.
+pub(crate) trait Documented: Default + Serialize {
+    /// A short description of the section as a whole.
+    const DESCRIPTION: &'static str;
+    /// A description of each field, keyed by its (dotted) path within the
+    /// section.
+    const FIELDS: Fields;
+    /// Descriptions for the fields of any nested tables which are shared
+    /// between sections, keyed by the nested table's name.
+    const NESTED: &'static [(&'static str, Fields)] = &[];
+}
+
+/// A top-level table in the config file.
+#[derive(Copy, Clone)]
+pub(crate) struct Section {
+    pub key: &'static str,
+    pub description: &'static str,
+    pub fields: Fields,
+    pub nested: &'static [(&'static str, Fields)],
+    check: fn(&str, &str) -> Result<(), toml::de::Error>,
+    example: fn() -> Result<Value, toml::ser::Error>,
+}
+
+impl Section {
+    pub fn new<T: DeserializeOwned + Documented>(key: &'static str) -> Section {
+        Section {
+            key,
+            description: T::DESCRIPTION,
+            fields: T::FIELDS,
+            nested: T::NESTED,
+            check: check_section::<T>,
+            example: || Value::try_from(T::default()),
+        }
+    }
+
+    /// Try to deserialize this section from the raw config file, if present.
+    pub fn check(&self, raw: &str) -> Result<(), toml::de::Error> {
+        (self.check)(raw, self.key)
+    }
+
+    /// The section's default value.
+    pub fn example(&self) -> Result<Value, toml::ser::Error> {
+        (self.example)()
+    }
+
+    /// Where a field appears in the section's list of descriptions, used to
+    /// keep the example config in the same order as the docs.
+    fn position(&self, path: &str) -> usize {
+        self.lookup(path)
+            .map(|(ix, _)| ix)
+            .unwrap_or(usize::max_value())
+    }
+
+    /// Look up the description for a field.
+    pub fn describe(&self, path: &str) -> Option<&'static str> {
+        self.lookup(path).map(|(_, description)| description)
+    }
+
+    fn lookup(&self, path: &str) -> Option<(usize, &'static str)> {
+        if let Some(found) = find_field(self.fields, path) {
+            return Some(found);
+        }
+
+        let dot = path.find('.')?;
+        let (table, field) = (&path[..dot], &path[dot + 1..]);
+
+        self.nested
+            .iter()
+            .find(|(name, _)| *name == table)
+            .and_then(|(_, fields)| find_field(fields, field))
+    }
+}
+
+fn find_field(fields: Fields, name: &str) -> Option<(usize, &'static str)> {
+    fields
+        .iter()
+        .position(|(field, _)| *field == name)
+        .map(|ix| (ix, fields[ix].1))
+}
+
+/// Generate a commented example config file from each section's default
+/// value.
+pub(crate) fn example(sections: &[Section]) -> Result<String, toml::ser::Error> {
+    let mut buffer = String::new();
+
+    for section in sections {
+        if !buffer.is_empty() {
+            buffer.push('\n');
+        }
+
+        write_comment(&mut buffer, section.description);
+
+        match section.example()? {
+            Value::Table(table) => write_table(&mut buffer, section, section.key, "", &table),
+            other => {
+                buffer.push_str(&format!("{} = {}\n", section.key, other));
+            }
+        }
+    }
+
+    Ok(buffer)
+}
+
+fn write_table(
+    buffer: &mut String,
+    section: &Section,
+    header: &str,
+    path: &str,
+    table: &toml::value::Table,
+) {
+    buffer.push_str(&format!("[{}]\n", header));
+
+    let (mut tables, mut values): (Vec<_>, Vec<_>) = table.iter().partition(|(_, v)| v.is_table());
+    values.sort_by_key(|(key, _)| section.position(&join_path(path, key)));
+    tables.sort_by_key(|(key, _)| section.position(&join_path(path, key)));
+
+    for (key, value) in values {
+        let path = join_path(path, key);
+        if let Some(description) = section.describe(&path) {
+            write_comment(buffer, description);
+        }
+        buffer.push_str(&format!("{} = {}\n", key, value));
+    }
+
+    for (key, value) in tables {
+        let path = join_path(path, key);
+        let header = format!("{}.{}", header, key);
+
+        buffer.push('\n');
+        if let Some(description) = section.describe(&path) {
+            write_comment(buffer, description);
+        }
+        if let Value::Table(ref table) = *value {
+            write_table(buffer, section, &header, &path, table);
+        }
+    }
+}
+
+fn join_path(parent: &str, key: &str) -> String {
+    if parent.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}.{}", parent, key)
+    }
+}
+
+/// Write a comment, wrapping long lines.
+fn write_comment(buffer: &mut String, text: &str) {
+    const WIDTH: usize = 78;
+    let mut line = String::from("#");
+
+    for word in text.split_whitespace() {
+        if line.len() + word.len() + 1 > WIDTH && line.len() > 1 {
+            buffer.push_str(&line);
+            buffer.push('\n');
+            line = String::from("#");
+        }
+        line.push(' ');
+        line.push_str(word);
+    }
+
+    buffer.push_str(&line);
+    buffer.push('\n');
+}
+
+/// Get the paths of any fields in a section's default value which don't
+/// have a description.
+#[cfg(test)]
+pub(crate) fn undocumented_fields(section: &Section) -> Vec<String> {
+    fn visit(section: &Section, path: &str, table: &toml::value::Table, missing: &mut Vec<String>) {
+        for (key, value) in table {
+            let path = join_path(path, key);
+            if section.describe(&path).is_none() {
+                missing.push(path.clone());
+            }
+            if let Value::Table(ref nested) = *value {
+                visit(section, &path, nested, missing);
+            }
+        }
+    }
+
+    let mut missing = Vec::new();
+    if let Ok(Value::Table(table)) = section.example() {
+        visit(section, "", &table, &mut missing);
+    }
+    missing
+}
+
+/// A problem found while checking a config file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    /// The section this diagnostic is about, if it could be attributed to one.
+    pub section: Option<String>,
+    pub message: String,
+    /// The (zero-based) line and column the problem was found at.
+    pub line_col: Option<(usize, usize)>,
+}
+
+impl Diagnostic {
+    fn from_toml(section: Option<&str>, err: &toml::de::Error) -> Diagnostic {
+        Diagnostic {
+            section: section.map(String::from),
+            message: err.to_string(),
+            line_col: err.line_col(),
+        }
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self.section {
+            Some(ref section) => write!(f, "[{}] {}", section, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+/// Check a config file against the known sections, returning every problem
+/// that was found.
+///
+/// Unlike `Config::from_toml()`, this will deserialize each section directly
+/// from the source text so errors are reported with their line and column.
+pub(crate) fn check(raw: &str, sections: &[Section]) -> Vec<Diagnostic> {
+    let top_level = match toml::from_str::<BTreeMap<String, IgnoredAny>>(raw) {
+        Ok(t) => t,
+        Err(e) => return vec![Diagnostic::from_toml(None, &e)],
+    };
+
+    let mut diags = Vec::new();
+
+    for key in top_level.keys() {
+        if sections.iter().all(|s| s.key != key) {
+            let line_col = find_section(raw, key);
+            let message = match line_col {
+                Some((line, col)) => format!(
+                    "unknown section `{}` at line {} column {}",
+                    key,
+                    line + 1,
+                    col + 1
+                ),
+                None => format!("unknown section `{}`", key),
+            };
+
+            diags.push(Diagnostic {
+                section: Some(key.clone()),
+                message,
+                line_col,
+            });
+        }
+    }
+
+    for section in sections {
+        if let Err(e) = section.check(raw) {
+            diags.push(Diagnostic::from_toml(Some(section.key), &e));
+        }
+    }
+
+    if let Ok(cfg) = Config::from_toml(raw) {
+        diags.extend(
+            cfg.general
+                .conflicts()
+                .into_iter()
+                .map(|message| Diagnostic {
+                    section: Some(String::from(General::KEY)),
+                    message,
+                    line_col: None,
+                }),
+        );
+    }
+
+    diags
+}
+
+fn check_section<T: DeserializeOwned>(raw: &str, key: &str) -> Result<(), toml::de::Error> {
+    let mut de = toml::Deserializer::new(raw);
+    SectionSeed::<T> {
+        key,
+        _section: PhantomData,
+    }
+    .deserialize(&mut de)?;
+    de.end()
+}
+
+/// Find where a top-level key is first declared, either as a table header
+/// (`[key]`, `[key.nested]`, `[[key]]`) or as a dotted/inline key.
+fn find_section(raw: &str, key: &str) -> Option<(usize, usize)> {
+    for (line_no, line) in raw.lines().enumerate() {
+        let trimmed = line.trim_start();
+        let col = line.len() - trimmed.len();
+        let name = trimmed.trim_start_matches('[').trim_start();
+
+        if name.starts_with(key) {
+            let rest = name[key.len()..].trim_start();
+            if rest.starts_with(']') || rest.starts_with('.') || rest.starts_with('=') {
+                return Some((line_no, col));
+            }
+        }
+    }
+
+    None
+}
+
+/// Deserializes the value associated with `key`, skipping everything else.
+struct SectionSeed<'a, T> {
+    key: &'a str,
+    _section: PhantomData<T>,
+}
+
+impl<'de, 'a, T: DeserializeOwned> DeserializeSeed<'de> for SectionSeed<'a, T> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, de: D) -> Result<(), D::Error> {
+        de.deserialize_map(self)
+    }
+}
+
+impl<'de, 'a, T: DeserializeOwned> Visitor<'de> for SectionSeed<'a, T> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "a table")
+    }
+
+    fn visit_map<M: MapAccess<'de>>(self, mut map: M) -> Result<(), M::Error> {
+        while let Some(key) = map.next_key::<String>()? {
+            if key == self.key {
+                map.next_value::<T>()?;
+            } else {
+                map.next_value::<IgnoredAny>()?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -101,6 +563,7 @@ mod tests {
                 threads: 42,
                 error_threshold: 5,
                 blacklist: Vec::new(),
+                ..Default::default()
             },
             rest: vec![(String::from("first"), Value::Integer(1))]
                 .into_iter()
@@ -112,4 +575,77 @@ mod tests {
 
         assert_eq!(round_tripped, cfg);
     }
+
+    fn sections() -> Vec<Section> {
+        vec![Section::new::<General>(General::KEY)]
+    }
+
+    #[test]
+    fn valid_config_has_no_diagnostics() {
+        let src = "[general]\nroot = '/srv'\nthreads = 4\n";
+
+        assert!(check(src, &sections()).is_empty());
+    }
+
+    #[test]
+    fn flag_unknown_sections() {
+        let src = "[general]\nroot = '/srv'\n\n[gihub]\ncredentials = 'x'\n";
+
+        let got = check(src, &sections());
+
+        assert_eq!(got.len(), 1);
+        assert_eq!(got[0].section, Some(String::from("gihub")));
+        assert_eq!(got[0].line_col, Some((3, 0)));
+    }
+
+    #[test]
+    fn flag_unknown_fields_with_their_location() {
+        let src = "[general]\nroot = '/srv'\nthread = 4\n";
+
+        let got = check(src, &sections());
+
+        assert_eq!(got.len(), 1);
+        assert_eq!(got[0].section, Some(String::from("general")));
+        assert!(got[0].message.contains("thread"));
+        assert!(got[0].line_col.is_some());
+    }
+
+    #[test]
+    fn example_config_is_valid() {
+        let sections = sections();
+
+        let example = example(&sections).unwrap();
+
+        assert!(example.contains("[general]"));
+        assert!(example.contains("# The top-level directory"));
+        assert!(check(&example, &sections).is_empty());
+        assert!(undocumented_fields(&sections[0]).is_empty());
+    }
+
+    #[test]
+    fn archiving_repositories_conflicts_with_alternates() {
+        let src = "[general.archive]\nenabled = true\n[general.alternates]\nenabled = true\n";
+
+        let diags = check(src, &sections());
+
+        assert_eq!(diags.len(), 1);
+        assert_eq!(diags[0].section.as_deref(), Some(General::KEY));
+
+        let bundles = src.replace(
+            "enabled = true\n[general.alternates]",
+            "enabled = true\ninput = 'bundle'\n[general.alternates]",
+        );
+        assert!(check(&bundles, &sections()).is_empty());
+    }
+
+    #[test]
+    fn syntax_errors_are_reported() {
+        let src = "[general\nroot = '/srv'\n";
+
+        let got = check(src, &sections());
+
+        assert_eq!(got.len(), 1);
+        assert_eq!(got[0].section, None);
+        assert_eq!(got[0].line_col.map(|(line, _)| line), Some(0));
+    }
 }
@@ -0,0 +1,319 @@
+//! Running as a long-lived process which starts backups on a cron-style
+//! schedule, for environments (e.g. containers) where cron isn't available.
+
+use crate::config::Config;
+use crate::driver::{self, DownloadPool, Finished};
+use crate::filter::Age;
+use crate::metrics;
+use crate::webhook;
+use actix::{Actor, AsyncContext, Context, Handler, System};
+use chrono::{DateTime, Utc};
+use failure::{Error, ResultExt};
+use rand::Rng;
+use serde::de::{Deserialize, Deserializer, Error as DeError};
+use serde::ser::{Serialize, Serializer};
+use slog::Logger;
+use std::fmt::{self, Debug, Formatter};
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DaemonConfig {
+    /// When backups should be started.
+    pub schedule: Schedule,
+    /// Wait up to this long before starting each backup.
+    pub jitter: Option<Age>,
+    /// Should a backup be started as soon as the daemon starts?
+    pub run_on_start: bool,
+}
+
+impl DaemonConfig {
+    pub const DESCRIPTION: &'static str =
+        "Settings for `repo-backup daemon`, which runs backups on a schedule.";
+    pub const FIELDS: &'static [(&'static str, &'static str)] = &[
+        (
+            "schedule",
+            "When to start a backup, as a cron expression in UTC (e.g. \"0 2 * * *\" \
+             for 2am every day). A leading seconds field may also be given.",
+        ),
+        (
+            "jitter",
+            "Wait a random amount of time up to this long (e.g. \"15m\") before \
+             starting each backup, so several machines don't all hit the \
+             providers at once.",
+        ),
+        (
+            "run_on_start",
+            "Start a backup as soon as the daemon starts instead of waiting for \
+             the schedule.",
+        ),
+    ];
+}
+
+impl Default for DaemonConfig {
+    fn default() -> DaemonConfig {
+        DaemonConfig {
+            schedule: "0 2 * * *".parse().expect("The default schedule is valid"),
+            jitter: None,
+            run_on_start: false,
+        }
+    }
+}
+
+/// A cron expression.
+#[derive(Clone)]
+pub struct Schedule {
+    source: String,
+    schedule: cron::Schedule,
+}
+
+impl Schedule {
+    /// When the schedule next fires after `now`.
+    pub fn next_after(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.schedule.after(&now).next()
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Schedule, Error> {
+        // the cron crate wants a seconds field, normal crontabs don't have one
+        let expr = if s.split_whitespace().count() == 5 {
+            format!("0 {}", s)
+        } else {
+            s.to_string()
+        };
+
+        let schedule = expr
+            .parse::<cron::Schedule>()
+            .map_err(|e| failure::format_err!("{}", e))
+            .context("Invalid cron expression")?;
+
+        Ok(Schedule {
+            source: s.to_string(),
+            schedule,
+        })
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl PartialEq for Schedule {
+    fn eq(&self, other: &Schedule) -> bool {
+        self.source == other.source
+    }
+}
+
+impl Debug for Schedule {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Schedule").field(&self.source).finish()
+    }
+}
+
+impl Serialize for Schedule {
+    fn serialize<S: Serializer>(&self, ser: S) -> Result<S::Ok, S::Error> {
+        self.source.serialize(ser)
+    }
+}
+
+impl<'de> Deserialize<'de> for Schedule {
+    fn deserialize<D: Deserializer<'de>>(de: D) -> Result<Schedule, D::Error> {
+        let source = String::deserialize(de)?;
+        source
+            .parse()
+            .map_err(|e| D::Error::custom(format!("invalid schedule \"{}\": {}", source, e)))
+    }
+}
+
+/// Keep running, starting a backup every time the schedule says to.
+pub fn daemon<P: AsRef<Path>>(config: P, logger: &Logger) -> Result<(), Error> {
+    let config = config.as_ref();
+    let cfg = driver::load_config(config)?;
+
+    driver::warn_about_unknown_sections(&cfg, logger);
+
+    let sys = System::new("repo-backup-daemon");
+
+    // pruning while a webhook is fetching into the store isn't safe, so it
+    // only happens at startup
+    driver::prune_shared_objects(&cfg.general, logger);
+    // scheduled backups and webhooks share workers, so they never touch the
+    // same repository at once
+    let pool = driver::download_pool(&cfg, logger)?;
+
+    let metrics = match cfg.general.metrics.address {
+        Some(ref address) => Some(metrics::serve(&cfg, address, logger)?),
+        None => None,
+    };
+
+    if cfg.general.webhook.enabled {
+        webhook::listen(&cfg, pool.gits.clone(), metrics.clone(), logger)?;
+    }
+
+    info!(logger, "Started the backup daemon";
+        "config-file" => config.display(),
+        "root" => cfg.general.root.display(),
+        "schedule" => cfg.general.daemon.schedule.to_string());
+
+    Scheduler {
+        cfg,
+        pool,
+        logger: logger.clone(),
+        running: false,
+        metrics,
+    }
+    .start();
+
+    sys.run().map_err(Error::from)
+}
+
+/// Starts backups on schedule, making sure only one runs at a time.
+struct Scheduler {
+    cfg: Config,
+    pool: DownloadPool,
+    logger: Logger,
+    running: bool,
+    metrics: Option<metrics::Cache>,
+}
+
+impl Scheduler {
+    fn schedule_next(&self, ctx: &mut Context<Scheduler>) {
+        let daemon = &self.cfg.general.daemon;
+        let now = Utc::now();
+
+        let next = match daemon.schedule.next_after(now) {
+            Some(next) => next,
+            None => {
+                warn!(self.logger, "The schedule will never run again, stopping");
+                System::current().stop();
+                return;
+            }
+        };
+
+        let spread = chrono::Duration::from_std(jitter(daemon.jitter.as_ref()))
+            .unwrap_or_else(|_| chrono::Duration::zero());
+        let at = next + spread;
+        let delay = at.signed_duration_since(now).to_std().unwrap_or_default();
+
+        info!(self.logger, "Scheduled the next backup"; "at" => at.to_rfc3339());
+
+        ctx.run_later(delay, |scheduler, ctx| {
+            scheduler.start_backup(ctx);
+            scheduler.schedule_next(ctx);
+        });
+    }
+
+    fn start_backup(&mut self, ctx: &mut Context<Scheduler>) {
+        if self.running {
+            warn!(
+                self.logger,
+                "The previous backup is still running, skipping this one"
+            );
+            return;
+        }
+
+        info!(self.logger, "Starting a backup");
+
+        let finished = ctx.address().recipient();
+
+        match driver::start_backup(
+            &self.cfg,
+            &self.logger,
+            Some(self.pool.clone()),
+            Some(finished),
+        ) {
+            Ok(()) => self.running = true,
+            Err(e) => {
+                error!(self.logger, "Unable to start the backup"; "error" => e.to_string());
+
+                for cause in e.iter_causes() {
+                    warn!(self.logger, "Caused By"; "cause" => cause.to_string());
+                }
+            }
+        }
+    }
+}
+
+impl Actor for Scheduler {
+    type Context = Context<Scheduler>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        if self.cfg.general.daemon.run_on_start {
+            self.start_backup(ctx);
+        }
+
+        self.schedule_next(ctx);
+    }
+}
+
+impl Handler<Finished> for Scheduler {
+    type Result = ();
+
+    fn handle(&mut self, msg: Finished, _ctx: &mut Self::Context) {
+        // only sent once every download the backup queued has finished or
+        // been cancelled, so the next backup never overlaps with this one
+        self.running = false;
+
+        info!(self.logger, "The backup finished";
+            "successful" => msg.succeeded());
+
+        if let Some(ref metrics) = self.metrics {
+            metrics.finished(msg);
+        }
+    }
+}
+
+/// A random delay somewhere between nothing and `max`.
+fn jitter(max: Option<&Age>) -> Duration {
+    match max {
+        Some(max) if max.0 > Duration::from_secs(0) => {
+            let millis = max.0.as_secs() * 1000 + u64::from(max.0.subsec_millis());
+            Duration::from_millis(rand::thread_rng().gen_range(0, millis.max(1)))
+        }
+        _ => Duration::from_secs(0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn parse_crontab_style_schedules() {
+        let schedule: Schedule = "30 2 * * *".parse().unwrap();
+        let now = Utc.ymd(2019, 5, 1).and_hms(12, 0, 0);
+
+        assert_eq!(
+            schedule.next_after(now).unwrap(),
+            Utc.ymd(2019, 5, 2).and_hms(2, 30, 0)
+        );
+
+        // an explicit seconds field works too
+        let schedule: Schedule = "15 30 2 * * *".parse().unwrap();
+        assert_eq!(
+            schedule.next_after(now).unwrap(),
+            Utc.ymd(2019, 5, 2).and_hms(2, 30, 15)
+        );
+
+        assert!("every tuesday".parse::<Schedule>().is_err());
+    }
+
+    #[test]
+    fn jitter_stays_in_range() {
+        let max: Age = "10s".parse().unwrap();
+
+        for _ in 0..100 {
+            assert!(jitter(Some(&max)) < max.0);
+        }
+
+        assert_eq!(jitter(None), Duration::from_secs(0));
+    }
+}
@@ -1,31 +1,39 @@
-use crate::config::{Config, ConfigError};
-use crate::git::{DownloadRepo, GitClone, GitRepo};
-use crate::providers::{GitHub, GitLab, Provider};
+use crate::alternates;
+use crate::config::{self, Config, ConfigError, Diagnostic, General, Section};
+use crate::filter::{Decision, Filters};
+use crate::git::{
+    canonical_url, Cancellation, DownloadRepo, Downloaded, EmptyWiki, GitClone, GitRepo, RepoLocks,
+};
+use crate::metrics;
+use crate::migrate::Migration;
+use crate::notify;
+use crate::providers::{self, Gists, GitHub, GitHubConfig, GitLab, GitLabConfig, Provider};
 use actix::{
-    Actor, Arbiter, AsyncContext, Context, Handler, Recipient, Running, StreamHandler, SyncArbiter,
-    System,
+    Actor, Addr, Arbiter, AsyncContext, Context, Handler, Recipient, Running, SpawnHandle,
+    StreamHandler, SyncArbiter, System,
 };
+use chrono::{DateTime, Utc};
 use failure::{Error, ResultExt};
-use futures::future::Future;
+use futures::future::{self, Future};
 use futures::stream::{self, Stream};
 use serde::Deserialize;
 use slog::Logger;
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
 
 pub fn run<P: AsRef<Path>>(config: P, logger: &Logger) -> Result<(), Error> {
     let config = config.as_ref();
+    let cfg = load_config(config)?;
 
-    let cfg = fs::read_to_string(&config)
-        .map_err(Error::from)
-        .and_then(|s| Config::from_toml(&s).map_err(Error::from))
-        .context("Unable to load the config")?;
+    warn_about_unknown_sections(&cfg, logger);
 
     let sys = System::new("repo-backup");
 
-    let mut driver = Driver::new(cfg.clone(), logger.clone());
-    register_providers(&mut driver, &cfg, &logger);
-    driver.start();
+    prune_shared_objects(&cfg.general, logger);
+    start_backup(&cfg, logger, None, None)?;
 
     info!(logger, "Started the backup process"; 
         "config-file" => config.display(),
@@ -36,23 +44,174 @@ pub fn run<P: AsRef<Path>>(config: P, logger: &Logger) -> Result<(), Error> {
     sys.run().map_err(Error::from)
 }
 
-fn register_providers(driver: &mut Driver, cfg: &Config, logger: &Logger) {
-    debug!(logger, "Registering providers");
+/// Start a `Driver` which backs up every repository on the current `System`.
+///
+/// Once the backup is done the `System` is stopped, unless `on_finished` is
+/// given, in which case it's told the backup finished instead. Repositories
+/// are downloaded by `pool` if given, otherwise by a new `download_pool()`.
+pub(crate) fn start_backup(
+    cfg: &Config,
+    logger: &Logger,
+    pool: Option<DownloadPool>,
+    on_finished: Option<Recipient<Finished>>,
+) -> Result<(), Error> {
+    let pool = match pool {
+        Some(pool) => pool,
+        None => download_pool(cfg, logger)?,
+    };
 
-    try_register("github", &cfg, driver, logger, |got, logger| {
-        debug!(logger, "Registering the GitHub provider");
-        GitHub::new(got, logger.clone())
-    });
-    try_register("gitlab", &cfg, driver, logger, |got, logger| {
-        debug!(logger, "Registering the GitLab provider");
-        GitLab::new(got, logger.clone())
+    let mut driver =
+        Driver::new_with_recipient(cfg.clone(), logger.clone(), pool.gits.recipient())?;
+    driver.with_locks(pool.locks);
+    register_providers(&mut driver, &cfg, &logger)?;
+    if let Some(recipient) = on_finished {
+        driver.notify_when_finished(recipient);
+    }
+    driver.start();
+
+    Ok(())
+}
+
+/// The workers which download repositories, and the locks they use to stay
+/// out of each other's way.
+#[derive(Clone)]
+pub(crate) struct DownloadPool {
+    pub gits: Addr<GitClone>,
+    pub locks: RepoLocks,
+}
+
+/// Start the workers which download repositories.
+pub(crate) fn download_pool(cfg: &Config, logger: &Logger) -> Result<DownloadPool, Error> {
+    let migration = Migration::from_config(cfg)?.map(Arc::new);
+    let general = cfg.general.clone();
+    let logger = logger.clone();
+    let locks = RepoLocks::default();
+    let l2 = locks.clone();
+
+    let gits = SyncArbiter::start(cfg.general.threads, move || {
+        GitClone::new(general.clone(), migration.clone(), logger.clone()).with_locks(l2.clone())
     });
+
+    Ok(DownloadPool { gits, locks })
+}
+
+/// Drop repositories which have been deleted from the shared object store,
+/// so their objects can be cleaned up.
+pub(crate) fn prune_shared_objects(cfg: &General, logger: &Logger) {
+    match alternates::prune_store(&cfg.alternates, &cfg.root) {
+        Ok(forgotten) => {
+            for dest_dir in forgotten {
+                info!(logger, "Removed a deleted repository from the shared object store";
+                    "dest-dir" => dest_dir.display());
+            }
+        }
+        Err(e) => {
+            warn!(logger, "Unable to prune the shared object store";
+                "error" => e.to_string());
+        }
+    }
+}
+
+/// Read and parse a config file.
+pub fn load_config<P: AsRef<Path>>(config: P) -> Result<Config, Error> {
+    let cfg = fs::read_to_string(config.as_ref())
+        .map_err(Error::from)
+        .and_then(|s| Config::from_toml(&s).map_err(Error::from))
+        .context("Unable to load the config")?;
+
+    if let Some(conflict) = cfg.general.conflicts().into_iter().next() {
+        Err::<(), _>(failure::err_msg(conflict)).context("Unable to load the config")?;
+    }
+
+    Ok(cfg)
+}
+
+/// Check a config file for problems (unknown sections or fields, invalid
+/// values, etc.) without running a backup.
+pub fn check_config<P: AsRef<Path>>(config: P) -> Result<Vec<Diagnostic>, Error> {
+    let raw = fs::read_to_string(config.as_ref()).context("Unable to read the config")?;
+
+    Ok(config::check(&raw, &known_sections()))
+}
+
+/// Generate a commented example config containing every known section and
+/// its default values.
+pub fn example_config() -> Result<String, Error> {
+    config::example(&known_sections()).map_err(Error::from)
+}
+
+/// Every top-level section we know how to handle.
+fn known_sections() -> Vec<Section> {
+    let mut sections = vec![Section::new::<General>(General::KEY)];
+    sections.extend(providers::sections());
+    sections
+}
+
+pub(crate) fn warn_about_unknown_sections(cfg: &Config, logger: &Logger) {
+    let known = known_sections();
+
+    for key in cfg.rest.keys() {
+        if known.iter().all(|section| section.key != key) {
+            warn!(logger, "Ignoring unknown config section"; "section" => key);
+        }
+    }
+}
+
+fn register_providers(driver: &mut Driver, cfg: &Config, logger: &Logger) -> Result<(), Error> {
+    driver.providers.extend(providers(cfg, logger)?);
+    Ok(())
+}
+
+/// Create every provider which has a section in the config.
+fn providers(cfg: &Config, logger: &Logger) -> Result<Vec<Box<dyn Provider>>, Error> {
+    debug!(logger, "Registering providers");
+    let mut providers = Vec::new();
+    let filters = Filters::from_config(cfg)?;
+
+    try_register(
+        GitHubConfig::KEY,
+        &cfg,
+        &mut providers,
+        logger,
+        |got, logger| {
+            debug!(logger, "Registering the GitHub provider");
+            GitHub::new(got, logger.clone()).with_topics(filters.wants_topics(GitHubConfig::KEY))
+        },
+    )?;
+
+    // gists are configured in the [github] section, which we now know is valid
+    if let Ok(gh) = cfg.get_deserialized::<GitHubConfig>(GitHubConfig::KEY) {
+        if Gists::wanted(&gh) {
+            debug!(logger, "Registering the Gists provider");
+            providers.push(Box::new(Gists::new(gh, logger.clone())));
+        }
+    }
+
+    try_register(
+        GitLabConfig::KEY,
+        &cfg,
+        &mut providers,
+        logger,
+        |got, logger| {
+            debug!(logger, "Registering the GitLab provider");
+            GitLab::new(got, logger.clone())
+                .with_languages(filters.wants_languages(GitLabConfig::KEY))
+        },
+    )?;
+
+    Ok(providers)
 }
 
 /// Try to parse the corresponding section from a `Config`, if successful use
-/// the resulting value to construct a `Provider` to be registered with the
-/// `Driver`.
-fn try_register<F, P, C>(key: &str, cfg: &Config, driver: &mut Driver, logger: &Logger, then: F)
+/// the resulting value to construct a `Provider` which gets added to the
+/// list of providers.
+fn try_register<F, P, C>(
+    key: &str,
+    cfg: &Config,
+    providers: &mut Vec<Box<dyn Provider>>,
+    logger: &Logger,
+    then: F,
+) -> Result<(), Error>
 where
     F: FnOnce(C, &Logger) -> P,
     P: Provider + 'static,
@@ -61,49 +220,204 @@ where
     match cfg.get_deserialized(key) {
         Ok(got) => {
             let provider = then(got, logger);
-            driver.register(provider);
+            providers.push(Box::new(provider));
+            Ok(())
         }
         Err(ConfigError::Toml(toml)) => {
-            warn!(logger, "Unable to parse the \"{}\" config section", key;
-                "error" => toml.to_string());
+            let msg = format!("Unable to parse the \"{}\" config section", key);
+            Err(Error::from(toml).context(msg).into())
+        }
+        Err(ConfigError::MissingKey) => Ok(()),
+    }
+}
+
+/// A repository found by one of the providers, and what a backup would do
+/// with it.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Listed {
+    pub provider: String,
+    pub url: String,
+    /// Where the repository would be saved, relative to the backup root.
+    pub dest_dir: PathBuf,
+    pub decision: ListDecision,
+    /// Why the repository wouldn't be backed up.
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ListDecision {
+    /// The repository would be backed up.
+    Include,
+    /// The repository was filtered out.
+    Skip,
+    /// Another provider already found the same repository.
+    Duplicate,
+    /// A different repository would be saved to the same place.
+    Conflict,
+}
+
+impl ListDecision {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ListDecision::Include => "include",
+            ListDecision::Skip => "skip",
+            ListDecision::Duplicate => "duplicate",
+            ListDecision::Conflict => "conflict",
         }
-        Err(ConfigError::MissingKey) => {}
     }
 }
 
+/// Ask every provider for its repositories and decide what a backup would do
+/// with each of them, without touching any git repositories.
+pub fn list(cfg: &Config, logger: &Logger) -> Result<Vec<Listed>, Error> {
+    let providers = providers(cfg, logger)?;
+    list_from(providers, cfg, logger)
+}
+
+fn list_from(
+    mut providers: Vec<Box<dyn Provider>>,
+    cfg: &Config,
+    logger: &Logger,
+) -> Result<Vec<Listed>, Error> {
+    let mut sys = System::new("repo-backup-list");
+
+    let repository_lists: Vec<_> = providers.iter_mut().map(|p| p.repositories()).collect();
+    // like a backup, a provider which fails shouldn't stop the others
+    let discovered = sys.block_on(
+        stream::iter_ok::<_, Error>(repository_lists)
+            .flatten()
+            .then(Ok::<_, Error>)
+            .collect(),
+    )?;
+
+    let filters = Filters::from_config(cfg)?;
+    let mut seen = Deduplicator::default();
+    let mut listed = Vec::new();
+
+    for outcome in discovered {
+        let repo = match outcome {
+            Ok(repo) => repo,
+            Err(e) => {
+                warn!(logger, "Unable to discover every repository"; "error" => e.to_string());
+                for cause in e.iter_causes() {
+                    warn!(logger, "Caused By"; "cause" => cause.to_string());
+                }
+                continue;
+            }
+        };
+
+        let (decision, reason) = match seen.insert(&repo) {
+            Seen::Duplicate(original) => (
+                ListDecision::Duplicate,
+                Some(format!("already found by {}", original.provider)),
+            ),
+            Seen::Conflict(original) => (
+                ListDecision::Conflict,
+                Some(format!("{} is saved to the same place", original.ssh_url)),
+            ),
+            Seen::New => match filters.check(&repo) {
+                Decision::Include => (ListDecision::Include, None),
+                Decision::Skip(reason) => (ListDecision::Skip, Some(reason)),
+            },
+        };
+
+        listed.push(Listed {
+            provider: repo.provider,
+            url: repo.ssh_url,
+            dest_dir: repo.dest_dir,
+            decision,
+            reason,
+        });
+    }
+
+    Ok(listed)
+}
+
 pub struct Driver {
     config: Config,
     logger: Logger,
     providers: Vec<Box<dyn Provider>>,
+    filters: Filters,
+    seen: Deduplicator,
     gits: Recipient<DownloadRepo>,
     stats: Statistics,
+    /// How many repositories each provider found.
+    discovered: BTreeMap<String, usize>,
+    /// Why each repository which couldn't be backed up failed.
+    failures: BTreeMap<PathBuf, String>,
+    started_at: DateTime<Utc>,
+    discovery_finished: bool,
+    finishing: bool,
+    /// Repositories which were sent to the workers but haven't come back yet.
+    in_flight: usize,
+    /// Calls off queued downloads when the backup gives up early.
+    cancellation: Cancellation,
+    /// The stream of discovered repositories, so it can be stopped early.
+    discovery: Option<SpawnHandle>,
+    /// The backup is over (e.g. after too many errors) and will exit with
+    /// this code once every download in flight has finished.
+    exit_code: Option<i32>,
+    on_finished: Option<Recipient<Finished>>,
+    /// Shared with the workers, so the backup's own bookkeeping never races
+    /// a download.
+    locks: RepoLocks,
 }
 
 impl Driver {
     /// Create a new driver which will download repositories on a background
     /// thread pool.
-    pub fn new(config: Config, logger: Logger) -> Driver {
+    pub fn new(config: Config, logger: Logger) -> Result<Driver, Error> {
+        Driver::with_migration(config, logger, None)
+    }
+
+    /// Create a new driver which also mirrors each downloaded repository.
+    pub fn with_migration(
+        config: Config,
+        logger: Logger,
+        migration: Option<Migration>,
+    ) -> Result<Driver, Error> {
         let l2 = logger.clone();
-        let root = config.general.root.clone();
+        let general = config.general.clone();
+        let migration = migration.map(Arc::new);
+        let locks = RepoLocks::default();
+        let l3 = locks.clone();
         let gits = SyncArbiter::start(config.general.threads, move || {
-            GitClone::new(root.clone(), l2.clone())
+            GitClone::new(general.clone(), migration.clone(), l2.clone()).with_locks(l3.clone())
         });
 
-        Driver::new_with_recipient(config, logger, gits.recipient())
+        let mut driver = Driver::new_with_recipient(config, logger, gits.recipient())?;
+        driver.with_locks(locks);
+        Ok(driver)
     }
 
     pub fn new_with_recipient(
         config: Config,
         logger: Logger,
         gits: Recipient<DownloadRepo>,
-    ) -> Driver {
-        Driver {
+    ) -> Result<Driver, Error> {
+        let filters = Filters::from_config(&config)?;
+
+        Ok(Driver {
             config,
             logger,
             providers: Vec::new(),
+            filters,
+            seen: Deduplicator::default(),
             gits,
             stats: Statistics::default(),
-        }
+            discovered: BTreeMap::new(),
+            failures: BTreeMap::new(),
+            started_at: Utc::now(),
+            discovery_finished: false,
+            finishing: false,
+            in_flight: 0,
+            cancellation: Cancellation::default(),
+            discovery: None,
+            exit_code: None,
+            on_finished: None,
+            locks: RepoLocks::default(),
+        })
     }
 
     pub fn register<P: Provider + 'static>(&mut self, provider: P) -> &mut Self {
@@ -120,6 +434,124 @@ impl Driver {
         self.register(provider);
         self
     }
+
+    /// Share repository locks with the workers downloading repositories.
+    pub(crate) fn with_locks(&mut self, locks: RepoLocks) -> &mut Self {
+        self.locks = locks;
+        self
+    }
+
+    /// Let someone else know when the backup is finished instead of stopping
+    /// the `System` (e.g. when running as a daemon).
+    pub(crate) fn notify_when_finished(&mut self, recipient: Recipient<Finished>) -> &mut Self {
+        self.on_finished = Some(recipient);
+        self
+    }
+
+    /// The backup is over, one way or another.
+    fn shut_down(&mut self, ctx: &mut Context<Driver>, exit_code: i32) {
+        if self.exit_code.is_some() {
+            return;
+        }
+        self.exit_code = Some(exit_code);
+
+        // nothing new gets started, but fetches which are already running
+        // are left to finish so they can't race the next backup
+        self.cancellation.cancel();
+        if let Some(discovery) = self.discovery.take() {
+            ctx.cancel_future(discovery);
+        }
+
+        self.report_if_drained(ctx);
+    }
+
+    /// Say the backup is over once every download in flight has finished.
+    fn report_if_drained(&mut self, ctx: &mut Context<Driver>) {
+        let exit_code = match self.exit_code {
+            Some(exit_code) if self.in_flight == 0 => exit_code,
+            _ => return,
+        };
+
+        let finished = Finished {
+            stats: self.stats,
+            discovered: self.discovered.clone(),
+            failures: self.failures.clone(),
+            // a backup which ran to completion is always finishing
+            aborted: !self.finishing,
+            started_at: self.started_at,
+            finished_at: Utc::now(),
+            exit_code,
+        };
+
+        // sending emails and reading every repository's state can take a
+        // while, and the daemon's webhooks and metrics share this thread
+        let reporting = self.report(finished.clone());
+
+        match self.on_finished {
+            Some(ref recipient) => {
+                if let Err(e) = recipient.do_send(finished) {
+                    warn!(self.logger, "Unable to say the backup finished"; "error" => e.to_string());
+                }
+
+                ctx.stop();
+            }
+            None => {
+                // exiting would cut the notification off part-way through
+                let _ = reporting.join();
+                System::current().stop_with_code(exit_code);
+            }
+        }
+    }
+
+    /// Write the metrics textfile and send notifications on a background
+    /// thread.
+    fn report(&self, finished: Finished) -> JoinHandle<()> {
+        let general = self.config.general.clone();
+        let locks = self.locks.clone();
+        let logger = self.logger.clone();
+
+        thread::spawn(move || {
+            if let Some(ref textfile) = general.metrics.textfile {
+                if let Err(e) = metrics::write_textfile(textfile, &general.root, &finished) {
+                    warn!(logger, "Unable to write the metrics";
+                        "path" => textfile.display(),
+                        "error" => e.to_string());
+                }
+            }
+
+            notify::notify(&general.notify, &general.root, &finished, &locks, &logger);
+        })
+    }
+
+    /// Stop once every provider has finished and every repository has been
+    /// dealt with.
+    fn stop_if_finished(&mut self, ctx: &mut Context<Driver>) {
+        let handled = self.stats.error_count
+            + self.stats.success
+            + self.stats.ignored
+            + self.stats.duplicates
+            + self.stats.conflicts;
+
+        if !self.discovery_finished || handled != self.stats.total_repos || self.finishing {
+            return;
+        }
+
+        self.finishing = true;
+        debug!(self.logger, "Waiting for providers to finish");
+
+        let pending: Vec<_> = self.providers.iter_mut().map(|p| p.finish()).collect();
+        let mailbox = ctx.address();
+        let logger = self.logger.clone();
+
+        Arbiter::spawn(future::join_all(pending).then(move |outcome| {
+            if let Err(e) = outcome {
+                warn!(logger, "A provider didn't finish cleanly"; "error" => e.to_string());
+            }
+
+            mailbox.do_send(Stop);
+            Ok(())
+        }));
+    }
 }
 
 impl Actor for Driver {
@@ -130,11 +562,16 @@ impl Actor for Driver {
 
         let mut pending_repository_lists = Vec::new();
 
-        for provider in &self.providers {
-            pending_repository_lists.push(provider.repositories());
+        for (ix, provider) in self.providers.iter_mut().enumerate() {
+            let repos = provider
+                .repositories()
+                .map(move |repo| Discovered { provider: ix, repo });
+            pending_repository_lists.push(repos);
         }
 
-        ctx.add_stream(stream::iter_ok::<_, Error>(pending_repository_lists).flatten());
+        let discovery =
+            ctx.add_stream(stream::iter_ok::<_, Error>(pending_repository_lists).flatten());
+        self.discovery = Some(discovery);
     }
 
     fn stopped(&mut self, _ctx: &mut Self::Context) {
@@ -142,22 +579,50 @@ impl Actor for Driver {
     }
 }
 
-impl StreamHandler<GitRepo, Error> for Driver {
-    fn handle(&mut self, repo: GitRepo, ctx: &mut Self::Context) {
+/// A repository and the index of the provider which found it.
+struct Discovered {
+    provider: usize,
+    repo: GitRepo,
+}
+
+impl StreamHandler<Discovered, Error> for Driver {
+    fn handle(&mut self, msg: Discovered, ctx: &mut Self::Context) {
+        let Discovered { provider, repo } = msg;
+
         debug!(self.logger, "Discovered a repository";
             "ssh-url" => &repo.ssh_url,
             "dest-dir" => repo.dest_dir.display());
         self.stats.total_repos += 1;
+        *self.discovered.entry(repo.provider.clone()).or_insert(0) += 1;
 
-        let ignored = self
-            .config
-            .general
-            .blacklist
-            .iter()
-            .any(|item| Path::new(&item) == &repo.dest_dir);
+        match self.seen.insert(&repo) {
+            Seen::New => {}
+            Seen::Duplicate(original) => {
+                info!(self.logger, "Skipping a repository which was already discovered";
+                    "dest-dir" => repo.dest_dir.display(),
+                    "provider" => &repo.provider,
+                    "url" => &repo.ssh_url,
+                    "first-provider" => &original.provider,
+                    "first-url" => &original.ssh_url);
+                self.stats.duplicates += 1;
+                return;
+            }
+            Seen::Conflict(original) => {
+                error!(self.logger, "Two different repositories have the same destination";
+                    "dest-dir" => repo.dest_dir.display(),
+                    "provider" => &repo.provider,
+                    "url" => &repo.ssh_url,
+                    "first-provider" => &original.provider,
+                    "first-url" => &original.ssh_url);
+                self.stats.conflicts += 1;
+                return;
+            }
+        }
 
-        if ignored {
-            info!(self.logger, "Ignoring repo"; "dest-dir" => repo.dest_dir.display());
+        if let Decision::Skip(reason) = self.filters.check(&repo) {
+            info!(self.logger, "Ignoring repo";
+                "dest-dir" => repo.dest_dir.display(),
+                "reason" => reason);
             self.stats.ignored += 1;
             return;
         }
@@ -167,12 +632,21 @@ impl StreamHandler<GitRepo, Error> for Driver {
         let r2 = repo.clone();
         let fut = self
             .gits
-            .send(DownloadRepo(repo.clone()))
-            .and_then(move |outcome| mailbox.send(Done { repo: r2, outcome }));
+            .send(DownloadRepo(repo.clone(), self.cancellation.clone()))
+            .map_err(|e| failure::format_err!("{}", e))
+            .and_then(|outcome| outcome)
+            .then(move |outcome| {
+                mailbox.send(Done {
+                    provider,
+                    repo: r2,
+                    outcome,
+                })
+            });
+        self.in_flight += 1;
 
         let logger = self.logger.clone();
         Arbiter::spawn(fut.map_err(move |e| {
-            error!(logger, "Unable to download {} because {}", repo.ssh_url, e);
+            error!(logger, "Unable to report on {} because {}", repo.ssh_url, e);
         }));
     }
 
@@ -186,8 +660,10 @@ impl StreamHandler<GitRepo, Error> for Driver {
         Running::Continue
     }
 
-    fn finished(&mut self, _ctx: &mut Self::Context) {
+    fn finished(&mut self, ctx: &mut Self::Context) {
         debug!(self.logger, "Discovered all repositories");
+        self.discovery_finished = true;
+        self.stop_if_finished(ctx);
     }
 }
 
@@ -197,74 +673,178 @@ struct Stop;
 impl Handler<Stop> for Driver {
     type Result = ();
 
-    fn handle(&mut self, _msg: Stop, _ctx: &mut Self::Context) {
+    fn handle(&mut self, _msg: Stop, ctx: &mut Self::Context) {
         info!(self.logger, "Stopping...";
             "failed-backups" => self.stats.error_count,
             "successful-updates" => self.stats.success,
             "ignored" => self.stats.ignored,
+            "duplicates" => self.stats.duplicates,
+            "conflicts" => self.stats.conflicts,
+            "failed-mirrors" => self.stats.mirror_failures,
             "total-repos" => self.stats.total_repos);
-        System::current().stop();
+
+        // conflicting destinations mean a repository wasn't backed up
+        let exit_code = if self.stats.conflicts > 0 { 1 } else { 0 };
+        self.shut_down(ctx, exit_code);
+    }
+}
+
+/// Sent when a backup is finished, if the `Driver` was asked to.
+#[derive(Debug, Clone, Message)]
+pub(crate) struct Finished {
+    pub stats: Statistics,
+    /// How many repositories each provider found.
+    pub discovered: BTreeMap<String, usize>,
+    /// Why each repository which couldn't be backed up failed.
+    pub failures: BTreeMap<PathBuf, String>,
+    /// Did the backup give up early because of too many errors?
+    pub aborted: bool,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+    /// What the exit code would have been for a one-off backup.
+    pub exit_code: i32,
+}
+
+impl Finished {
+    /// Was every repository backed up? Repositories skipped because they
+    /// had the same destination as another count as failures.
+    pub fn succeeded(&self) -> bool {
+        self.failures.is_empty() && self.stats.conflicts == 0
     }
 }
 
 #[derive(Debug, Message)]
 struct Done {
+    pub provider: usize,
     pub repo: GitRepo,
-    pub outcome: Result<(), Error>,
+    pub outcome: Result<Downloaded, Error>,
 }
 
 impl Handler<Done> for Driver {
     type Result = ();
 
     fn handle(&mut self, msg: Done, ctx: &mut Self::Context) {
-        if let Err(e) = msg.outcome {
-            warn!(self.logger, "Error backing up a repository";
-                "error" => e.to_string(),
-                "dest" => msg.repo.dest_dir.display(),
-                "url" => &msg.repo.ssh_url);
-
-            for cause in e.iter_causes() {
-                warn!(self.logger, "Caused By"; "cause" => cause.to_string());
+        self.in_flight -= 1;
+
+        // downloads which were already running keep finishing after bailing
+        if self.exit_code.is_some() {
+            self.report_if_drained(ctx);
+            return;
+        }
+
+        match msg.outcome {
+            Err(ref e) if e.downcast_ref::<EmptyWiki>().is_some() => {
+                info!(self.logger, "Skipping an empty wiki";
+                    "dest" => msg.repo.dest_dir.display());
+                self.stats.ignored += 1;
             }
+            Err(e) => {
+                warn!(self.logger, "Error backing up a repository";
+                    "error" => e.to_string(),
+                    "dest" => msg.repo.dest_dir.display(),
+                    "url" => &msg.repo.ssh_url);
+
+                for cause in e.iter_causes() {
+                    warn!(self.logger, "Caused By"; "cause" => cause.to_string());
+                }
 
-            self.stats.error_count += 1;
-            let threshold = self.config.general.error_threshold;
+                let causes: Vec<_> = e.iter_chain().map(|c| c.to_string()).collect();
+                self.failures
+                    .insert(msg.repo.dest_dir.clone(), causes.join(": "));
+                self.stats.error_count += 1;
+                let threshold = self.config.general.error_threshold;
 
-            if threshold > 0 && self.stats.error_count >= threshold {
-                error!(self.logger, "Too many errors were encountered. Bailing";
-                    "error-count" => self.stats.error_count);
+                if threshold > 0 && self.stats.error_count >= threshold {
+                    error!(self.logger, "Too many errors were encountered. Bailing";
+                        "error-count" => self.stats.error_count,
+                        "in-flight" => self.in_flight);
 
-                System::current().stop_with_code(1);
+                    self.shut_down(ctx, 1);
+                    return;
+                }
+            }
+            Ok(downloaded) => {
+                info!(self.logger, "Successfully backed up a repo";
+                    "repo" => msg.repo.dest_dir.display());
+                self.stats.success += 1;
+
+                if let Some(reason) = downloaded.mirror_failure {
+                    warn!(self.logger, "Unable to mirror a repository";
+                        "repo" => msg.repo.dest_dir.display(),
+                        "error" => reason);
+                    self.stats.mirror_failures += 1;
+                }
+
+                let dir = self.config.general.root.join(&msg.repo.dest_dir);
+                self.providers[msg.provider].downloaded(&msg.repo, &dir);
             }
-        } else {
-            info!(self.logger, "Successfully backed up a repo";
-                "repo" => msg.repo.dest_dir.display());
-            self.stats.success += 1;
         }
 
-        if self.stats.error_count + self.stats.success + self.stats.ignored
-            == self.stats.total_repos
-        {
-            ctx.notify(Stop);
+        self.stop_if_finished(ctx);
+    }
+}
+
+/// Keeps track of every repository we've seen so far, so the same repository
+/// reported by multiple sources (e.g. your own repos and an organisation's
+/// repos) is only downloaded once.
+#[derive(Debug, Default)]
+pub(crate) struct Deduplicator {
+    seen: HashMap<PathBuf, GitRepo>,
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum Seen<'a> {
+    New,
+    /// The same repository was already seen.
+    Duplicate(&'a GitRepo),
+    /// A different repository already maps to the same destination.
+    Conflict(&'a GitRepo),
+}
+
+impl Deduplicator {
+    pub fn insert(&mut self, repo: &GitRepo) -> Seen<'_> {
+        let mut is_new = false;
+        let original = self.seen.entry(repo.dest_dir.clone()).or_insert_with(|| {
+            is_new = true;
+            repo.clone()
+        });
+
+        if is_new {
+            Seen::New
+        } else if canonical_url(&original.ssh_url) == canonical_url(&repo.ssh_url) {
+            Seen::Duplicate(original)
+        } else {
+            Seen::Conflict(original)
         }
     }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Default)]
 pub struct Statistics {
-    error_count: usize,
-    success: usize,
-    ignored: usize,
-    total_repos: usize,
+    pub(crate) error_count: usize,
+    pub(crate) success: usize,
+    pub(crate) ignored: usize,
+    /// Repositories which were skipped because they'd already been seen.
+    pub(crate) duplicates: usize,
+    /// Distinct repositories which would have been saved to the same place.
+    pub(crate) conflicts: usize,
+    /// Repositories which were backed up but couldn't be mirrored.
+    pub(crate) mirror_failures: usize,
+    pub(crate) total_repos: usize,
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::git::GitRepo;
+    use crate::config::undocumented_fields;
+    use crate::git::{Cancelled, GitRepo};
+    use actix::ResponseFuture;
+    use futures::sync::oneshot;
     use slog::Discard;
     use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering};
     use std::sync::{Arc, Mutex};
+    use std::time::Duration;
 
     #[derive(Default, Debug, Clone)]
     struct Mock {
@@ -276,11 +856,11 @@ mod tests {
     }
 
     impl Handler<DownloadRepo> for Mock {
-        type Result = Result<(), Error>;
+        type Result = Result<Downloaded, Error>;
 
         fn handle(&mut self, msg: DownloadRepo, _ctx: &mut Self::Context) -> Self::Result {
             self.repos.lock().unwrap().push(msg);
-            Ok(())
+            Ok(Downloaded::default())
         }
     }
 
@@ -291,33 +871,132 @@ mod tests {
     }
 
     impl Handler<DownloadRepo> for DodgyActor {
-        type Result = Result<(), Error>;
+        type Result = Result<Downloaded, Error>;
 
         fn handle(&mut self, _msg: DownloadRepo, _ctx: &mut Self::Context) -> Self::Result {
             Err(failure::err_msg("Oops.."))
         }
     }
 
+    struct EmptyWikis;
+
+    impl Actor for EmptyWikis {
+        type Context = Context<EmptyWikis>;
+    }
+
+    impl Handler<DownloadRepo> for EmptyWikis {
+        type Result = Result<Downloaded, Error>;
+
+        fn handle(&mut self, _msg: DownloadRepo, _ctx: &mut Self::Context) -> Self::Result {
+            Err(EmptyWiki.into())
+        }
+    }
+
+    struct BrokenMirror;
+
+    impl Actor for BrokenMirror {
+        type Context = Context<BrokenMirror>;
+    }
+
+    impl Handler<DownloadRepo> for BrokenMirror {
+        type Result = Result<Downloaded, Error>;
+
+        fn handle(&mut self, _msg: DownloadRepo, _ctx: &mut Self::Context) -> Self::Result {
+            Ok(Downloaded {
+                mirror_failure: Some(String::from("Connection refused")),
+            })
+        }
+    }
+
+    /// Fails the first download and takes a while to finish the others, so
+    /// they're still queued when the driver bails.
+    struct FailFirst {
+        received: usize,
+        cancelled: Arc<AtomicUsize>,
+    }
+
+    impl Actor for FailFirst {
+        type Context = Context<FailFirst>;
+    }
+
+    impl Handler<DownloadRepo> for FailFirst {
+        type Result = ResponseFuture<Downloaded, Error>;
+
+        fn handle(&mut self, msg: DownloadRepo, ctx: &mut Self::Context) -> Self::Result {
+            self.received += 1;
+            if self.received == 1 {
+                return Box::new(future::err::<Downloaded, _>(failure::err_msg("Oops..")));
+            }
+
+            let (tx, rx) = oneshot::channel();
+            let cancelled = Arc::clone(&self.cancelled);
+            ctx.run_later(Duration::from_millis(50), move |_, _| {
+                let outcome: Result<Downloaded, Error> = if msg.1.is_cancelled() {
+                    cancelled.fetch_add(1, Ordering::SeqCst);
+                    Err(Cancelled.into())
+                } else {
+                    Ok(Downloaded::default())
+                };
+                let _ = tx.send(outcome);
+            });
+
+            Box::new(rx.map_err(Error::from).and_then(|outcome| outcome))
+        }
+    }
+
     struct MockProvider {
         repos: Vec<GitRepo>,
     }
 
     impl Provider for MockProvider {
-        fn repositories(&self) -> Box<dyn Stream<Item = GitRepo, Error = Error>> {
+        fn repositories(&mut self) -> Box<dyn Stream<Item = GitRepo, Error = Error>> {
             Box::new(stream::iter_ok(self.repos.clone()))
         }
     }
 
+    #[test]
+    fn list_repositories_without_downloading_them() {
+        let mut cfg = Config::default();
+        cfg.general.blacklist = vec![PathBuf::from("/2")];
+        let repos = vec![
+            mock_repo("/1", "git@github.com:foo/one.git"),
+            mock_repo("/2", "git@github.com:foo/two.git"),
+            mock_repo("/1", "https://github.com/foo/one"),
+            mock_repo("/1", "git@github.com:bar/one.git"),
+        ];
+        let providers: Vec<Box<dyn Provider>> = vec![Box::new(MockProvider { repos })];
+
+        let got = list_from(providers, &cfg, &Logger::root(Discard, o!())).unwrap();
+
+        let decisions: Vec<_> = got.iter().map(|l| l.decision).collect();
+        assert_eq!(
+            decisions,
+            vec![
+                ListDecision::Include,
+                ListDecision::Skip,
+                ListDecision::Duplicate,
+                ListDecision::Conflict,
+            ]
+        );
+        assert_eq!(got[1].reason.as_deref(), Some("blacklisted"));
+    }
+
     #[test]
     fn run_driver_to_completion() {
         let should_be = vec![
             GitRepo {
+                provider: String::from("mock"),
                 dest_dir: PathBuf::from("/1"),
                 ssh_url: String::from("1"),
+                wiki: false,
+                metadata: Default::default(),
             },
             GitRepo {
+                provider: String::from("mock"),
                 dest_dir: PathBuf::from("/2"),
                 ssh_url: String::from("2"),
+                wiki: false,
+                metadata: Default::default(),
             },
         ];
 
@@ -330,7 +1009,7 @@ mod tests {
             repos: Arc::clone(&repos),
         }
         .start();
-        let mut driver = Driver::new_with_recipient(cfg, logger, mock.recipient());
+        let mut driver = Driver::new_with_recipient(cfg, logger, mock.recipient()).unwrap();
         driver.register(MockProvider {
             repos: should_be.clone(),
         });
@@ -347,6 +1026,56 @@ mod tests {
         assert_eq!(got, should_be);
     }
 
+    struct WaitForFinish {
+        got: Arc<Mutex<Option<Finished>>>,
+    }
+
+    impl Actor for WaitForFinish {
+        type Context = Context<WaitForFinish>;
+    }
+
+    impl Handler<Finished> for WaitForFinish {
+        type Result = ();
+
+        fn handle(&mut self, msg: Finished, _ctx: &mut Self::Context) {
+            *self.got.lock().unwrap() = Some(msg);
+            System::current().stop();
+        }
+    }
+
+    #[test]
+    fn tell_the_daemon_when_a_backup_finishes() {
+        let got = Arc::new(Mutex::new(None));
+        let sys = System::new("test");
+        let waiter = WaitForFinish {
+            got: Arc::clone(&got),
+        }
+        .start();
+        let mut driver = Driver::new_with_recipient(
+            Config::default(),
+            Logger::root(Discard, o!()),
+            Mock::default().start().recipient(),
+        )
+        .unwrap();
+        driver.register(MockProvider {
+            repos: vec![mock_repo("/1", "1"), mock_repo("/2", "2")],
+        });
+        driver.notify_when_finished(waiter.recipient());
+        driver.start();
+
+        assert!(sys.run().is_ok());
+
+        let finished = got
+            .lock()
+            .unwrap()
+            .take()
+            .expect("The backup should have finished");
+        assert_eq!(finished.stats.success, 2);
+        assert_eq!(finished.stats.total_repos, 2);
+        assert_eq!(finished.discovered["mock"], 2);
+        assert_eq!(finished.exit_code, 0);
+    }
+
     #[test]
     fn stop_after_encountering_the_error_threshold() {
         let mut cfg = Config::default();
@@ -357,16 +1086,23 @@ mod tests {
             cfg,
             Logger::root(Discard, o!()),
             DodgyActor.start().recipient(),
-        );
+        )
+        .unwrap();
         driver.register(MockProvider {
             repos: vec![
                 GitRepo {
+                    provider: String::from("mock"),
                     dest_dir: PathBuf::from("/1"),
                     ssh_url: String::from("1"),
+                    wiki: false,
+                    metadata: Default::default(),
                 },
                 GitRepo {
+                    provider: String::from("mock"),
                     dest_dir: PathBuf::from("/1"),
                     ssh_url: String::from("1"),
+                    wiki: false,
+                    metadata: Default::default(),
                 },
             ],
         });
@@ -374,4 +1110,203 @@ mod tests {
 
         assert!(sys.run().is_err());
     }
+
+    #[test]
+    fn bailing_cancels_queued_downloads_and_waits_for_them() {
+        let mut cfg = Config::default();
+        cfg.general.error_threshold = 1;
+        let got = Arc::new(Mutex::new(None));
+        let cancelled = Arc::new(AtomicUsize::new(0));
+
+        let sys = System::new("test");
+        let waiter = WaitForFinish {
+            got: Arc::clone(&got),
+        }
+        .start();
+        let pool = FailFirst {
+            received: 0,
+            cancelled: Arc::clone(&cancelled),
+        }
+        .start();
+        let mut driver =
+            Driver::new_with_recipient(cfg, Logger::root(Discard, o!()), pool.recipient()).unwrap();
+        driver.register(MockProvider {
+            repos: vec![
+                mock_repo("/1", "1"),
+                mock_repo("/2", "2"),
+                mock_repo("/3", "3"),
+            ],
+        });
+        driver.notify_when_finished(waiter.recipient());
+        driver.start();
+
+        assert!(sys.run().is_ok());
+
+        let finished = got
+            .lock()
+            .unwrap()
+            .take()
+            .expect("The backup should have finished");
+        assert!(finished.aborted);
+        assert_eq!(finished.exit_code, 1);
+        assert_eq!(finished.stats.error_count, 1);
+        // the daemon is only told once everything queued was called off
+        let cancelled = cancelled.load(Ordering::SeqCst);
+        assert!(cancelled > 0);
+        assert_eq!(cancelled, finished.stats.total_repos - 1);
+    }
+
+    #[test]
+    fn empty_wikis_arent_errors() {
+        let mut cfg = Config::default();
+        cfg.general.error_threshold = 1;
+
+        let sys = System::new("test");
+        let mut driver = Driver::new_with_recipient(
+            cfg,
+            Logger::root(Discard, o!()),
+            EmptyWikis.start().recipient(),
+        )
+        .unwrap();
+        driver.register(MockProvider {
+            repos: vec![mock_repo("/1.wiki", "git@github.com:org/1.wiki.git")],
+        });
+        driver.start();
+
+        assert!(sys.run().is_ok());
+    }
+
+    #[test]
+    fn mirror_failures_dont_fail_the_backup() {
+        let mut cfg = Config::default();
+        cfg.general.error_threshold = 1;
+        let got = Arc::new(Mutex::new(None));
+
+        let sys = System::new("test");
+        let waiter = WaitForFinish {
+            got: Arc::clone(&got),
+        }
+        .start();
+        let mut driver = Driver::new_with_recipient(
+            cfg,
+            Logger::root(Discard, o!()),
+            BrokenMirror.start().recipient(),
+        )
+        .unwrap();
+        driver.register(MockProvider {
+            repos: vec![mock_repo("/1", "1"), mock_repo("/2", "2")],
+        });
+        driver.notify_when_finished(waiter.recipient());
+        driver.start();
+
+        assert!(sys.run().is_ok());
+
+        let finished = got.lock().unwrap().take().unwrap();
+        assert_eq!(finished.stats.success, 2);
+        assert_eq!(finished.stats.mirror_failures, 2);
+        assert_eq!(finished.stats.error_count, 0);
+        assert_eq!(finished.exit_code, 0);
+    }
+
+    #[test]
+    fn every_config_field_is_documented() {
+        for section in known_sections() {
+            let missing = undocumented_fields(&section);
+            assert!(missing.is_empty(), "[{}] {:?}", section.key, missing);
+        }
+    }
+
+    #[test]
+    fn the_example_config_passes_validation() {
+        let example = example_config().unwrap();
+
+        assert_eq!(config::check(&example, &known_sections()), Vec::new());
+    }
+
+    fn mock_repo(dest: &str, url: &str) -> GitRepo {
+        GitRepo {
+            provider: String::from("mock"),
+            dest_dir: PathBuf::from(dest),
+            ssh_url: String::from(url),
+            wiki: false,
+            metadata: Default::default(),
+        }
+    }
+
+    #[test]
+    fn duplicate_repositories_are_only_downloaded_once() {
+        let repos: Arc<Mutex<Vec<DownloadRepo>>> = Default::default();
+
+        let sys = System::new("test");
+        let mock = Mock {
+            repos: Arc::clone(&repos),
+        }
+        .start();
+        let mut driver = Driver::new_with_recipient(
+            Config::default(),
+            Logger::root(Discard, o!()),
+            mock.recipient(),
+        )
+        .unwrap();
+        driver.register(MockProvider {
+            repos: vec![
+                mock_repo("/1", "git@github.com:org/project.git"),
+                mock_repo("/2", "git@github.com:org/other.git"),
+            ],
+        });
+        driver.register(MockProvider {
+            repos: vec![mock_repo("/1", "https://github.com/org/project")],
+        });
+        driver.start();
+
+        assert!(sys.run().is_ok());
+
+        let got: Vec<_> = repos
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|r| r.0.dest_dir.clone())
+            .collect();
+        assert_eq!(got, vec![PathBuf::from("/1"), PathBuf::from("/2")]);
+    }
+
+    #[test]
+    fn different_repositories_with_the_same_destination_is_an_error() {
+        let sys = System::new("test");
+        let mut driver = Driver::new_with_recipient(
+            Config::default(),
+            Logger::root(Discard, o!()),
+            Mock::default().start().recipient(),
+        )
+        .unwrap();
+        driver.register(MockProvider {
+            repos: vec![
+                mock_repo("/1", "git@github.com:org/project.git"),
+                mock_repo("/1", "git@gitlab.com:org/project.git"),
+            ],
+        });
+        driver.start();
+
+        assert!(sys.run().is_err());
+    }
+
+    #[test]
+    fn stop_when_every_repository_is_ignored() {
+        let mut cfg = Config::default();
+        cfg.general.blacklist.push(PathBuf::from("/1"));
+
+        let sys = System::new("test");
+        let mut driver = Driver::new_with_recipient(
+            cfg,
+            Logger::root(Discard, o!()),
+            Mock::default().start().recipient(),
+        )
+        .unwrap();
+        driver.register(MockProvider {
+            repos: vec![mock_repo("/1", "1")],
+        });
+        driver.start();
+
+        assert!(sys.run().is_ok());
+    }
 }
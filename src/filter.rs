@@ -0,0 +1,529 @@
+use crate::config::{Config, Fields};
+use crate::git::GitRepo;
+use chrono::{DateTime, Utc};
+use failure::{Error, ResultExt};
+use globset::{GlobBuilder, GlobMatcher};
+use regex::Regex;
+use serde::de::{Deserialize, Deserializer, Error as DeError};
+use serde::ser::{Serialize, Serializer};
+use std::collections::HashMap;
+use std::fmt::{self, Debug, Formatter};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Rules for deciding which repositories should be backed up.
+///
+/// Patterns are matched against a repository's destination directory (e.g.
+/// `github.com/Michael-F-Bryan/repo-backup`) and are globs by default. Prefix
+/// a pattern with `regex:` to use a regular expression instead.
+///
+/// The metadata-based filters only apply when the provider reports the
+/// corresponding piece of information, so a repository is never skipped
+/// because its size or topics are unknown.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FilterConfig {
+    /// If not empty, only repositories matching at least one of these
+    /// patterns will be backed up.
+    pub include: Vec<Pattern>,
+    /// Repositories matching any of these patterns are never backed up.
+    pub exclude: Vec<Pattern>,
+    /// Skip repositories which are forks of another repository.
+    pub skip_forks: bool,
+    /// Skip private repositories.
+    pub skip_private: bool,
+    /// Skip repositories larger than this many megabytes.
+    pub max_size_mb: Option<u64>,
+    /// If not empty, only back up repositories tagged with at least one of
+    /// these topics.
+    pub only_topics: Vec<String>,
+    /// If not empty, only back up repositories whose primary language is one
+    /// of these (case insensitive).
+    pub only_languages: Vec<String>,
+    /// Skip archived repositories which haven't been pushed to in this long.
+    pub skip_archived_after: Option<Age>,
+}
+
+impl FilterConfig {
+    pub(crate) const DESCRIPTION: &'static str =
+        "Decide which repositories to back up. Patterns are globs (e.g. \
+         \"github.com/some-org/*\") matched against the destination directory, \
+         or regular expressions when prefixed with \"regex:\".";
+    pub(crate) const FIELDS: Fields = &[
+        (
+            "include",
+            "If not empty, only back up repositories matching one of these patterns.",
+        ),
+        (
+            "exclude",
+            "Never back up repositories matching one of these patterns.",
+        ),
+        ("skip_forks", "Skip repositories which are forks."),
+        ("skip_private", "Skip private repositories."),
+        (
+            "max_size_mb",
+            "Skip repositories larger than this many megabytes.",
+        ),
+        (
+            "only_topics",
+            "If not empty, only back up repositories tagged with one of these \
+             topics.",
+        ),
+        (
+            "only_languages",
+            "If not empty, only back up repositories whose primary language is \
+             one of these.",
+        ),
+        (
+            "skip_archived_after",
+            "Skip archived repositories which haven't been pushed to in this \
+             long (e.g. \"2y\" or \"6months\").",
+        ),
+    ];
+
+    fn check(&self, repo: &GitRepo, now: DateTime<Utc>) -> Decision {
+        let path = normalized(&repo.dest_dir);
+        let meta = &repo.metadata;
+
+        if let Some(pattern) = self.exclude.iter().find(|p| p.matches(&path)) {
+            return Decision::Skip(format!("excluded by \"{}\"", pattern));
+        }
+
+        if !self.include.is_empty() && !self.include.iter().any(|p| p.matches(&path)) {
+            return Decision::Skip(String::from("not in the include list"));
+        }
+
+        if self.skip_forks && meta.fork == Some(true) {
+            return Decision::Skip(String::from("fork"));
+        }
+
+        if self.skip_private && meta.private == Some(true) {
+            return Decision::Skip(String::from("private"));
+        }
+
+        if let (Some(max), Some(size_kb)) = (self.max_size_mb, meta.size_kb) {
+            if size_kb > max * 1024 {
+                return Decision::Skip(format!("larger than {} MB", max));
+            }
+        }
+
+        if let Some(ref topics) = meta.topics {
+            if !self.only_topics.is_empty() && !topics.iter().any(|t| self.only_topics.contains(t))
+            {
+                return Decision::Skip(String::from("doesn't have a wanted topic"));
+            }
+        }
+
+        if let Some(ref language) = meta.language {
+            let wanted = self.only_languages.is_empty()
+                || self
+                    .only_languages
+                    .iter()
+                    .any(|l| l.eq_ignore_ascii_case(language));
+
+            if !wanted {
+                return Decision::Skip(format!("written in {}", language));
+            }
+        }
+
+        if let (Some(ref age), Some(true), Some(last_push)) =
+            (&self.skip_archived_after, meta.archived, meta.last_push)
+        {
+            if now
+                .signed_duration_since(last_push)
+                .to_std()
+                .unwrap_or_default()
+                > age.0
+            {
+                return Decision::Skip(format!("archived and not pushed to in {}", age));
+            }
+        }
+
+        Decision::Include
+    }
+}
+
+/// Should a repository be backed up?
+#[derive(Debug, Clone, PartialEq)]
+pub enum Decision {
+    Include,
+    /// Skip the repository, with a human-readable reason why.
+    Skip(String),
+}
+
+/// The global filter, blacklist and per-provider filters, combined.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct Filters {
+    blacklist: Vec<PathBuf>,
+    global: FilterConfig,
+    providers: HashMap<String, FilterConfig>,
+}
+
+impl Filters {
+    pub fn from_config(cfg: &Config) -> Result<Filters, Error> {
+        let mut providers = HashMap::new();
+
+        for (key, section) in &cfg.rest {
+            if let Some(filter) = section.get("filter") {
+                let filter: FilterConfig = filter
+                    .clone()
+                    .try_into()
+                    .with_context(|_| format!("Unable to parse the [{}.filter] section", key))?;
+                providers.insert(key.clone(), filter);
+            }
+        }
+
+        Ok(Filters {
+            blacklist: cfg.general.blacklist.clone(),
+            global: cfg.general.filter.clone(),
+            providers,
+        })
+    }
+
+    pub fn check(&self, repo: &GitRepo) -> Decision {
+        self.check_at(repo, Utc::now())
+    }
+
+    /// Does any filter which applies to this provider look at topics?
+    pub fn wants_topics(&self, provider: &str) -> bool {
+        self.applicable(provider)
+            .any(|filter| !filter.only_topics.is_empty())
+    }
+
+    /// Does any filter which applies to this provider look at languages?
+    pub fn wants_languages(&self, provider: &str) -> bool {
+        self.applicable(provider)
+            .any(|filter| !filter.only_languages.is_empty())
+    }
+
+    fn applicable<'a>(&'a self, provider: &str) -> impl Iterator<Item = &'a FilterConfig> + 'a {
+        Some(&self.global)
+            .into_iter()
+            .chain(self.providers.get(provider))
+    }
+
+    fn check_at(&self, repo: &GitRepo, now: DateTime<Utc>) -> Decision {
+        if self.blacklist.iter().any(|item| item == &repo.dest_dir) {
+            return Decision::Skip(String::from("blacklisted"));
+        }
+
+        match self.global.check(repo, now) {
+            Decision::Include => {}
+            skip => return skip,
+        }
+
+        match self.providers.get(&repo.provider) {
+            Some(filter) => filter.check(repo, now),
+            None => Decision::Include,
+        }
+    }
+}
+
+/// A glob or regular expression used to match repositories.
+#[derive(Clone)]
+pub struct Pattern {
+    source: String,
+    matcher: Matcher,
+}
+
+#[derive(Clone)]
+enum Matcher {
+    Glob(GlobMatcher),
+    Regex(Regex),
+}
+
+impl Pattern {
+    const REGEX_PREFIX: &'static str = "regex:";
+
+    pub fn new(source: &str) -> Result<Pattern, Error> {
+        let matcher = if source.starts_with(Pattern::REGEX_PREFIX) {
+            let re = Regex::new(&source[Pattern::REGEX_PREFIX.len()..])?;
+            Matcher::Regex(re)
+        } else {
+            let glob = GlobBuilder::new(source).literal_separator(true).build()?;
+            Matcher::Glob(glob.compile_matcher())
+        };
+
+        Ok(Pattern {
+            source: source.to_string(),
+            matcher,
+        })
+    }
+
+    /// Does this pattern match a path (using `/` as the separator)?
+    pub fn matches(&self, path: &str) -> bool {
+        match self.matcher {
+            Matcher::Glob(ref glob) => glob.is_match(path),
+            Matcher::Regex(ref re) => re.is_match(path),
+        }
+    }
+}
+
+/// How long ago something happened, written in a human-friendly way (e.g. `2y`
+/// or `6months 2weeks`).
+#[derive(Clone)]
+pub struct Age(pub Duration, String);
+
+impl FromStr for Age {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Age, Error> {
+        let duration = humantime::parse_duration(s).context("Invalid duration")?;
+        Ok(Age(duration, s.to_string()))
+    }
+}
+
+impl fmt::Display for Age {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.1)
+    }
+}
+
+impl Debug for Age {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Age").field(&self.1).finish()
+    }
+}
+
+impl PartialEq for Age {
+    fn eq(&self, other: &Age) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl Serialize for Age {
+    fn serialize<S: Serializer>(&self, ser: S) -> Result<S::Ok, S::Error> {
+        self.1.serialize(ser)
+    }
+}
+
+impl<'de> Deserialize<'de> for Age {
+    fn deserialize<D: Deserializer<'de>>(de: D) -> Result<Age, D::Error> {
+        let source = String::deserialize(de)?;
+        source
+            .parse()
+            .map_err(|e| D::Error::custom(format!("invalid duration \"{}\": {}", source, e)))
+    }
+}
+
+/// Does a path match any of the patterns? An empty list matches everything.
+pub(crate) fn matches_any(patterns: &[Pattern], path: &Path) -> bool {
+    let path = normalized(path);
+    patterns.is_empty() || patterns.iter().any(|p| p.matches(&path))
+}
+
+/// Get a path as a string using `/` as the separator, regardless of platform.
+pub(crate) fn normalized(path: &Path) -> String {
+    path.components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+impl FromStr for Pattern {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Pattern, Error> {
+        Pattern::new(s)
+    }
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl Debug for Pattern {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Pattern").field(&self.source).finish()
+    }
+}
+
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Pattern) -> bool {
+        self.source == other.source
+    }
+}
+
+impl Serialize for Pattern {
+    fn serialize<S: Serializer>(&self, ser: S) -> Result<S::Ok, S::Error> {
+        self.source.serialize(ser)
+    }
+}
+
+impl<'de> Deserialize<'de> for Pattern {
+    fn deserialize<D: Deserializer<'de>>(de: D) -> Result<Pattern, D::Error> {
+        let source = String::deserialize(de)?;
+        Pattern::new(&source)
+            .map_err(|e| D::Error::custom(format!("invalid pattern \"{}\": {}", source, e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn repo(provider: &str, dest: &str) -> GitRepo {
+        GitRepo {
+            provider: provider.to_string(),
+            dest_dir: PathBuf::from(dest),
+            ssh_url: String::new(),
+            wiki: false,
+            metadata: Default::default(),
+        }
+    }
+
+    fn patterns(items: &[&str]) -> Vec<Pattern> {
+        items.iter().map(|p| p.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn globs_dont_cross_directories() {
+        let pattern = Pattern::new("github.com/some-org/*").unwrap();
+
+        assert!(pattern.matches("github.com/some-org/project"));
+        assert!(!pattern.matches("github.com/some-org/nested/project"));
+        assert!(!pattern.matches("github.com/other-org/project"));
+    }
+
+    #[test]
+    fn regex_patterns() {
+        let pattern = Pattern::new(r"regex:^github\.com/[^/]+/.*-rs$").unwrap();
+
+        assert!(pattern.matches("github.com/someone/bindings-rs"));
+        assert!(!pattern.matches("gitlab.com/someone/bindings-rs"));
+    }
+
+    #[test]
+    fn invalid_patterns_are_rejected_when_parsing() {
+        let got = toml::from_str::<FilterConfig>("include = ['regex:(unclosed']");
+
+        assert!(got.is_err());
+    }
+
+    #[test]
+    fn exclude_wins_over_include() {
+        let filter = FilterConfig {
+            include: patterns(&["github.com/*/*"]),
+            exclude: patterns(&["github.com/some-org/secret"]),
+            ..Default::default()
+        };
+        let now = Utc::now();
+
+        assert_eq!(
+            filter.check(&repo("github", "github.com/some-org/project"), now),
+            Decision::Include
+        );
+        assert!(
+            filter.check(&repo("github", "github.com/some-org/secret"), now) != Decision::Include
+        );
+        assert!(
+            filter.check(&repo("gitlab", "gitlab.com/some-org/project"), now) != Decision::Include
+        );
+    }
+
+    #[test]
+    fn provider_filters_only_apply_to_their_provider() {
+        let mut filters = Filters::default();
+        filters.providers.insert(
+            String::from("github"),
+            FilterConfig {
+                include: patterns(&["github.com/first/*", "github.com/second/*"]),
+                ..Default::default()
+            },
+        );
+
+        assert_eq!(
+            filters.check(&repo("github", "github.com/first/project")),
+            Decision::Include
+        );
+        assert!(filters.check(&repo("github", "github.com/third/project")) != Decision::Include);
+        assert_eq!(
+            filters.check(&repo("gitlab", "gitlab.com/third/project")),
+            Decision::Include
+        );
+    }
+
+    #[test]
+    fn invalid_provider_filters_are_errors() {
+        let mut cfg = Config::default();
+        cfg.rest.insert(
+            String::from("github"),
+            toml::from_str("[filter]\nonly_owner = ['typo']").unwrap(),
+        );
+
+        assert!(Filters::from_config(&cfg).is_err());
+    }
+
+    #[test]
+    fn the_blacklist_still_works() {
+        let mut cfg = Config::default();
+        cfg.general
+            .blacklist
+            .push(PathBuf::from("github.com/someone/huge"));
+
+        let filters = Filters::from_config(&cfg).unwrap();
+
+        assert!(filters.check(&repo("github", "github.com/someone/huge")) != Decision::Include);
+        assert_eq!(
+            filters.check(&repo("github", "github.com/someone/small")),
+            Decision::Include
+        );
+    }
+
+    #[test]
+    fn skip_forks_but_keep_originals() {
+        let filter = FilterConfig {
+            skip_forks: true,
+            ..Default::default()
+        };
+        let mut fork = repo("github", "github.com/me/their-project");
+        fork.metadata.fork = Some(true);
+        let mut original = repo("github", "github.com/me/my-project");
+        original.metadata.fork = Some(false);
+        let unknown = repo("github", "github.com/me/who-knows");
+        let now = Utc::now();
+
+        assert!(filter.check(&fork, now) != Decision::Include);
+        assert_eq!(filter.check(&original, now), Decision::Include);
+        assert_eq!(filter.check(&unknown, now), Decision::Include);
+    }
+
+    #[test]
+    fn filter_by_size_and_topic() {
+        let filter: FilterConfig =
+            toml::from_str("max_size_mb = 500\nonly_topics = ['backup']").unwrap();
+        let mut huge = repo("gitlab", "gitlab.com/me/huge");
+        huge.metadata.size_kb = Some(600 * 1024);
+        huge.metadata.topics = Some(vec![String::from("backup")]);
+        let mut untagged = repo("gitlab", "gitlab.com/me/untagged");
+        untagged.metadata.topics = Some(Vec::new());
+        let mut wanted = repo("gitlab", "gitlab.com/me/wanted");
+        wanted.metadata.size_kb = Some(20);
+        wanted.metadata.topics = Some(vec![String::from("backup"), String::from("rust")]);
+        let now = Utc::now();
+
+        assert!(filter.check(&huge, now) != Decision::Include);
+        assert!(filter.check(&untagged, now) != Decision::Include);
+        assert_eq!(filter.check(&wanted, now), Decision::Include);
+    }
+
+    #[test]
+    fn skip_repos_archived_a_long_time_ago() {
+        let filter: FilterConfig = toml::from_str("skip_archived_after = '2y'").unwrap();
+        let now = Utc::now();
+        let mut old = repo("github", "github.com/me/old");
+        old.metadata.archived = Some(true);
+        old.metadata.last_push = Some(now - chrono::Duration::days(3 * 365));
+        let mut recent = old.clone();
+        recent.metadata.last_push = Some(now - chrono::Duration::days(30));
+        let mut active = old.clone();
+        active.metadata.archived = Some(false);
+
+        assert!(filter.check(&old, now) != Decision::Include);
+        assert_eq!(filter.check(&recent, now), Decision::Include);
+        assert_eq!(filter.check(&active, now), Decision::Include);
+    }
+}
@@ -1,19 +1,281 @@
+use crate::alternates;
+use crate::archive;
+use crate::bundle::{self, Refs};
+use crate::config::General;
+use crate::maintenance;
+use crate::migrate::Migration;
+use crate::providers::GitHubConfig;
+use crate::snapshot;
+use crate::state::{self, RepoState};
+use crate::upload;
+use crate::verify;
 use actix::{Actor, Handler, Message, SyncContext};
+use chrono::{DateTime, Utc};
 use failure::{Error, ResultExt};
 use slog::Logger;
+use std::collections::{BTreeSet, HashSet};
+use std::ffi::OsStr;
 use std::fmt::{self, Display, Formatter};
+use std::fs;
 use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
 
 #[derive(Debug, Clone)]
 pub(crate) struct GitClone {
     logger: Logger,
-    root: PathBuf,
+    cfg: General,
+    migration: Option<Arc<Migration>>,
+    locks: RepoLocks,
 }
 
 impl GitClone {
-    pub fn new(root: PathBuf, logger: Logger) -> GitClone {
-        GitClone { root, logger }
+    pub fn new(cfg: General, migration: Option<Arc<Migration>>, logger: Logger) -> GitClone {
+        GitClone {
+            cfg,
+            migration,
+            logger,
+            locks: RepoLocks::default(),
+        }
+    }
+
+    /// Share repository locks with the other workers in a pool.
+    pub fn with_locks(self, locks: RepoLocks) -> GitClone {
+        GitClone { locks, ..self }
+    }
+
+    /// The shared object store new clones should borrow from, if any.
+    fn reference_store(&self) -> Result<Option<PathBuf>, Error> {
+        if !self.cfg.alternates.enabled {
+            return Ok(None);
+        }
+
+        let _store = self.locks.lock(&self.cfg.alternates.store);
+        let store = alternates::ensure_store(&self.cfg.alternates, &self.cfg.root)
+            .context("Unable to create the shared object store")?;
+        Ok(Some(store))
+    }
+
+    /// Everything which should happen after a repository is successfully
+    /// downloaded.
+    fn after_download(&self, repo: &GitRepo, just_cloned: bool) -> Result<(), Error> {
+        let dest_dir = &repo.dest_dir;
+        let mut bundle_changed = false;
+        let mut archive_changed = false;
+
+        if self.cfg.alternates.enabled {
+            // git doesn't like several fetches into the store at once (they
+            // all want packed-refs.lock)
+            let _store = self.locks.lock(&self.cfg.alternates.store);
+            let repacked =
+                alternates::share(&self.cfg.alternates, &self.cfg.root, dest_dir, just_cloned)
+                    .context("Unable to share objects with the other repositories")?;
+
+            if repacked {
+                debug!(self.logger, "Removed objects which are in the shared object store";
+                    "dest-dir" => dest_dir.display());
+            }
+        } else {
+            let dissociated =
+                alternates::dissociate(&self.cfg.alternates, &self.cfg.root, dest_dir)
+                    .context("Unable to stop borrowing from the shared object store")?;
+
+            if dissociated {
+                info!(self.logger, "Copied borrowed objects back into the repository";
+                    "dest-dir" => dest_dir.display());
+            }
+        }
+
+        if self.cfg.maintenance.enabled {
+            let reason = maintenance::run(
+                &self.cfg.maintenance,
+                &self.cfg.root,
+                dest_dir,
+                just_cloned,
+                Utc::now(),
+            )
+            .context("Unable to clean up the repository")?;
+
+            if let Some(reason) = reason {
+                debug!(self.logger, "Cleaned up the repository";
+                    "dest-dir" => dest_dir.display(),
+                    "reason" => reason);
+            }
+        }
+
+        if self.cfg.bundle.enabled {
+            let created = bundle::export(&self.cfg.bundle, &self.cfg.root, dest_dir)
+                .context("Unable to export a bundle")?;
+
+            if let Some(bundle) = created {
+                debug!(self.logger, "Created a bundle";
+                    "dest-dir" => dest_dir.display(),
+                    "bundle" => bundle.display());
+                bundle_changed = true;
+            }
+        }
+
+        if self.cfg.retention.enabled {
+            let today = Utc::today().naive_utc();
+            let created = snapshot::take(&self.cfg.retention, &self.cfg.root, dest_dir, today)
+                .context("Unable to take a snapshot")?;
+
+            if let Some(snapshot) = created {
+                debug!(self.logger, "Took a snapshot";
+                    "dest-dir" => dest_dir.display(),
+                    "snapshot" => snapshot.display());
+            }
+        }
+
+        if self.cfg.archive.enabled {
+            let created = archive::export(
+                &self.cfg.archive,
+                &self.cfg.bundle,
+                &self.cfg.root,
+                dest_dir,
+                bundle_changed,
+            )
+            .context("Unable to create an encrypted archive")?;
+
+            if let Some(archive) = created {
+                debug!(self.logger, "Created an encrypted archive";
+                    "dest-dir" => dest_dir.display(),
+                    "archive" => archive.display());
+                archive_changed = true;
+            }
+        }
+
+        if self.cfg.upload.enabled {
+            let mut files = Vec::new();
+            if self.cfg.bundle.enabled {
+                files.push((
+                    bundle::bundle_path(&self.cfg.bundle, &self.cfg.root, dest_dir),
+                    bundle_changed,
+                ));
+            }
+            if self.cfg.archive.enabled {
+                files.push((
+                    archive::archive_path(&self.cfg.archive, &self.cfg.root, dest_dir),
+                    archive_changed,
+                ));
+            }
+
+            let uploaded = upload::upload(&self.cfg.upload, &self.cfg.root, dest_dir, &files)
+                .context("Unable to upload to object storage")?;
+            debug!(self.logger, "Uploaded to object storage";
+                "dest-dir" => dest_dir.display(),
+                "uploaded" => uploaded);
+        }
+
+        Ok(())
+    }
+
+    /// Push a backed up repository to its mirror, if it has one, returning
+    /// why that failed.
+    ///
+    /// The repository itself is safely backed up by now, so a broken mirror
+    /// shouldn't count as a failed backup.
+    fn mirror(&self, repo: &GitRepo) -> Option<String> {
+        let migration = self.migration.as_ref().filter(|m| m.wants(repo))?;
+
+        match migration.mirror(&self.cfg.root, repo) {
+            Ok(url) => {
+                debug!(self.logger, "Mirrored the repository";
+                    "dest-dir" => repo.dest_dir.display(),
+                    "url" => url);
+                None
+            }
+            Err(e) => {
+                let causes: Vec<_> = e.iter_chain().map(|c| c.to_string()).collect();
+                Some(causes.join(": "))
+            }
+        }
+    }
+
+    /// Move a backup which was saved under an older name (e.g. before a
+    /// gist's description changed) to where the repository belongs now.
+    fn adopt_renamed(&self, repo: &GitRepo, repo_dir: &Path) -> Result<(), Error> {
+        let stable_name = match repo.metadata.stable_name {
+            Some(ref name) if !repo_dir.exists() => name,
+            _ => return Ok(()),
+        };
+
+        let previous = match find_renamed(repo_dir, stable_name) {
+            Some(previous) => previous,
+            None => return Ok(()),
+        };
+
+        fs::rename(&previous, repo_dir).with_context(|_| {
+            format!(
+                "Unable to move \"{}\" to \"{}\"",
+                previous.display(),
+                repo_dir.display()
+            )
+        })?;
+
+        let previous_dest = repo
+            .dest_dir
+            .with_file_name(previous.file_name().unwrap_or_default());
+        state::rename(&self.cfg.root, &previous_dest, &repo.dest_dir)?;
+
+        info!(self.logger, "Moved a renamed repository's backup";
+            "from" => previous_dest.display(),
+            "to" => repo.dest_dir.display());
+
+        Ok(())
+    }
+
+    /// Remember how backing up a repository went (e.g. for
+    /// `repo-backup status`).
+    fn record_outcome(
+        &self,
+        repo: &GitRepo,
+        outcome: &Result<(), Error>,
+        fetched: &Fetched,
+    ) -> Result<(), Error> {
+        let mut state = RepoState::load(&self.cfg.root, &repo.dest_dir)?;
+        let now = Utc::now();
+        let previous_attempt = state.last_attempt;
+
+        state.provider = Some(repo.provider.clone());
+        state.wiki = repo.is_wiki();
+        // webhooks don't tell us the description, so keep the last one
+        state.description = repo
+            .metadata
+            .description
+            .clone()
+            .or_else(|| state.description.take());
+        state.last_attempt = Some(now);
+
+        match outcome {
+            Ok(()) => {
+                state.last_success = Some(now);
+                state.last_error = None;
+                state.failing_since = None;
+                state.failure_notified = false;
+                state.fetch_seconds = Some(
+                    fetched.duration.as_secs() as f64
+                        + f64::from(fetched.duration.subsec_millis()) / 1000.0,
+                );
+                state.fetch_bytes = Some(fetched.bytes);
+            }
+            Err(e) => {
+                if state.failing_since.is_none() {
+                    // it was already failing before we kept track of when
+                    // it started
+                    let already_failing = state.last_error.is_some();
+                    state.failing_since =
+                        previous_attempt.filter(|_| already_failing).or(Some(now));
+                }
+
+                let causes: Vec<_> = e.iter_chain().map(|c| c.to_string()).collect();
+                state.last_error = Some(causes.join(": "));
+            }
+        }
+
+        state.save(&self.cfg.root, &repo.dest_dir)
     }
 }
 
@@ -22,53 +284,323 @@ impl Actor for GitClone {
 }
 
 impl Handler<DownloadRepo> for GitClone {
-    type Result = Result<(), Error>;
+    type Result = Result<Downloaded, Error>;
 
     fn handle(&mut self, msg: DownloadRepo, _ctx: &mut Self::Context) -> Self::Result {
-        let DownloadRepo(GitRepo { ssh_url, dest_dir }) = msg;
+        let DownloadRepo(repo, cancellation) = msg;
+        // the same repository may be queued twice (e.g. by a webhook)
+        let _lock = self.locks.lock(&repo.dest_dir);
+
+        if cancellation.is_cancelled() {
+            return Err(Cancelled.into());
+        }
 
         debug!(self.logger, "Downloading a repository";
-            "dest-dir" => dest_dir.display(),
-            "url" => &ssh_url,
+            "dest-dir" => repo.dest_dir.display(),
+            "url" => &repo.ssh_url,
             "thread-id" => format_args!("{:?}", std::thread::current().id()));
 
         // make sure the path is absolute
-        let dest_dir = self.root.join(dest_dir);
+        let repo_dir = self.cfg.root.join(&repo.dest_dir);
+
+        if let Err(e) = self.adopt_renamed(&repo, &repo_dir) {
+            warn!(self.logger, "Unable to move a renamed repository's backup";
+                "dest-dir" => repo.dest_dir.display(),
+                "error" => e.to_string());
+        }
+
+        let just_cloned = !repo_dir.exists();
+        let started = Instant::now();
+        let size_before = if just_cloned {
+            0
+        } else {
+            object_size(&repo_dir)
+        };
 
-        if dest_dir.exists() {
+        let outcome = if !just_cloned {
             debug!(self.logger, "Fetching updates"; 
-            "dir" => dest_dir.display());
-            fetch_updates(&dest_dir)
+            "dir" => repo_dir.display());
+            fetch_updates(&repo_dir)
         } else {
             debug!(self.logger, "Cloning into repo"; 
-            "dir" => dest_dir.display());
-            do_clone(&dest_dir, &ssh_url)
+            "dir" => repo_dir.display());
+            self.reference_store()
+                .and_then(|store| do_clone(&repo_dir, &repo.ssh_url, store.as_deref()))
+        };
+
+        let fetched = Fetched {
+            duration: started.elapsed(),
+            bytes: object_size(&repo_dir).saturating_sub(size_before) * 1024,
+        };
+
+        let outcome = match outcome {
+            // providers will happily say a wiki is enabled even when nobody
+            // has created the first page yet
+            Err(ref e) if repo.is_wiki() && is_missing_repo(e) => return Err(EmptyWiki.into()),
+            Err(e) => Err(e),
+            Ok(()) => self.after_download(&repo, just_cloned),
+        };
+
+        if let Err(e) = self.record_outcome(&repo, &outcome, &fetched) {
+            warn!(self.logger, "Unable to save the backup's outcome";
+                "dest-dir" => repo.dest_dir.display(),
+                "error" => e.to_string());
         }
+
+        outcome?;
+
+        Ok(Downloaded {
+            mirror_failure: self.mirror(&repo),
+        })
     }
 }
 
-/// Request that a repository is downloaded.
+impl Handler<VerifyRepo> for GitClone {
+    type Result = Result<verify::Report, Error>;
+
+    fn handle(&mut self, msg: VerifyRepo, _ctx: &mut Self::Context) -> Self::Result {
+        let VerifyRepo(dest_dir) = msg;
+
+        debug!(self.logger, "Verifying a repository";
+            "dest-dir" => dest_dir.display(),
+            "thread-id" => format_args!("{:?}", std::thread::current().id()));
+
+        verify::check(&self.cfg.root, &dest_dir)
+    }
+}
+
+/// How long a fetch (or clone) took and roughly how much it downloaded.
+#[derive(Debug, Copy, Clone, PartialEq)]
+struct Fetched {
+    duration: Duration,
+    bytes: u64,
+}
+
+/// The size of a repository's objects in kilobytes, or zero if it can't be
+/// found (e.g. because the clone failed).
+fn object_size(repo_dir: &Path) -> u64 {
+    count_objects(repo_dir).map(|c| c.size_kb).unwrap_or(0)
+}
+
+/// Makes sure only one worker touches a repository (or the shared object
+/// store) at a time.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct RepoLocks(Arc<(Mutex<HashSet<PathBuf>>, Condvar)>);
+
+impl RepoLocks {
+    /// Wait until nobody else is using the repository, then claim it until
+    /// the guard is dropped.
+    pub fn lock(&self, dest_dir: &Path) -> RepoGuard<'_> {
+        let (ref busy, ref released) = *self.0;
+        let mut busy = busy.lock().expect("The lock was poisoned");
+
+        while busy.contains(dest_dir) {
+            busy = released.wait(busy).expect("The lock was poisoned");
+        }
+        busy.insert(dest_dir.to_path_buf());
+
+        RepoGuard {
+            locks: self,
+            dest_dir: dest_dir.to_path_buf(),
+        }
+    }
+}
+
+pub(crate) struct RepoGuard<'a> {
+    locks: &'a RepoLocks,
+    dest_dir: PathBuf,
+}
+
+impl<'a> Drop for RepoGuard<'a> {
+    fn drop(&mut self) {
+        let (ref busy, ref released) = *self.locks.0;
+
+        if let Ok(mut busy) = busy.lock() {
+            busy.remove(&self.dest_dir);
+        }
+        released.notify_all();
+    }
+}
+
+/// Request that a repository (given as its path relative to the backup root)
+/// is checked for corruption.
 #[derive(Debug, Clone, PartialEq)]
-pub struct DownloadRepo(pub GitRepo);
+pub struct VerifyRepo(pub PathBuf);
+
+impl Message for VerifyRepo {
+    type Result = Result<verify::Report, Error>;
+}
+
+/// Request that a repository is downloaded, unless the download is cancelled
+/// before a worker gets to it.
+#[derive(Debug, Clone)]
+pub struct DownloadRepo(pub GitRepo, pub Cancellation);
+
+/// Lets whoever queued some downloads call off the ones which haven't started
+/// yet (e.g. when a backup gives up after too many errors).
+#[derive(Debug, Clone, Default)]
+pub struct Cancellation(Arc<AtomicBool>);
+
+impl Cancellation {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
 
 impl Message for DownloadRepo {
-    type Result = Result<(), Error>;
+    type Result = Result<Downloaded, Error>;
+}
+
+/// A repository was backed up, although things which happen afterwards may
+/// still have gone wrong.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Downloaded {
+    /// Why the repository couldn't be mirrored, if it couldn't.
+    pub mirror_failure: Option<String>,
 }
 
 /// A basic git repository.
 #[derive(Debug, Clone, PartialEq)]
 pub struct GitRepo {
+    /// The name of the `Provider` this repository came from (i.e. the key for
+    /// its section in the config file).
+    pub provider: String,
     /// The destination directory, relative to the backup root.
     pub dest_dir: PathBuf,
     pub ssh_url: String,
+    /// Is this the wiki belonging to another repository?
+    pub wiki: bool,
+    pub metadata: Metadata,
+}
+
+impl GitRepo {
+    const WIKI_SUFFIX: &'static str = ".wiki";
+
+    /// The repository containing this repository's wiki, which gets saved
+    /// next to it (e.g. `github.com/foo/bar.wiki`).
+    pub fn wiki(&self) -> GitRepo {
+        let mut dest_name = self
+            .dest_dir
+            .file_name()
+            .map(|n| n.to_os_string())
+            .unwrap_or_default();
+        dest_name.push(GitRepo::WIKI_SUFFIX);
+
+        let url = if self.ssh_url.ends_with(".git") {
+            &self.ssh_url[..self.ssh_url.len() - 4]
+        } else {
+            &self.ssh_url
+        };
+
+        GitRepo {
+            provider: self.provider.clone(),
+            dest_dir: self.dest_dir.with_file_name(dest_name),
+            ssh_url: format!("{}{}.git", url, GitRepo::WIKI_SUFFIX),
+            wiki: true,
+            metadata: self.metadata.clone(),
+        }
+    }
+
+    /// Is this a wiki repository created by `GitRepo::wiki()`?
+    pub fn is_wiki(&self) -> bool {
+        self.wiki
+    }
+}
+
+/// Extra information about a repository, as reported by its provider.
+///
+/// Anything the provider doesn't know (or doesn't tell us) is left as `None`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Metadata {
+    pub fork: Option<bool>,
+    pub archived: Option<bool>,
+    pub private: Option<bool>,
+    /// The size of the repository, in kilobytes.
+    pub size_kb: Option<u64>,
+    /// The repository's primary language.
+    pub language: Option<String>,
+    pub topics: Option<Vec<String>>,
+    /// A human-readable description (e.g. a gist's description).
+    pub description: Option<String>,
+    /// The start of the repository's directory name which never changes
+    /// (e.g. a gist's id) when the rest of it can, so an existing backup can
+    /// be found again after being renamed.
+    pub stable_name: Option<String>,
+    /// When something was last pushed to the repository.
+    pub last_push: Option<DateTime<Utc>>,
 }
 
 impl From<hubcaps::repositories::Repo> for GitRepo {
     fn from(other: hubcaps::repositories::Repo) -> GitRepo {
+        let last_push = DateTime::parse_from_rfc3339(&other.pushed_at)
+            .ok()
+            .map(|when| when.with_timezone(&Utc));
+
         GitRepo {
+            provider: GitHubConfig::KEY.to_string(),
             dest_dir: Path::new("github.com").join(other.full_name),
             ssh_url: other.ssh_url,
+            wiki: false,
+            metadata: Metadata {
+                fork: Some(other.fork),
+                archived: Some(other.archived),
+                private: Some(other.private),
+                size_kb: Some(other.size),
+                language: other.language,
+                // hubcaps doesn't ask for topics (they need a preview API),
+                // so the GitHub provider looks them up when they're needed
+                topics: None,
+                description: None,
+                stable_name: None,
+                last_push,
+            },
+        }
+    }
+}
+
+/// Normalise a git URL so the same repository gives the same string whether
+/// it's accessed via SSH or HTTPS (e.g. `git@github.com:Org/Repo.git` and
+/// `https://github.com/org/repo` both become `github.com/org/repo`).
+pub(crate) fn canonical_url(url: &str) -> String {
+    let (has_scheme, rest) = match url.find("://") {
+        Some(ix) => (true, &url[ix + 3..]),
+        None => (false, url),
+    };
+
+    let rest = match rest.find('@') {
+        Some(ix) if rest[..ix].find('/').is_none() => &rest[ix + 1..],
+        _ => rest,
+    };
+
+    let (host, path) = if has_scheme {
+        let ix = rest.find('/').unwrap_or_else(|| rest.len());
+        let host = &rest[..ix];
+        // drop any port number
+        let host = host.split(':').next().unwrap_or(host);
+        (host, &rest[ix..])
+    } else {
+        // scp-like syntax, "host:path"
+        match rest.find(':') {
+            Some(ix) => (&rest[..ix], &rest[ix + 1..]),
+            None => ("", rest),
         }
+    };
+
+    let path = path.trim_matches('/');
+    let path = if path.ends_with(".git") {
+        &path[..path.len() - 4]
+    } else {
+        path
+    };
+
+    if host.is_empty() {
+        path.to_lowercase()
+    } else {
+        format!("{}/{}", host, path).to_lowercase()
     }
 }
 
@@ -104,9 +636,27 @@ macro_rules! cmd {
     }};
 }
 
-fn do_clone(dest_dir: &Path, ssh_url: &str) -> Result<(), Error> {
-    cmd!("git", "clone", "--quiet", "--recursive", ssh_url, dest_dir)
-        .context("Unable to clone the repository")?;
+/// Clone a repository, borrowing any objects which are already in the
+/// `reference` repository.
+pub(crate) fn do_clone(
+    dest_dir: &Path,
+    ssh_url: &str,
+    reference: Option<&Path>,
+) -> Result<(), Error> {
+    let outcome = match reference {
+        Some(reference) => cmd!(
+            "git",
+            "clone",
+            "--quiet",
+            "--recursive",
+            "--reference-if-able",
+            reference,
+            ssh_url,
+            dest_dir
+        ),
+        None => cmd!("git", "clone", "--quiet", "--recursive", ssh_url, dest_dir),
+    };
+    outcome.context("Unable to clone the repository")?;
 
     Ok(())
 }
@@ -118,12 +668,322 @@ fn fetch_updates(dest_dir: &Path) -> Result<(), Error> {
         "--recurse-submodules=yes"; in dest_dir)
     .context("Unable to fetch upstream changes")?;
 
+    if !has_fetched_anything(dest_dir) {
+        // the upstream repository is empty, so there's nothing to merge
+        return Ok(());
+    }
+
     cmd!("git", "merge", "--ff-only", "--quiet", "FETCH_HEAD"; in dest_dir)
         .context("Unable to fast-forward to the latest changes")?;
 
     Ok(())
 }
 
+/// Get the `"<hash> <ref>"` pairs for every ref in a repository (including
+/// `HEAD`). An empty repository has no refs.
+pub(crate) fn refs(repo_dir: &Path) -> Result<Refs, Error> {
+    let mut cmd = cmd!(@compose_cmd; "git", "show-ref", "--head");
+    cmd.current_dir(repo_dir);
+
+    // show-ref fails when there are no refs, so we can't use cmd!() here
+    let output = cmd.output().context("Unable to execute the command")?;
+    let refs = parse_refs(&output.stdout)?;
+
+    if refs.is_empty() || output.status.success() {
+        Ok(refs)
+    } else {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        Err(failure::err_msg(stderr.into_owned())
+            .context("Unable to list the repository's refs")
+            .into())
+    }
+}
+
+/// Get the refs contained in a bundle.
+pub(crate) fn bundle_refs(repo_dir: &Path, bundle: &Path) -> Result<Refs, Error> {
+    let output = cmd!("git", "bundle", "list-heads", bundle; in repo_dir)
+        .context("Unable to read the bundle's refs")?;
+
+    parse_refs(&output.stdout)
+}
+
+fn parse_refs(stdout: &[u8]) -> Result<Refs, Error> {
+    let stdout = std::str::from_utf8(stdout).context("Unable to parse the list of refs")?;
+
+    Ok(stdout
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty())
+        .map(String::from)
+        .collect())
+}
+
+pub(crate) fn create_bundle(repo_dir: &Path, bundle: &Path) -> Result<(), Error> {
+    cmd!("git", "bundle", "create", bundle, "--all"; in repo_dir)
+        .context("Unable to create the bundle")?;
+
+    Ok(())
+}
+
+pub(crate) fn verify_bundle(repo_dir: &Path, bundle: &Path) -> Result<(), Error> {
+    cmd!("git", "bundle", "verify", "--quiet", bundle; in repo_dir)
+        .context("The bundle failed verification")?;
+
+    Ok(())
+}
+
+pub(crate) fn init_bare(repo_dir: &Path) -> Result<(), Error> {
+    cmd!("git", "init", "--bare", "--quiet", repo_dir)
+        .context("Unable to create a bare repository")?;
+
+    Ok(())
+}
+
+/// Fetch refs from another repository on disk.
+pub(crate) fn fetch_refs(repo_dir: &Path, from: &Path, refspec: &str) -> Result<(), Error> {
+    cmd!("git", "fetch", "--quiet", "--prune", "--no-tags", from, refspec; in repo_dir)
+        .with_context(|_| format!("Unable to fetch from \"{}\"", from.display()))?;
+
+    Ok(())
+}
+
+/// Repack everything into a single pack. When `local_only` is set, objects
+/// borrowed from another repository (via alternates) are left out.
+pub(crate) fn repack(repo_dir: &Path, local_only: bool) -> Result<(), Error> {
+    let outcome = if local_only {
+        cmd!("git", "repack", "-a", "-d", "-l", "-q"; in repo_dir)
+    } else {
+        cmd!("git", "repack", "-a", "-d", "-q"; in repo_dir)
+    };
+    outcome.context("Unable to repack the repository")?;
+
+    Ok(())
+}
+
+/// Get the names of all refs starting with `prefix`.
+pub(crate) fn ref_names(repo_dir: &Path, prefix: &str) -> Result<Vec<String>, Error> {
+    let output = cmd!("git", "for-each-ref", "--format=%(refname)", prefix; in repo_dir)
+        .context("Unable to list the repository's refs")?;
+    let stdout = String::from_utf8(output.stdout).context("Unable to parse the list of refs")?;
+
+    Ok(stdout.lines().map(String::from).collect())
+}
+
+pub(crate) fn delete_ref(repo_dir: &Path, name: &str) -> Result<(), Error> {
+    cmd!("git", "update-ref", "-d", name; in repo_dir)
+        .with_context(|_| format!("Unable to delete \"{}\"", name))?;
+
+    Ok(())
+}
+
+/// Run `git gc`, optionally deleting unreachable objects straight away
+/// instead of waiting for them to expire.
+pub(crate) fn gc(repo_dir: &Path, prune_now: bool) -> Result<(), Error> {
+    let outcome = if prune_now {
+        cmd!("git", "gc", "--quiet", "--prune=now"; in repo_dir)
+    } else {
+        cmd!("git", "gc", "--quiet"; in repo_dir)
+    };
+    outcome.context("Unable to garbage collect the repository")?;
+
+    Ok(())
+}
+
+/// Run git's incremental maintenance tasks, which are cheaper than a full
+/// `git gc` on large repositories.
+pub(crate) fn maintenance_run(repo_dir: &Path, has_packs: bool) -> Result<(), Error> {
+    let mut cmd = cmd!(@compose_cmd; "git", "maintenance", "run", "--quiet",
+        "--task=loose-objects", "--task=commit-graph");
+    // the multi-pack-index can't be written when there are no packs yet
+    if has_packs {
+        cmd.arg("--task=incremental-repack");
+    }
+    cmd.current_dir(repo_dir);
+
+    cmd!(@execute; cmd).context("Unable to run git's maintenance tasks")?;
+
+    // the loose-objects task leaves the originals behind until its next run
+    cmd!("git", "prune-packed", "--quiet"; in repo_dir)
+        .context("Unable to remove loose objects which were packed")?;
+
+    Ok(())
+}
+
+/// How many loose objects and packfiles a repository has.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub(crate) struct ObjectCounts {
+    pub loose: u64,
+    pub packs: u64,
+    /// How much space loose and packed objects take up, in kilobytes.
+    pub size_kb: u64,
+}
+
+pub(crate) fn count_objects(repo_dir: &Path) -> Result<ObjectCounts, Error> {
+    let output = cmd!("git", "count-objects", "-v"; in repo_dir)
+        .context("Unable to count the repository's objects")?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let mut counts = ObjectCounts::default();
+
+    for line in stdout.lines() {
+        let mut parts = line.splitn(2, ':');
+        let key = parts.next().unwrap_or_default().trim();
+        let value = parts.next().and_then(|v| v.trim().parse().ok());
+
+        match (key, value) {
+            ("count", Some(n)) => counts.loose = n,
+            ("packs", Some(n)) => counts.packs = n,
+            ("size", Some(n)) | ("size-pack", Some(n)) => counts.size_kb += n,
+            _ => {}
+        }
+    }
+
+    Ok(counts)
+}
+
+/// Check the repository for missing or corrupted objects.
+pub(crate) fn fsck(repo_dir: &Path) -> Result<(), Error> {
+    cmd!("git", "fsck", "--full", "--no-progress"; in repo_dir)
+        .context("The repository failed its integrity check")?;
+
+    Ok(())
+}
+
+/// Push every branch (as it was upstream) and tag to `url`, overwriting
+/// whatever is already there. With `prune`, branches and tags which only
+/// exist on the remote are deleted.
+pub(crate) fn push_mirror(repo_dir: &Path, url: &str, prune: bool) -> Result<(), Error> {
+    const UPSTREAM: &str = "refs/remotes/origin/";
+
+    let branches: Vec<_> = ref_names(repo_dir, UPSTREAM)?
+        .into_iter()
+        .filter(|name| name != "refs/remotes/origin/HEAD")
+        .map(|name| (format!("refs/heads/{}", &name[UPSTREAM.len()..]), name))
+        .collect();
+
+    let mut refspecs: Vec<String> = branches
+        .iter()
+        .map(|(dest, src)| format!("+{}:{}", src, dest))
+        .collect();
+    refspecs.push(String::from("+refs/tags/*:refs/tags/*"));
+
+    if prune {
+        let mut ours: BTreeSet<String> = ref_names(repo_dir, "refs/tags/")?.into_iter().collect();
+        ours.extend(branches.into_iter().map(|(dest, _)| dest));
+
+        for name in remote_refs(repo_dir, url)? {
+            if !ours.contains(&name) {
+                refspecs.push(format!(":{}", name));
+            }
+        }
+    }
+
+    let mut cmd = cmd!(@compose_cmd; "git", "push", "--quiet", url);
+    cmd.args(&refspecs).current_dir(repo_dir);
+    cmd!(@execute; cmd).with_context(|_| format!("Unable to push to {}", url))?;
+
+    Ok(())
+}
+
+/// Get the names of the branches and tags in a remote repository.
+fn remote_refs(repo_dir: &Path, url: &str) -> Result<Vec<String>, Error> {
+    let output = cmd!("git", "ls-remote", "--heads", "--tags", url; in repo_dir)
+        .with_context(|_| format!("Unable to list the refs in {}", url))?;
+    let stdout = String::from_utf8(output.stdout).context("Unable to parse the list of refs")?;
+
+    Ok(stdout
+        .lines()
+        .filter_map(|line| line.split('\t').nth(1))
+        // annotated tags are listed a second time, peeled
+        .filter(|name| !name.ends_with("^{}"))
+        .map(String::from)
+        .collect())
+}
+
+/// Has the repository downloaded any Git LFS objects?
+pub(crate) fn uses_lfs(repo_dir: &Path) -> bool {
+    repo_dir.join(".git").join("lfs").join("objects").is_dir()
+}
+
+/// Push every Git LFS object we have to `url`. Objects which were never
+/// downloaded are skipped instead of failing the push.
+pub(crate) fn push_lfs(repo_dir: &Path, url: &str) -> Result<(), Error> {
+    // git-lfs wants the name of a remote, so make up a temporary one
+    let remote = format!("remote.repo-backup.url={}", url);
+    cmd!("git", "-c", remote, "-c", "lfs.allowincompletepush=true", 
+        "lfs", "push", "--all", "repo-backup"; in repo_dir)
+    .with_context(|_| format!("Unable to push LFS objects to {}", url))?;
+
+    Ok(())
+}
+
+/// Find every repository under `root` (without looking inside the
+/// repositories themselves), returning their paths relative to `root`.
+pub(crate) fn find_repositories(root: &Path) -> Result<Vec<PathBuf>, Error> {
+    let mut repos = Vec::new();
+
+    // nothing has been backed up yet
+    if !root.exists() {
+        return Ok(repos);
+    }
+
+    let mut pending = vec![root.to_path_buf()];
+
+    while let Some(dir) = pending.pop() {
+        if dir.join(".git").is_dir() {
+            repos.push(dir.strip_prefix(root).unwrap_or(&dir).to_path_buf());
+            continue;
+        }
+
+        for entry in
+            fs::read_dir(&dir).with_context(|_| format!("Unable to read \"{}\"", dir.display()))?
+        {
+            let path = entry?.path();
+            // our own bookkeeping (state, the shared object store, etc.)
+            let internal = path.file_name() == Some(OsStr::new(".repo-backup"));
+
+            if path.is_dir() && !internal {
+                pending.push(path);
+            }
+        }
+    }
+
+    repos.sort();
+    Ok(repos)
+}
+
+/// Find the directory next to `repo_dir` which is named `stable_name` (maybe
+/// followed by a hyphen and something else), if there is one.
+fn find_renamed(repo_dir: &Path, stable_name: &str) -> Option<PathBuf> {
+    let prefix = format!("{}-", stable_name);
+
+    fs::read_dir(repo_dir.parent()?)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .find(|path| {
+            let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+            path.is_dir() && (name == stable_name || name.starts_with(&prefix))
+        })
+}
+
+fn has_fetched_anything(repo_dir: &Path) -> bool {
+    match fs::metadata(repo_dir.join(".git").join("FETCH_HEAD")) {
+        Ok(meta) => meta.len() > 0,
+        Err(_) => false,
+    }
+}
+
+/// Did the command fail because the remote repository doesn't exist (or
+/// exists but is completely empty)?
+fn is_missing_repo(err: &Error) -> bool {
+    err.iter_chain().any(|cause| {
+        let msg = cause.to_string().to_lowercase();
+        msg.contains("repository not found")
+            || msg.contains("could not be found")
+            || msg.contains("does not appear to be a git repository")
+    })
+}
+
 fn can_update_git_repo(repo_dir: &Path) -> Result<(), Error> {
     if !repo_dir.join(".git").is_dir() {
         return Err(NotARepo.into());
@@ -147,6 +1007,16 @@ fn can_update_git_repo(repo_dir: &Path) -> Result<(), Error> {
 #[fail(display = "Not a git repository")]
 struct NotARepo;
 
+/// A wiki which is enabled but doesn't have any pages yet.
+#[derive(Debug, Copy, Clone, PartialEq, Fail)]
+#[fail(display = "The wiki is empty")]
+pub(crate) struct EmptyWiki;
+
+/// A download which was cancelled before it started.
+#[derive(Debug, Copy, Clone, PartialEq, Fail)]
+#[fail(display = "The download was cancelled")]
+pub(crate) struct Cancelled;
+
 #[derive(Debug, Clone, PartialEq, Fail)]
 struct UnsavedChanges {
     count: usize,
@@ -158,25 +1028,98 @@ impl Display for UnsavedChanges {
     }
 }
 
+/// Run a git command in `repo_dir` while setting up a test repository,
+/// committing as a made-up user.
+#[cfg(test)]
+pub(crate) fn test_git(repo_dir: &Path, args: &[&str]) {
+    let status = Command::new("git")
+        .args(&["-c", "user.name=Test", "-c", "user.email=test@example.com"])
+        .args(args)
+        .current_dir(repo_dir)
+        .stdout(std::process::Stdio::null())
+        .status()
+        .unwrap();
+    assert!(status.success(), "git {:?} failed", args);
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use std::fs::File;
     use std::process::Stdio;
 
-    macro_rules! require_program {
-        ($name:expr) => {{
-            let exists = ::std::process::Command::new($name)
-                .arg("--help")
-                .stdout(::std::process::Stdio::null())
-                .stderr(::std::process::Stdio::null())
-                .status()
-                .is_ok();
-            if !exists {
-                eprintln!("Couldn't find \"{}\"", $name);
-                return;
-            }
-        }};
+    #[test]
+    fn canonicalise_git_urls() {
+        let inputs = vec![
+            "git@github.com:Michael-F-Bryan/repo-backup.git",
+            "https://github.com/Michael-F-Bryan/repo-backup",
+            "https://github.com/michael-f-bryan/repo-backup.git/",
+            "ssh://git@github.com:22/Michael-F-Bryan/repo-backup.git",
+        ];
+
+        for input in inputs {
+            assert_eq!(
+                canonical_url(input),
+                "github.com/michael-f-bryan/repo-backup",
+                "{}",
+                input
+            );
+        }
+
+        assert_ne!(
+            canonical_url("git@github.com:org/project.git"),
+            canonical_url("git@gitlab.com:org/project.git")
+        );
+    }
+
+    #[test]
+    fn find_a_gist_whose_description_changed() {
+        let temp = tempfile::tempdir().unwrap();
+        let owner = temp.path().join("gist.github.com/octocat");
+        fs::create_dir_all(owner.join("aa5a315d-old-description")).unwrap();
+        fs::create_dir_all(owner.join("bb5a315d-something-else")).unwrap();
+
+        let got = find_renamed(&owner.join("aa5a315d-new-description"), "aa5a315d");
+
+        assert_eq!(got, Some(owner.join("aa5a315d-old-description")));
+        assert!(find_renamed(&owner.join("cc5a315d"), "cc5a315d").is_none());
+    }
+
+    #[test]
+    fn wikis_live_next_to_their_repository() {
+        let repo = GitRepo {
+            provider: String::from("github"),
+            dest_dir: PathBuf::from("github.com/foo/bar"),
+            ssh_url: String::from("git@github.com:foo/bar.git"),
+            wiki: false,
+            metadata: Metadata::default(),
+        };
+
+        let wiki = repo.wiki();
+
+        assert_eq!(wiki.dest_dir, Path::new("github.com/foo/bar.wiki"));
+        assert_eq!(wiki.ssh_url, "git@github.com:foo/bar.wiki.git");
+        assert!(wiki.is_wiki());
+        assert!(!repo.is_wiki());
+        // a normal repository which happens to be named like a wiki
+        let notes = GitRepo {
+            dest_dir: PathBuf::from("github.com/foo/notes.wiki"),
+            ssh_url: String::from("git@github.com:foo/notes.wiki.git"),
+            ..repo.clone()
+        };
+        assert!(!notes.is_wiki());
+    }
+
+    #[test]
+    fn missing_wikis_are_detected() {
+        let not_found = Error::from(failure::err_msg(
+            "ERROR: Repository not found.\nfatal: Could not read from remote repository.",
+        ));
+        let not_found = Error::from(not_found.context("Unable to clone the repository"));
+        assert!(is_missing_repo(&not_found));
+
+        let denied = failure::err_msg("Permission denied (publickey).");
+        assert!(!is_missing_repo(&denied));
     }
 
     #[test]
@@ -232,18 +1175,51 @@ mod tests {
         let temp = tempfile::tempdir().unwrap();
         let sub_dir = temp.path().join("dest");
 
-        do_clone(&sub_dir, env!("CARGO_MANIFEST_DIR")).unwrap();
+        do_clone(&sub_dir, env!("CARGO_MANIFEST_DIR"), None).unwrap();
 
         assert!(sub_dir.join(".git").exists());
     }
 
+    #[test]
+    fn bundles_contain_every_ref() {
+        require_program!("git");
+
+        let temp = tempfile::tempdir().unwrap();
+        let sub_dir = temp.path().join("dest");
+        let bundle = temp.path().join("dest.bundle");
+        do_clone(&sub_dir, env!("CARGO_MANIFEST_DIR"), None).unwrap();
+
+        create_bundle(&sub_dir, &bundle).unwrap();
+
+        verify_bundle(&sub_dir, &bundle).unwrap();
+        let repo_refs = refs(&sub_dir).unwrap();
+        assert!(!repo_refs.is_empty());
+        assert_eq!(bundle_refs(&sub_dir, &bundle).unwrap(), repo_refs);
+    }
+
+    #[test]
+    fn empty_repos_have_no_refs() {
+        require_program!("git");
+
+        let temp = tempfile::tempdir().unwrap();
+        let status = Command::new("git")
+            .arg("init")
+            .arg(temp.path())
+            .stdout(Stdio::null())
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        assert!(refs(temp.path()).unwrap().is_empty());
+    }
+
     #[test]
     fn clone_and_then_update() {
         require_program!("git");
 
         let temp = tempfile::tempdir().unwrap();
         let sub_dir = temp.path().join("dest");
-        do_clone(&sub_dir, env!("CARGO_MANIFEST_DIR")).unwrap();
+        do_clone(&sub_dir, env!("CARGO_MANIFEST_DIR"), None).unwrap();
 
         assert!(fetch_updates(&sub_dir).is_ok());
     }
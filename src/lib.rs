@@ -11,11 +11,47 @@ extern crate slog;
 #[macro_use]
 extern crate serde_derive;
 
+/// Skip the current test when a program it needs isn't installed.
+#[cfg(test)]
+macro_rules! require_program {
+    ($name:expr) => {{
+        let exists = ::std::process::Command::new($name)
+            .arg("--help")
+            .stdout(::std::process::Stdio::null())
+            .stderr(::std::process::Stdio::null())
+            .status()
+            .is_ok();
+        if !exists {
+            eprintln!("Couldn't find \"{}\"", $name);
+            return;
+        }
+    }};
+}
+
+pub mod alternates;
+pub mod archive;
+pub mod bundle;
 pub mod config;
+pub mod daemon;
 mod driver;
+pub mod filter;
 mod git;
+pub mod maintenance;
+mod metadata;
+pub mod metrics;
+pub mod migrate;
+pub mod notify;
 pub mod providers;
+pub mod restore;
+pub mod snapshot;
+mod state;
+pub mod status;
+pub mod upload;
+pub mod verify;
+pub mod webhook;
 
-pub use crate::config::Config;
-pub use crate::driver::{run, Driver};
-pub use crate::git::GitRepo;
+pub use crate::config::{Config, Diagnostic};
+pub use crate::driver::{
+    check_config, example_config, list, load_config, run, Driver, ListDecision, Listed,
+};
+pub use crate::git::{GitRepo, Metadata};
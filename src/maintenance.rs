@@ -0,0 +1,233 @@
+//! Keeping backed up repositories tidy. Every fetch leaves behind loose
+//! objects and another packfile, which (across thousands of repositories)
+//! adds up to a lot of inodes and wasted space.
+
+use crate::filter::Age;
+use crate::git::{self, ObjectCounts};
+use crate::state::RepoState;
+use chrono::{DateTime, Utc};
+use failure::{Error, ResultExt};
+use std::path::Path;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MaintenanceConfig {
+    /// Should repositories be cleaned up after they're fetched?
+    pub enabled: bool,
+    /// How to clean up a repository.
+    pub strategy: Strategy,
+    /// Clean up once a repository has this many loose objects.
+    pub loose_objects: u64,
+    /// Clean up once a repository has this many packfiles.
+    pub packs: u64,
+    /// Clean up when it's been this long since the last clean up.
+    pub interval: Option<Age>,
+}
+
+impl MaintenanceConfig {
+    pub const DESCRIPTION: &'static str =
+        "Periodically garbage collect or repack repositories after they're fetched.";
+    pub const FIELDS: &'static [(&'static str, &'static str)] = &[
+        (
+            "enabled",
+            "Should repositories be cleaned up when they need it? This happens \
+             on the same thread, straight after a successful fetch.",
+        ),
+        (
+            "strategy",
+            "How to clean up, either \"gc\" (git gc), \"maintenance\" (git's \
+             cheaper incremental tasks, needs git 2.30 or newer) or \"repack\" \
+             (pack everything into a single pack without pruning anything).",
+        ),
+        (
+            "loose_objects",
+            "Clean up once a repository has at least this many loose objects.",
+        ),
+        (
+            "packs",
+            "Clean up once a repository has at least this many packfiles.",
+        ),
+        (
+            "interval",
+            "Clean up when it's been this long since the last clean up (e.g. \
+             \"30days\"), regardless of how many objects there are.",
+        ),
+    ];
+}
+
+impl Default for MaintenanceConfig {
+    fn default() -> MaintenanceConfig {
+        MaintenanceConfig {
+            enabled: false,
+            strategy: Strategy::Gc,
+            loose_objects: 1000,
+            packs: 20,
+            interval: "30days".parse().ok(),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Strategy {
+    Gc,
+    Maintenance,
+    Repack,
+}
+
+/// Clean up a repository if it needs it, returning why it was cleaned up.
+///
+/// Freshly cloned repositories are already tidy, so they only get their
+/// timestamp recorded.
+pub(crate) fn run(
+    cfg: &MaintenanceConfig,
+    root: &Path,
+    dest_dir: &Path,
+    just_cloned: bool,
+    now: DateTime<Utc>,
+) -> Result<Option<String>, Error> {
+    let repo_dir = root.join(dest_dir);
+    let mut state = RepoState::load(root, dest_dir)?;
+
+    if just_cloned {
+        state.last_maintenance = Some(now);
+        state.save(root, dest_dir)?;
+        return Ok(None);
+    }
+
+    let counts = git::count_objects(&repo_dir)?;
+    let reason = match due(cfg, &counts, state.last_maintenance, now) {
+        Some(reason) => reason,
+        None => return Ok(None),
+    };
+
+    match cfg.strategy {
+        Strategy::Gc => git::gc(&repo_dir, false),
+        Strategy::Maintenance => git::maintenance_run(&repo_dir, counts.packs > 0),
+        // borrowed objects stay in the shared object store
+        Strategy::Repack => git::repack(&repo_dir, true),
+    }
+    .with_context(|_| format!("Maintenance failed ({})", reason))?;
+
+    state.last_maintenance = Some(now);
+    state.save(root, dest_dir)?;
+
+    Ok(Some(reason))
+}
+
+/// Does a repository need cleaning up? Returns the reason if it does.
+fn due(
+    cfg: &MaintenanceConfig,
+    counts: &ObjectCounts,
+    last: Option<DateTime<Utc>>,
+    now: DateTime<Utc>,
+) -> Option<String> {
+    if counts.loose >= cfg.loose_objects {
+        return Some(format!("{} loose objects", counts.loose));
+    }
+
+    if counts.packs >= cfg.packs {
+        return Some(format!("{} packs", counts.packs));
+    }
+
+    let interval = cfg.interval.as_ref()?;
+
+    match last {
+        None => Some(String::from("never cleaned up")),
+        Some(last) => {
+            let elapsed = now.signed_duration_since(last).to_std().unwrap_or_default();
+
+            if elapsed >= interval.0 {
+                Some(format!("not cleaned up in {}", interval))
+            } else {
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn decide_when_maintenance_is_due() {
+        let cfg = MaintenanceConfig {
+            enabled: true,
+            ..Default::default()
+        };
+        let now = Utc::now();
+        let tidy = ObjectCounts {
+            loose: 10,
+            packs: 1,
+            ..Default::default()
+        };
+
+        assert!(due(&cfg, &tidy, Some(now), now).is_none());
+        assert!(due(&cfg, &tidy, None, now).is_some());
+        assert!(due(&cfg, &tidy, Some(now - chrono::Duration::days(31)), now).is_some());
+
+        let messy = ObjectCounts {
+            loose: 5000,
+            packs: 1,
+            ..Default::default()
+        };
+        assert_eq!(
+            due(&cfg, &messy, Some(now), now).unwrap(),
+            "5000 loose objects"
+        );
+        let fragmented = ObjectCounts {
+            loose: 0,
+            packs: 50,
+            ..Default::default()
+        };
+        assert_eq!(due(&cfg, &fragmented, Some(now), now).unwrap(), "50 packs");
+
+        let no_interval = MaintenanceConfig {
+            interval: None,
+            ..cfg
+        };
+        assert!(due(&no_interval, &tidy, None, now).is_none());
+    }
+
+    #[test]
+    fn pack_loose_objects() {
+        require_program!("git");
+
+        let temp = tempfile::tempdir().unwrap();
+        let dest_dir = Path::new("example.com/foo/bar");
+        let repo_dir = temp.path().join(dest_dir);
+        fs::create_dir_all(&repo_dir).unwrap();
+        git::test_git(&repo_dir, &["init", "--quiet"]);
+        let now = Utc::now();
+
+        for &strategy in &[Strategy::Maintenance, Strategy::Repack, Strategy::Gc] {
+            fs::write(repo_dir.join("file.txt"), format!("{:?}", strategy)).unwrap();
+            git::test_git(&repo_dir, &["add", "file.txt"]);
+            git::test_git(
+                &repo_dir,
+                &["commit", "--quiet", "--message", "Another commit"],
+            );
+            let cfg = MaintenanceConfig {
+                enabled: true,
+                strategy,
+                loose_objects: 3,
+                interval: None,
+                ..Default::default()
+            };
+            assert!(git::count_objects(&repo_dir).unwrap().loose >= 3);
+
+            let reason = run(&cfg, temp.path(), dest_dir, false, now).unwrap();
+
+            assert!(reason.is_some(), "{:?}", strategy);
+            assert_eq!(git::count_objects(&repo_dir).unwrap().loose, 0);
+            let state = RepoState::load(temp.path(), dest_dir).unwrap();
+            assert_eq!(state.last_maintenance, Some(now));
+
+            // nothing left to do the second time around
+            let reason = run(&cfg, temp.path(), dest_dir, false, now).unwrap();
+            assert!(reason.is_none());
+        }
+    }
+}
@@ -0,0 +1,424 @@
+//! Backing up the things associated with a repository which don't live in
+//! git (issues, pull requests, etc.) as JSON files.
+
+use chrono::{DateTime, Utc};
+use failure::{Error, ResultExt};
+use reqwest::header::{HeaderMap, HeaderValue, ACCEPT, CONTENT_LENGTH, LINK};
+use reqwest::{Client, StatusCode};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+/// Get the directory a repository's metadata should be saved to.
+///
+/// This sits next to the repository (e.g. `github.com/foo/bar.meta/`) so it
+/// doesn't leave the working copy dirty.
+pub(crate) fn meta_dir(repo_dir: &Path) -> PathBuf {
+    let mut name = repo_dir
+        .file_name()
+        .map(|n| n.to_os_string())
+        .unwrap_or_default();
+    name.push(".meta");
+
+    repo_dir.with_file_name(name)
+}
+
+/// A blocking client for JSON APIs which use the `Link` header for
+/// pagination (GitHub, GitLab and Gitea all do this).
+#[derive(Debug, Clone)]
+pub(crate) struct JsonClient {
+    client: Client,
+}
+
+impl JsonClient {
+    pub fn new(headers: HeaderMap) -> Result<JsonClient, Error> {
+        let client = Client::builder()
+            .default_headers(headers)
+            .build()
+            .context("Unable to create the HTTP client")?;
+
+        Ok(JsonClient { client })
+    }
+
+    pub fn get(&self, url: &str) -> Result<Value, Error> {
+        let (value, _) = self.get_page(url)?;
+        Ok(value)
+    }
+
+    /// Get a single item, returning `None` if it doesn't exist.
+    pub fn get_optional(&self, url: &str) -> Result<Option<Value>, Error> {
+        let response = self
+            .client
+            .get(url)
+            .send()
+            .with_context(|_| format!("Request to {} failed", url))?;
+
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        let value = response
+            .error_for_status()
+            .and_then(|mut r| r.json())
+            .with_context(|_| format!("Request to {} failed", url))?;
+
+        Ok(Some(value))
+    }
+
+    /// Send a JSON body to an endpoint, returning whatever it responds with.
+    pub fn post(&self, url: &str, body: &Value) -> Result<Value, Error> {
+        let value = self
+            .client
+            .post(url)
+            .json(body)
+            .send()
+            .and_then(|r| r.error_for_status())
+            .and_then(|mut r| r.json())
+            .with_context(|_| format!("Request to {} failed", url))?;
+
+        Ok(value)
+    }
+
+    /// Get every item from a paginated endpoint.
+    pub fn get_all(&self, url: &str) -> Result<Vec<Value>, Error> {
+        let mut items = Vec::new();
+
+        self.for_each_page(url, |page| {
+            items.extend(page);
+            Ok(true)
+        })?;
+
+        Ok(items)
+    }
+
+    /// Call `on_page` with the items from each page, stopping early if it
+    /// returns `false`.
+    pub fn for_each_page<F>(&self, url: &str, mut on_page: F) -> Result<(), Error>
+    where
+        F: FnMut(Vec<Value>) -> Result<bool, Error>,
+    {
+        let mut next = Some(url.to_string());
+
+        while let Some(url) = next {
+            let (value, next_page) = self.get_page(&url)?;
+
+            let items = match value {
+                Value::Array(items) => items,
+                other => vec![other],
+            };
+
+            if !on_page(items)? {
+                break;
+            }
+            next = next_page;
+        }
+
+        Ok(())
+    }
+
+    /// Download the raw contents of a URL to a file, returning the number of
+    /// bytes written.
+    pub fn download(&self, url: &str, dest: &Path) -> Result<u64, Error> {
+        let mut response = self
+            .client
+            .get(url)
+            .header(ACCEPT, HeaderValue::from_static("application/octet-stream"))
+            .send()
+            .and_then(|r| r.error_for_status())
+            .with_context(|_| format!("Request to {} failed", url))?;
+
+        let mut f = create_file(dest)?;
+        let bytes = response
+            .copy_to(&mut f)
+            .with_context(|_| format!("Unable to save {} to \"{}\"", url, dest.display()))?;
+
+        Ok(bytes)
+    }
+
+    /// Ask how big the file at a URL is, without downloading it.
+    pub fn content_length(&self, url: &str) -> Option<u64> {
+        let response = self
+            .client
+            .head(url)
+            .header(ACCEPT, HeaderValue::from_static("application/octet-stream"))
+            .send()
+            .and_then(|r| r.error_for_status())
+            .ok()?;
+
+        response
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|len| len.to_str().ok())
+            .and_then(|len| len.parse().ok())
+    }
+
+    fn get_page(&self, url: &str) -> Result<(Value, Option<String>), Error> {
+        let mut response = self
+            .client
+            .get(url)
+            .send()
+            .and_then(|r| r.error_for_status())
+            .with_context(|_| format!("Request to {} failed", url))?;
+
+        let next = response
+            .headers()
+            .get(LINK)
+            .and_then(|link| link.to_str().ok())
+            .and_then(next_link);
+        let value = response
+            .json()
+            .with_context(|_| format!("Unable to parse the response from {}", url))?;
+
+        Ok((value, next))
+    }
+}
+
+/// Find the `rel="next"` URL in a `Link` header.
+fn next_link(header: &str) -> Option<String> {
+    header.split(',').find_map(|link| {
+        let mut parts = link.split(';');
+        let url = parts.next()?.trim();
+        let is_next = parts.any(|p| p.trim() == "rel=\"next\"");
+
+        if is_next && url.starts_with('<') && url.ends_with('>') {
+            Some(url[1..url.len() - 1].to_string())
+        } else {
+            None
+        }
+    })
+}
+
+/// Remembers when each kind of item was last exported, so reruns only need
+/// to ask for things which have changed.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct Since {
+    path: PathBuf,
+    timestamps: BTreeMap<String, DateTime<Utc>>,
+}
+
+impl Since {
+    const FILENAME: &'static str = "since.json";
+
+    pub fn load(meta_dir: &Path) -> Result<Since, Error> {
+        let path = meta_dir.join(Since::FILENAME);
+
+        let timestamps = if path.exists() {
+            let f = File::open(&path)
+                .with_context(|_| format!("Unable to open \"{}\"", path.display()))?;
+            serde_json::from_reader(f)
+                .with_context(|_| format!("Unable to parse \"{}\"", path.display()))?
+        } else {
+            BTreeMap::new()
+        };
+
+        Ok(Since { path, timestamps })
+    }
+
+    pub fn get(&self, kind: &str) -> Option<DateTime<Utc>> {
+        self.timestamps.get(kind).cloned()
+    }
+
+    /// Record that `kind` was exported and save to disk.
+    pub fn update(&mut self, kind: &str, when: DateTime<Utc>) -> Result<(), Error> {
+        self.timestamps.insert(kind.to_string(), when);
+
+        let f = create_file(&self.path)?;
+        serde_json::to_writer_pretty(f, &self.timestamps)?;
+        Ok(())
+    }
+
+    /// A query string parameter for only asking for items which changed
+    /// since the last export (e.g. `&since=2019-01-01T00:00:00Z`).
+    pub fn query(&self, kind: &str, param: &str) -> String {
+        match self.get(kind) {
+            Some(when) => format!("&{}={}", param, when.format("%Y-%m-%dT%H:%M:%SZ")),
+            None => String::new(),
+        }
+    }
+}
+
+/// Was this item updated after `since`? Items without a valid `updated_at`
+/// field are assumed to have changed.
+pub(crate) fn updated_since(item: &Value, since: Option<DateTime<Utc>>) -> bool {
+    let updated_at = item["updated_at"]
+        .as_str()
+        .and_then(|s| DateTime::parse_from_rfc3339(s).ok());
+
+    match (updated_at, since) {
+        (Some(updated), Some(since)) => updated.with_timezone(&Utc) >= since,
+        _ => true,
+    }
+}
+
+pub(crate) fn write_json(path: &Path, value: &Value) -> Result<(), Error> {
+    let f = create_file(path)?;
+    serde_json::to_writer_pretty(f, value)
+        .with_context(|_| format!("Unable to write to \"{}\"", path.display()))?;
+    Ok(())
+}
+
+/// Save each item to its own file in `dir`, named after the item's `key`
+/// field (e.g. `issues/42.json`).
+pub(crate) fn write_each(dir: &Path, items: &[Value], key: &str) -> Result<(), Error> {
+    for item in items {
+        let name = match item[key] {
+            Value::Number(ref n) => n.to_string(),
+            Value::String(ref s) => s.clone(),
+            _ => continue,
+        };
+
+        write_json(&dir.join(format!("{}.json", name)), item)?;
+    }
+
+    Ok(())
+}
+
+/// A file attached to a release.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Asset {
+    pub name: String,
+    pub url: String,
+    /// The asset's size in bytes, if the provider tells us.
+    pub size: Option<u64>,
+}
+
+/// Download each asset into `dir`, skipping any which have already been
+/// downloaded. Returns the number of assets which were downloaded.
+pub(crate) fn download_assets(
+    client: &JsonClient,
+    assets: &[Asset],
+    dir: &Path,
+) -> Result<usize, Error> {
+    let mut downloaded = 0;
+
+    for asset in assets {
+        let dest = dir.join(safe_name(&asset.name));
+
+        if dest.exists() {
+            let expected = asset.size.or_else(|| client.content_length(&asset.url));
+            if is_up_to_date(&dest, expected) {
+                continue;
+            }
+        }
+
+        // download to a temporary file first so an interrupted download
+        // doesn't look like a complete one
+        let partial = dir.join(format!("{}.part", safe_name(&asset.name)));
+        client.download(&asset.url, &partial)?;
+        fs::rename(&partial, &dest)
+            .with_context(|_| format!("Unable to save \"{}\"", dest.display()))?;
+        downloaded += 1;
+    }
+
+    Ok(downloaded)
+}
+
+/// Has a file already been downloaded? If we don't know how big it should
+/// be we assume it hasn't changed, because release assets are almost never
+/// replaced.
+fn is_up_to_date(path: &Path, expected_size: Option<u64>) -> bool {
+    match (fs::metadata(path), expected_size) {
+        (Ok(meta), Some(size)) => meta.is_file() && meta.len() == size,
+        (Ok(meta), None) => meta.is_file(),
+        (Err(_), _) => false,
+    }
+}
+
+/// Make a tag or asset name safe to use as a single path component (e.g.
+/// `release/v1.0` becomes `release-v1.0`).
+pub(crate) fn safe_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' => '-',
+            other => other,
+        })
+        .collect();
+
+    match name.as_str() {
+        "" | "." | ".." => format!("_{}", name),
+        _ => name,
+    }
+}
+
+fn create_file(path: &Path) -> Result<File, Error> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|_| format!("Unable to create \"{}\"", parent.display()))?;
+    }
+
+    let f =
+        File::create(path).with_context(|_| format!("Unable to create \"{}\"", path.display()))?;
+    Ok(f)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn metadata_lives_next_to_the_repo() {
+        let got = meta_dir(Path::new("/srv/github.com/foo/bar"));
+
+        assert_eq!(got, Path::new("/srv/github.com/foo/bar.meta"));
+    }
+
+    #[test]
+    fn parse_link_headers() {
+        let header = "<https://api.github.com/repositories/1/issues?page=2>; rel=\"next\", \
+                      <https://api.github.com/repositories/1/issues?page=5>; rel=\"last\"";
+
+        assert_eq!(
+            next_link(header).unwrap(),
+            "https://api.github.com/repositories/1/issues?page=2"
+        );
+        assert!(next_link("<https://example.com?page=1>; rel=\"prev\"").is_none());
+    }
+
+    #[test]
+    fn remember_when_things_were_exported() {
+        let temp = tempfile::tempdir().unwrap();
+        let now = Utc::now();
+
+        let mut since = Since::load(temp.path()).unwrap();
+        assert_eq!(since.query("issues", "since"), "");
+        since.update("issues", now).unwrap();
+
+        let reloaded = Since::load(temp.path()).unwrap();
+        assert_eq!(reloaded.get("issues"), Some(now));
+        assert!(reloaded.query("issues", "since").starts_with("&since="));
+    }
+
+    #[test]
+    fn tags_are_turned_into_a_single_path_component() {
+        assert_eq!(safe_name("v1.0.0"), "v1.0.0");
+        assert_eq!(safe_name("release/v1.0"), "release-v1.0");
+        assert_eq!(safe_name(".."), "_..");
+    }
+
+    #[test]
+    fn only_download_assets_we_dont_already_have() {
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("asset.tar.gz");
+        assert!(!is_up_to_date(&path, Some(4)));
+
+        fs::write(&path, b"1234").unwrap();
+
+        assert!(is_up_to_date(&path, Some(4)));
+        assert!(is_up_to_date(&path, None));
+        assert!(!is_up_to_date(&path, Some(1024)));
+    }
+
+    #[test]
+    fn save_each_item_to_its_own_file() {
+        let temp = tempfile::tempdir().unwrap();
+        let items = vec![json!({"number": 1}), json!({"number": 42})];
+
+        write_each(&temp.path().join("issues"), &items, "number").unwrap();
+
+        assert!(temp.path().join("issues").join("1.json").exists());
+        assert!(temp.path().join("issues").join("42.json").exists());
+    }
+}
@@ -0,0 +1,446 @@
+//! Exposing how backups are going as Prometheus metrics, either served by
+//! `repo-backup daemon` or written to a file for node_exporter's textfile
+//! collector after each backup.
+
+use crate::config::Config;
+use crate::driver::Finished;
+use crate::filter;
+use crate::state::{self, RepoState};
+use actix::Arbiter;
+use chrono::{DateTime, Utc};
+use failure::{Error, ResultExt};
+use futures::future::{self, Future};
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use hyper::service::service_fn;
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use slog::Logger;
+use std::fmt::Write;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// Where the daemon serves metrics from.
+    pub address: Option<String>,
+    /// Write metrics to this file after every backup.
+    pub textfile: Option<PathBuf>,
+}
+
+impl MetricsConfig {
+    pub const DESCRIPTION: &'static str = "Exposing Prometheus metrics about each backup.";
+    pub const FIELDS: &'static [(&'static str, &'static str)] = &[
+        (
+            "address",
+            "The address and port `repo-backup daemon` serves metrics on (e.g. \
+             \"127.0.0.1:9184\"), at /metrics.",
+        ),
+        (
+            "textfile",
+            "Write metrics to this file after every backup, for node_exporter's \
+             textfile collector (e.g. \
+             \"/var/lib/node_exporter/textfile/repo_backup.prom\").",
+        ),
+    ];
+}
+
+/// The text Prometheus scrapes, built from the last backup (if there has
+/// been one) and the state saved for each repository.
+pub(crate) fn render(root: &Path, last: Option<&Finished>) -> Result<String, Error> {
+    let mut metrics = Metrics::default();
+
+    if let Some(last) = last {
+        metrics.family(
+            "repo_backup_last_run_timestamp_seconds",
+            "gauge",
+            "When the last backup finished.",
+        );
+        metrics.sample(
+            "repo_backup_last_run_timestamp_seconds",
+            &[],
+            timestamp(last.finished_at),
+        );
+
+        metrics.family(
+            "repo_backup_last_run_duration_seconds",
+            "gauge",
+            "How long the last backup took.",
+        );
+        let duration = last.finished_at.signed_duration_since(last.started_at);
+        metrics.sample(
+            "repo_backup_last_run_duration_seconds",
+            &[],
+            duration.num_milliseconds() as f64 / 1000.0,
+        );
+
+        metrics.family(
+            "repo_backup_last_run_success",
+            "gauge",
+            "Whether the last backup succeeded (1) or not (0).",
+        );
+        let success = if last.succeeded() { 1.0 } else { 0.0 };
+        metrics.sample("repo_backup_last_run_success", &[], success);
+
+        metrics.family(
+            "repo_backup_last_run_repositories",
+            "gauge",
+            "Repositories handled by the last backup, by outcome.",
+        );
+        let stats = &last.stats;
+        let outcomes = [
+            ("discovered", stats.total_repos),
+            ("success", stats.success),
+            ("failed", stats.error_count),
+            ("ignored", stats.ignored),
+            ("duplicate", stats.duplicates),
+            ("conflict", stats.conflicts),
+            ("mirror_failed", stats.mirror_failures),
+        ];
+        for &(outcome, count) in &outcomes {
+            metrics.sample(
+                "repo_backup_last_run_repositories",
+                &[("outcome", outcome)],
+                count as f64,
+            );
+        }
+
+        metrics.family(
+            "repo_backup_discovered_repositories",
+            "gauge",
+            "Repositories each provider found during the last backup.",
+        );
+        for (provider, &count) in &last.discovered {
+            metrics.sample(
+                "repo_backup_discovered_repositories",
+                &[("provider", provider.as_str())],
+                count as f64,
+            );
+        }
+    }
+
+    // one unreadable state file shouldn't hide every other repository
+    let mut repos = Vec::new();
+    let mut state_errors = 0_usize;
+    for repo in state::saved(root)? {
+        match RepoState::load(root, &repo) {
+            Ok(state) => repos.push((filter::normalized(&repo), state)),
+            Err(_) => state_errors += 1,
+        }
+    }
+
+    metrics.family(
+        "repo_backup_state_errors",
+        "gauge",
+        "Repositories whose saved state couldn't be read.",
+    );
+    metrics.sample("repo_backup_state_errors", &[], state_errors as f64);
+
+    let per_repo: &[(&str, &str, fn(&RepoState) -> Option<f64>)] = &[
+        (
+            "repo_backup_repository_last_success_timestamp_seconds",
+            "When the repository was last backed up successfully.",
+            |s| s.last_success.map(timestamp),
+        ),
+        (
+            "repo_backup_repository_last_attempt_timestamp_seconds",
+            "When the repository was last backed up, successfully or not.",
+            |s| s.last_attempt.map(timestamp),
+        ),
+        (
+            "repo_backup_repository_failed",
+            "Whether the repository's last backup failed (1) or not (0).",
+            |s| Some(if s.last_error.is_some() { 1.0 } else { 0.0 }),
+        ),
+        (
+            "repo_backup_repository_fetch_duration_seconds",
+            "How long the repository's last successful fetch took.",
+            |s| s.fetch_seconds,
+        ),
+        (
+            "repo_backup_repository_fetch_bytes",
+            "How many bytes of objects the repository's last successful fetch added.",
+            |s| s.fetch_bytes.map(|b| b as f64),
+        ),
+    ];
+
+    for &(name, help, value) in per_repo {
+        metrics.family(name, "gauge", help);
+
+        for (repo, state) in &repos {
+            if let Some(value) = value(state) {
+                let provider = state.provider.as_ref().map(|p| p.as_str()).unwrap_or("");
+                metrics.sample(
+                    name,
+                    &[("repo", repo.as_str()), ("provider", provider)],
+                    value,
+                );
+            }
+        }
+    }
+
+    Ok(metrics.text)
+}
+
+/// Atomically replace `path` with the current metrics, so the collector never
+/// sees half a file.
+pub(crate) fn write_textfile(path: &Path, root: &Path, last: &Finished) -> Result<(), Error> {
+    let text = render(root, Some(last))?;
+
+    let mut partial = path.to_path_buf().into_os_string();
+    partial.push(".part");
+    let partial = PathBuf::from(partial);
+
+    fs::write(&partial, text)
+        .with_context(|_| format!("Unable to write to \"{}\"", partial.display()))?;
+    fs::rename(&partial, path)
+        .with_context(|_| format!("Unable to save \"{}\"", path.display()))?;
+
+    Ok(())
+}
+
+/// The metrics served by the daemon.
+///
+/// Rendering reads the state of every repository, so it happens on a
+/// background thread whenever something changes instead of on every scrape.
+#[derive(Debug, Clone)]
+pub(crate) struct Cache {
+    root: PathBuf,
+    logger: Logger,
+    /// The most recently finished backup.
+    last: Arc<Mutex<Option<Finished>>>,
+    text: Arc<Mutex<String>>,
+    /// Only one thread renders at a time, so an older render can't replace
+    /// a newer one.
+    rendering: Arc<Mutex<()>>,
+}
+
+impl Cache {
+    pub fn new(root: &Path, logger: &Logger) -> Cache {
+        Cache {
+            root: root.to_path_buf(),
+            logger: logger.clone(),
+            last: Arc::default(),
+            text: Arc::default(),
+            rendering: Arc::default(),
+        }
+    }
+
+    /// Remember the backup which just finished and render the metrics again.
+    pub fn finished(&self, finished: Finished) {
+        if let Ok(mut last) = self.last.lock() {
+            *last = Some(finished);
+        }
+
+        self.refresh();
+    }
+
+    /// Render the metrics again in the background (e.g. because a
+    /// repository was backed up).
+    pub fn refresh(&self) {
+        let cache = self.clone();
+
+        thread::spawn(move || {
+            let _rendering = cache.rendering.lock();
+            let last = cache.last.lock().ok().and_then(|last| last.clone());
+
+            match render(&cache.root, last.as_ref()) {
+                Ok(text) => {
+                    if let Ok(mut cached) = cache.text.lock() {
+                        *cached = text;
+                    }
+                }
+                Err(e) => {
+                    warn!(cache.logger, "Unable to gather metrics"; "error" => e.to_string());
+                }
+            }
+        });
+    }
+
+    fn text(&self) -> String {
+        self.text
+            .lock()
+            .map(|text| text.clone())
+            .unwrap_or_default()
+    }
+}
+
+/// Start serving metrics on the current `System`, returning the cache which
+/// should be told whenever they change.
+pub(crate) fn serve(cfg: &Config, address: &str, logger: &Logger) -> Result<Cache, Error> {
+    let addr: SocketAddr = address
+        .parse()
+        .with_context(|_| format!("Invalid metrics address, \"{}\"", address))?;
+    let cache = Cache::new(&cfg.general.root, logger);
+    cache.refresh();
+
+    let l2 = logger.clone();
+    let c2 = cache.clone();
+    let server = Server::try_bind(&addr)
+        .context("Unable to start the metrics server")?
+        .serve(move || {
+            let cache = c2.clone();
+            service_fn(move |req| future::ok::<_, hyper::Error>(respond(&req, &cache)))
+        })
+        .map_err(move |e| {
+            error!(l2, "The metrics server stopped"; "error" => e.to_string());
+        });
+
+    Arbiter::spawn(server);
+    info!(logger, "Serving metrics"; "address" => addr.to_string());
+
+    Ok(cache)
+}
+
+fn respond(req: &Request<Body>, cache: &Cache) -> Response<Body> {
+    if req.method() != Method::GET || req.uri().path() != "/metrics" {
+        let mut response = Response::new(Body::from("Not Found\n"));
+        *response.status_mut() = StatusCode::NOT_FOUND;
+        return response;
+    }
+
+    let mut response = Response::new(Body::from(cache.text()));
+    response.headers_mut().insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("text/plain; version=0.0.4"),
+    );
+    response
+}
+
+fn timestamp(when: DateTime<Utc>) -> f64 {
+    when.timestamp_millis() as f64 / 1000.0
+}
+
+/// Builds up metrics in Prometheus' text exposition format.
+#[derive(Debug, Default)]
+struct Metrics {
+    text: String,
+}
+
+impl Metrics {
+    fn family(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.text, "# HELP {} {}", name, help);
+        let _ = writeln!(self.text, "# TYPE {} {}", name, kind);
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: f64) {
+        self.text.push_str(name);
+
+        if !labels.is_empty() {
+            let labels: Vec<_> = labels
+                .iter()
+                .map(|(key, value)| format!("{}=\"{}\"", key, escape(value)))
+                .collect();
+            let _ = write!(self.text, "{{{}}}", labels.join(","));
+        }
+
+        let _ = writeln!(self.text, " {}", value);
+    }
+}
+
+fn escape(label: &str) -> String {
+    label
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::driver::Statistics;
+    use chrono::TimeZone;
+
+    #[test]
+    fn render_the_last_backup_and_every_repository() {
+        let temp = tempfile::tempdir().unwrap();
+        let finished_at = Utc.ymd(2019, 5, 1).and_hms(2, 30, 0);
+        let last = Finished {
+            stats: Statistics {
+                success: 2,
+                error_count: 1,
+                total_repos: 3,
+                ..Default::default()
+            },
+            discovered: vec![(String::from("github"), 3)].into_iter().collect(),
+            failures: vec![(PathBuf::from("github.com/foo/baz"), String::from("Oops"))]
+                .into_iter()
+                .collect(),
+            aborted: false,
+            started_at: finished_at - chrono::Duration::seconds(90),
+            finished_at,
+            exit_code: 0,
+        };
+        RepoState {
+            provider: Some(String::from("github")),
+            last_success: Some(finished_at),
+            last_attempt: Some(finished_at),
+            fetch_seconds: Some(1.5),
+            fetch_bytes: Some(2048),
+            ..Default::default()
+        }
+        .save(temp.path(), Path::new("github.com/foo/bar"))
+        .unwrap();
+
+        let got = render(temp.path(), Some(&last)).unwrap();
+
+        let expected = [
+            "repo_backup_last_run_timestamp_seconds 1556677800",
+            "repo_backup_last_run_duration_seconds 90",
+            // the exit code only reflects conflicts, not failed repositories
+            "repo_backup_last_run_success 0",
+            "repo_backup_last_run_repositories{outcome=\"failed\"} 1",
+            "repo_backup_discovered_repositories{provider=\"github\"} 3",
+            "repo_backup_repository_last_success_timestamp_seconds{repo=\"github.com/foo/bar\",provider=\"github\"} 1556677800",
+            "repo_backup_repository_failed{repo=\"github.com/foo/bar\",provider=\"github\"} 0",
+            "repo_backup_repository_fetch_duration_seconds{repo=\"github.com/foo/bar\",provider=\"github\"} 1.5",
+            "repo_backup_repository_fetch_bytes{repo=\"github.com/foo/bar\",provider=\"github\"} 2048",
+        ];
+        for line in &expected {
+            assert!(got.lines().any(|l| l == *line), "{} not in\n{}", line, got);
+        }
+
+        // nothing about the last run before the daemon has done a backup
+        let got = render(temp.path(), None).unwrap();
+        assert!(!got.contains("repo_backup_last_run"));
+        assert!(got.contains("repo_backup_repository_fetch_bytes"));
+    }
+
+    #[test]
+    fn skip_repositories_with_corrupt_state() {
+        let temp = tempfile::tempdir().unwrap();
+        RepoState {
+            provider: Some(String::from("github")),
+            fetch_bytes: Some(2048),
+            ..Default::default()
+        }
+        .save(temp.path(), Path::new("github.com/foo/bar"))
+        .unwrap();
+        let corrupt = temp
+            .path()
+            .join(state::STATE_DIR)
+            .join("github.com/foo/baz.json");
+        fs::write(&corrupt, "{ not json").unwrap();
+
+        let got = render(temp.path(), None).unwrap();
+
+        assert!(
+            got.lines().any(|l| l == "repo_backup_state_errors 1"),
+            "{}",
+            got
+        );
+        assert!(got.contains("repo=\"github.com/foo/bar\""));
+        assert!(!got.contains("github.com/foo/baz"));
+    }
+
+    #[test]
+    fn escape_label_values() {
+        let mut metrics = Metrics::default();
+
+        metrics.sample("foo", &[("repo", "a\"b\\c")], 1.0);
+
+        assert_eq!(metrics.text, "foo{repo=\"a\\\"b\\\\c\"} 1\n");
+    }
+}
@@ -0,0 +1,244 @@
+//! Mirroring backed up repositories into another forge (e.g. when moving from
+//! GitLab to a self-hosted Gitea or Forgejo), creating projects as they're
+//! needed and keeping the mirrors in sync every time the backup runs.
+
+use crate::config::Config;
+use crate::git::GitRepo;
+use crate::providers::{self, Destination};
+use crate::restore::{self, RestoreOptions, Target};
+use failure::{Error, ResultExt};
+use std::collections::HashMap;
+use std::fmt::{self, Debug, Formatter};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MigrateConfig {
+    /// Should each repository be mirrored after it's backed up?
+    pub enabled: bool,
+    /// The config section of the provider to mirror into (e.g. `"gitea"`).
+    pub destination: String,
+    /// Put every mirror under this user, organisation or group instead of
+    /// the repository's original owner, which becomes part of its name.
+    pub owner: Option<String>,
+    /// Should newly created projects be private?
+    pub private: bool,
+    /// Only mirror repositories from these providers (every provider when
+    /// empty).
+    pub providers: Vec<String>,
+    /// Delete branches and tags from the mirrors when they're deleted
+    /// upstream.
+    pub prune: bool,
+}
+
+impl MigrateConfig {
+    pub const DESCRIPTION: &'static str =
+        "Mirror every repository into another forge (e.g. Gitea or Forgejo), keeping it in sync.";
+    pub const FIELDS: &'static [(&'static str, &'static str)] = &[
+        (
+            "enabled",
+            "Should each repository be pushed to the destination after it's backed up?",
+        ),
+        (
+            "destination",
+            "Where to mirror repositories to, either \"gitea\", \"gitlab\" or \
+             \"github\". The API key comes from that section of the config.",
+        ),
+        (
+            "owner",
+            "Put every mirror under this user, organisation or group instead of \
+             the original owner, which is kept in the mirror's name (e.g. \
+             \"alice-dotfiles\"). Missing organisations and groups are created \
+             where the destination allows it.",
+        ),
+        ("private", "Should newly created projects be private?"),
+        (
+            "providers",
+            "Only mirror repositories from these providers (e.g. [\"gitlab\"]). \
+             Repositories from every provider are mirrored when this is empty.",
+        ),
+        (
+            "prune",
+            "Delete branches and tags from the mirrors when they're deleted \
+             upstream. Anything pushed straight to a mirror is deleted too.",
+        ),
+    ];
+}
+
+impl Default for MigrateConfig {
+    fn default() -> MigrateConfig {
+        MigrateConfig {
+            enabled: false,
+            destination: String::from("gitea"),
+            owner: None,
+            private: true,
+            providers: Vec::new(),
+            prune: false,
+        }
+    }
+}
+
+/// Pushes downloaded repositories to the destination configured in
+/// `[general.migrate]`.
+pub struct Migration {
+    cfg: MigrateConfig,
+    destination: Box<dyn Destination>,
+    /// Which repository each mirror (`<owner>/<name>`) belongs to, so two
+    /// repositories never overwrite each other's mirror.
+    claimed: Mutex<HashMap<String, PathBuf>>,
+}
+
+impl Migration {
+    /// Set up the migration described by `[general.migrate]`, if it's
+    /// enabled.
+    pub fn from_config(cfg: &Config) -> Result<Option<Migration>, Error> {
+        let migrate = &cfg.general.migrate;
+
+        if !migrate.enabled {
+            return Ok(None);
+        }
+
+        let destination = providers::destination(cfg, &migrate.destination)
+            .context("Unable to set up the migration's destination")?;
+
+        Ok(Some(Migration {
+            cfg: migrate.clone(),
+            destination,
+            claimed: Mutex::default(),
+        }))
+    }
+
+    /// Should this repository be mirrored?
+    pub(crate) fn wants(&self, repo: &GitRepo) -> bool {
+        // forges keep wikis alongside the project, not as their own project
+        !repo.is_wiki()
+            && (self.cfg.providers.is_empty() || self.cfg.providers.contains(&repo.provider))
+    }
+
+    /// Push a downloaded repository to its mirror, returning the mirror's
+    /// URL.
+    pub(crate) fn mirror(&self, root: &Path, repo: &GitRepo) -> Result<String, Error> {
+        self.claim(repo)?;
+
+        let opts = RestoreOptions {
+            create: Some(self.cfg.destination.clone()),
+            owner: self.cfg.owner.clone(),
+            private: self.cfg.private,
+            prune: self.cfg.prune,
+            ..Default::default()
+        };
+
+        restore::restore_one(root, &repo.dest_dir, &opts, Some(&*self.destination))
+    }
+
+    /// Make sure no other repository is already being mirrored to the same
+    /// place as this one.
+    fn claim(&self, repo: &GitRepo) -> Result<(), Error> {
+        let target = Target::new(&repo.dest_dir, self.cfg.owner.as_deref())?;
+        let full_name = target.full_name();
+
+        let mut claimed = self.claimed.lock().expect("The lock was poisoned");
+        let claimant = claimed
+            .entry(full_name.clone())
+            .or_insert_with(|| repo.dest_dir.clone());
+
+        if *claimant != repo.dest_dir {
+            return Err(failure::format_err!(
+                "\"{}\" is already mirrored to \"{}\"",
+                claimant.display(),
+                full_name
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl Debug for Migration {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Migration").field("cfg", &self.cfg).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::Metadata;
+    use std::path::PathBuf;
+
+    struct Nowhere;
+
+    impl Destination for Nowhere {
+        fn ensure_project(&self, _: &str, _: &str, _: bool) -> Result<String, Error> {
+            unreachable!()
+        }
+    }
+
+    fn repo(provider: &str, dest: &str) -> GitRepo {
+        GitRepo {
+            provider: provider.to_string(),
+            dest_dir: PathBuf::from(dest),
+            ssh_url: format!("git@{}.git", dest),
+            wiki: false,
+            metadata: Metadata::default(),
+        }
+    }
+
+    #[test]
+    fn only_mirror_repositories_from_the_chosen_providers() {
+        let migration = Migration {
+            cfg: MigrateConfig {
+                enabled: true,
+                providers: vec![String::from("gitlab")],
+                ..Default::default()
+            },
+            destination: Box::new(Nowhere),
+            claimed: Mutex::default(),
+        };
+
+        assert!(migration.wants(&repo("gitlab", "gitlab.com/foo/bar")));
+        assert!(!migration.wants(&repo("github", "github.com/foo/bar")));
+        assert!(!migration.wants(&repo("gitlab", "gitlab.com/foo/bar").wiki()));
+    }
+
+    struct Offline;
+
+    impl Destination for Offline {
+        fn ensure_project(&self, _: &str, _: &str, _: bool) -> Result<String, Error> {
+            Err(failure::err_msg("Offline"))
+        }
+    }
+
+    #[test]
+    fn two_repositories_never_share_a_mirror() {
+        let migration = Migration {
+            cfg: MigrateConfig {
+                enabled: true,
+                owner: Some(String::from("backups")),
+                ..Default::default()
+            },
+            destination: Box::new(Offline),
+            claimed: Mutex::default(),
+        };
+        let root = Path::new("/nonexistent");
+        let first = repo("github", "github.com/alice/dotfiles");
+        let same_name = repo("gitlab", "gitlab.com/alice/dotfiles");
+        let other_owner = repo("github", "github.com/bob/dotfiles");
+
+        let err = migration.mirror(root, &first).unwrap_err();
+        assert!(err.to_string().contains("create the destination project"));
+
+        let err = migration.mirror(root, &same_name).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "\"github.com/alice/dotfiles\" is already mirrored to \"backups/alice-dotfiles\""
+        );
+
+        // the first repository can still be mirrored every time it's backed up
+        let err = migration.mirror(root, &first).unwrap_err();
+        assert!(err.to_string().contains("create the destination project"));
+        let err = migration.mirror(root, &other_owner).unwrap_err();
+        assert!(err.to_string().contains("create the destination project"));
+    }
+}
@@ -0,0 +1,503 @@
+//! Telling somebody when a backup fails, by email, a JSON webhook or a
+//! command, because nobody reads the mail cron sends.
+
+use crate::driver::Finished;
+use crate::filter;
+use crate::git::RepoLocks;
+use crate::state::RepoState;
+use chrono::{DateTime, Utc};
+use failure::{Error, ResultExt};
+use lettre::smtp::authentication::Credentials;
+use lettre::{SmtpClient, Transport};
+use lettre_email::EmailBuilder;
+use slog::Logger;
+use std::fmt::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NotifyConfig {
+    /// When to send a notification.
+    pub when: Trigger,
+    /// A shell command which is given the summary as JSON on stdin.
+    pub command: Option<String>,
+    /// A URL the summary is POSTed to as JSON.
+    pub webhook: Option<String>,
+    /// The mail server to send emails through.
+    pub smtp_server: Option<String>,
+    pub smtp_username: Option<String>,
+    pub smtp_password: Option<String>,
+    /// Who emails are sent from.
+    pub email_from: Option<String>,
+    /// Who emails are sent to.
+    pub email_to: Vec<String>,
+}
+
+impl NotifyConfig {
+    pub const DESCRIPTION: &'static str =
+        "Sending a summary when a backup finishes, by email, webhook or command.";
+    pub const FIELDS: &'static [(&'static str, &'static str)] = &[
+        (
+            "when",
+            "When to send a summary, either \"always\", \"failure\" (any \
+             repository failed, there were conflicts or the backup gave up) or \
+             \"new-failures\" (a repository failed which no earlier summary \
+             mentioned).",
+        ),
+        (
+            "command",
+            "A shell command to run, given the summary as JSON on stdin and its \
+             one-line description in $REPO_BACKUP_SUBJECT.",
+        ),
+        ("webhook", "A URL to POST the summary to as JSON."),
+        (
+            "smtp_server",
+            "The mail server to send the summary through, using STARTTLS on port \
+             587 (or plain SMTP when it's \"localhost\").",
+        ),
+        (
+            "smtp_username",
+            "The username to log into the mail server with.",
+        ),
+        (
+            "smtp_password",
+            "The password to log into the mail server with.",
+        ),
+        ("email_from", "The address emails are sent from."),
+        ("email_to", "The addresses emails are sent to."),
+    ];
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Trigger {
+    Always,
+    Failure,
+    NewFailures,
+}
+
+impl Default for Trigger {
+    fn default() -> Trigger {
+        Trigger::Failure
+    }
+}
+
+/// What happened during a backup.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub(crate) struct Summary {
+    /// Was every repository backed up?
+    pub succeeded: bool,
+    /// Did the backup give up because of too many errors?
+    pub aborted: bool,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+    pub discovered: usize,
+    pub successful: usize,
+    pub failed: usize,
+    pub ignored: usize,
+    pub duplicates: usize,
+    pub conflicts: usize,
+    pub failures: Vec<Failure>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub(crate) struct Failure {
+    /// The repository's path relative to the backup root.
+    pub repo: String,
+    pub error: String,
+    /// Is this the first summary to mention the failure?
+    pub new: bool,
+}
+
+impl Summary {
+    pub fn new(root: &Path, finished: &Finished) -> Summary {
+        let stats = &finished.stats;
+
+        let failures = finished
+            .failures
+            .iter()
+            .map(|(repo, error)| {
+                // repositories can start failing between backups (e.g. when
+                // a webhook fetches them), so compare against what we've
+                // told people rather than when the failure started
+                let notified = RepoState::load(root, repo)
+                    .map(|state| state.failure_notified)
+                    .unwrap_or(false);
+
+                Failure {
+                    repo: filter::normalized(repo),
+                    error: error.clone(),
+                    new: !notified,
+                }
+            })
+            .collect::<Vec<_>>();
+
+        Summary {
+            succeeded: finished.succeeded(),
+            aborted: finished.aborted,
+            started_at: finished.started_at,
+            finished_at: finished.finished_at,
+            discovered: stats.total_repos,
+            successful: stats.success,
+            failed: stats.error_count,
+            ignored: stats.ignored,
+            duplicates: stats.duplicates,
+            conflicts: stats.conflicts,
+            failures,
+        }
+    }
+
+    /// Does this backup deserve a notification?
+    pub fn should_notify(&self, when: Trigger) -> bool {
+        match when {
+            Trigger::Always => true,
+            Trigger::Failure => !self.succeeded,
+            Trigger::NewFailures => self.failures.iter().any(|f| f.new),
+        }
+    }
+
+    pub fn subject(&self) -> String {
+        if self.aborted {
+            format!(
+                "repo-backup gave up after {} repositories failed",
+                self.failed
+            )
+        } else if self.failed > 0 {
+            format!(
+                "repo-backup failed to back up {} of {} repositories",
+                self.failed,
+                self.failed + self.successful
+            )
+        } else if self.conflicts > 0 {
+            format!(
+                "repo-backup skipped {} repositories with conflicting destinations",
+                self.conflicts
+            )
+        } else {
+            format!("repo-backup backed up {} repositories", self.successful)
+        }
+    }
+
+    pub fn body(&self) -> String {
+        let mut body = String::new();
+
+        let _ = writeln!(body, "Started:    {}", self.started_at.to_rfc3339());
+        let _ = writeln!(body, "Finished:   {}", self.finished_at.to_rfc3339());
+        let _ = writeln!(body, "Discovered: {}", self.discovered);
+        let _ = writeln!(body, "Successful: {}", self.successful);
+        let _ = writeln!(body, "Failed:     {}", self.failed);
+        let _ = writeln!(body, "Ignored:    {}", self.ignored);
+        let _ = writeln!(body, "Duplicates: {}", self.duplicates);
+        let _ = writeln!(body, "Conflicts:  {}", self.conflicts);
+
+        if !self.failures.is_empty() {
+            let _ = writeln!(body, "\nFailures:");
+        }
+
+        for failure in &self.failures {
+            let new = if failure.new { " (new)" } else { "" };
+            let _ = writeln!(body, "- {}{}: {}", failure.repo, new, failure.error);
+        }
+
+        body
+    }
+}
+
+/// Send a summary of the backup everywhere it's been asked for, if it should
+/// be sent at all.
+pub(crate) fn notify(
+    cfg: &NotifyConfig,
+    root: &Path,
+    finished: &Finished,
+    locks: &RepoLocks,
+    logger: &Logger,
+) {
+    if cfg.command.is_none() && cfg.webhook.is_none() && cfg.smtp_server.is_none() {
+        return;
+    }
+
+    let summary = Summary::new(root, finished);
+
+    if !summary.should_notify(cfg.when) {
+        return;
+    }
+
+    let mut outcomes = Vec::new();
+
+    if let Some(ref command) = cfg.command {
+        outcomes.push(("command", run_command(command, &summary)));
+    }
+    if let Some(ref url) = cfg.webhook {
+        outcomes.push(("webhook", post_webhook(url, &summary)));
+    }
+    if let Some(ref server) = cfg.smtp_server {
+        outcomes.push(("email", send_email(cfg, server, &summary)));
+    }
+
+    let mut sent = false;
+
+    for (channel, outcome) in outcomes {
+        match outcome {
+            Ok(()) => {
+                info!(logger, "Sent a notification";
+                    "channel" => channel,
+                    "subject" => summary.subject());
+                sent = true;
+            }
+            Err(e) => {
+                warn!(logger, "Unable to send a notification";
+                    "channel" => channel,
+                    "error" => e.to_string());
+
+                for cause in e.iter_causes() {
+                    warn!(logger, "Caused By"; "cause" => cause.to_string());
+                }
+            }
+        }
+    }
+
+    if sent {
+        if let Err(e) = mark_notified(root, finished, locks) {
+            warn!(logger, "Unable to remember which failures were notified";
+                "error" => e.to_string());
+        }
+    }
+}
+
+/// Remember that every failure in this backup has been told to somebody, so
+/// they aren't new next time.
+fn mark_notified(root: &Path, finished: &Finished, locks: &RepoLocks) -> Result<(), Error> {
+    for repo in finished.failures.keys() {
+        // a webhook may be saving this repository's state right now
+        let _lock = locks.lock(repo);
+        let mut state = RepoState::load(root, repo)?;
+
+        if !state.failure_notified {
+            state.failure_notified = true;
+            state.save(root, repo)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn run_command(command: &str, summary: &Summary) -> Result<(), Error> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .env("REPO_BACKUP_SUBJECT", summary.subject())
+        .stdin(Stdio::piped())
+        .spawn()
+        .context("Unable to execute the command")?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        // the command doesn't have to read the summary
+        let _ = serde_json::to_writer_pretty(&mut stdin, summary);
+    }
+
+    let status = child.wait().context("Unable to wait for the command")?;
+
+    if !status.success() {
+        return Err(failure::format_err!("The command failed ({})", status));
+    }
+
+    Ok(())
+}
+
+fn post_webhook(url: &str, summary: &Summary) -> Result<(), Error> {
+    reqwest::Client::new()
+        .post(url)
+        .json(summary)
+        .send()
+        .and_then(|response| response.error_for_status())
+        .with_context(|_| format!("Unable to POST the summary to \"{}\"", url))?;
+
+    Ok(())
+}
+
+fn send_email(cfg: &NotifyConfig, server: &str, summary: &Summary) -> Result<(), Error> {
+    let from = cfg
+        .email_from
+        .as_ref()
+        .ok_or_else(|| failure::err_msg("Sending emails needs an email_from address"))?;
+
+    if cfg.email_to.is_empty() {
+        return Err(failure::err_msg("Sending emails needs an email_to address"));
+    }
+
+    let mut email = EmailBuilder::new()
+        .from(from.as_str())
+        .subject(summary.subject())
+        .text(summary.body());
+    for to in &cfg.email_to {
+        email = email.to(to.as_str());
+    }
+    let email = email.build().context("Unable to create the email")?;
+
+    let client = if server == "localhost" {
+        SmtpClient::new_unencrypted_localhost()
+    } else {
+        SmtpClient::new_simple(server)
+    }
+    .with_context(|_| format!("Unable to connect to \"{}\"", server))?;
+
+    let client = match (&cfg.smtp_username, &cfg.smtp_password) {
+        (Some(username), Some(password)) => {
+            client.credentials(Credentials::new(username.clone(), password.clone()))
+        }
+        _ => client,
+    };
+
+    client
+        .transport()
+        .send(email.into())
+        .context("Unable to send the email")?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::driver::Statistics;
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn finished(failures: &[(&str, &str)]) -> Finished {
+        let now = Utc::now();
+
+        Finished {
+            stats: Statistics {
+                success: 3,
+                error_count: failures.len(),
+                total_repos: 3 + failures.len(),
+                ..Default::default()
+            },
+            discovered: Default::default(),
+            failures: failures
+                .iter()
+                .map(|&(repo, error)| (PathBuf::from(repo), error.to_string()))
+                .collect(),
+            aborted: false,
+            started_at: now - chrono::Duration::minutes(5),
+            finished_at: now,
+            exit_code: 0,
+        }
+    }
+
+    #[test]
+    fn only_notify_about_new_failures_when_asked() {
+        let temp = tempfile::tempdir().unwrap();
+        let finished = finished(&[
+            ("github.com/foo/broken", "Repository not found"),
+            ("github.com/foo/flaky", "Connection reset"),
+        ]);
+        // broken has been failing for a week and we were told about it,
+        // flaky started failing when a webhook fetched it before this backup
+        RepoState {
+            failing_since: Some(finished.started_at - chrono::Duration::days(7)),
+            failure_notified: true,
+            ..Default::default()
+        }
+        .save(temp.path(), Path::new("github.com/foo/broken"))
+        .unwrap();
+        RepoState {
+            failing_since: Some(finished.started_at - chrono::Duration::hours(1)),
+            ..Default::default()
+        }
+        .save(temp.path(), Path::new("github.com/foo/flaky"))
+        .unwrap();
+
+        let summary = Summary::new(temp.path(), &finished);
+
+        assert!(!summary.succeeded);
+        assert_eq!(
+            summary.failures.iter().map(|f| f.new).collect::<Vec<_>>(),
+            vec![false, true]
+        );
+        assert_eq!(
+            summary.subject(),
+            "repo-backup failed to back up 2 of 5 repositories"
+        );
+        assert!(summary.should_notify(Trigger::Failure));
+        assert!(summary.should_notify(Trigger::NewFailures));
+
+        // nothing new when only the long-broken repository fails
+        let only_broken = self::finished(&[("github.com/foo/broken", "Repository not found")]);
+        let summary = Summary::new(temp.path(), &only_broken);
+        assert!(summary.should_notify(Trigger::Failure));
+        assert!(!summary.should_notify(Trigger::NewFailures));
+
+        // or once both failures have been mentioned
+        mark_notified(temp.path(), &finished, &RepoLocks::default()).unwrap();
+        let summary = Summary::new(temp.path(), &finished);
+        assert!(summary.should_notify(Trigger::Failure));
+        assert!(!summary.should_notify(Trigger::NewFailures));
+
+        let finished = self::finished(&[]);
+        let summary = Summary::new(temp.path(), &finished);
+        assert!(summary.succeeded);
+        assert!(!summary.should_notify(Trigger::Failure));
+        assert!(summary.should_notify(Trigger::Always));
+    }
+
+    #[test]
+    fn wait_for_workers_before_marking_failures_as_notified() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path().to_path_buf();
+        let repo = Path::new("github.com/foo/flaky");
+        let finished = finished(&[("github.com/foo/flaky", "Connection reset")]);
+        let locks = RepoLocks::default();
+
+        // a webhook is busy fetching the repository
+        let guard = locks.lock(repo);
+        let marking = {
+            let (root, locks) = (root.clone(), locks.clone());
+            std::thread::spawn(move || mark_notified(&root, &finished, &locks))
+        };
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        RepoState {
+            last_error: Some(String::from("Connection reset")),
+            ..Default::default()
+        }
+        .save(&root, repo)
+        .unwrap();
+        drop(guard);
+
+        marking.join().unwrap().unwrap();
+
+        let state = RepoState::load(&root, repo).unwrap();
+        assert!(state.failure_notified);
+        assert_eq!(state.last_error.as_deref(), Some("Connection reset"));
+    }
+
+    #[test]
+    fn pipe_the_summary_into_a_command() {
+        if cfg!(windows) {
+            return;
+        }
+
+        let temp = tempfile::tempdir().unwrap();
+        let output = temp.path().join("summary.json");
+        let summary = Summary::new(
+            temp.path(),
+            &finished(&[("github.com/foo/broken", "Repository not found")]),
+        );
+        let command = format!(
+            "echo \"$REPO_BACKUP_SUBJECT\" > '{0}.subject' && cat > '{0}'",
+            output.display()
+        );
+
+        run_command(&command, &summary).unwrap();
+
+        let got: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&output).unwrap()).unwrap();
+        assert_eq!(got["failures"][0]["repo"], "github.com/foo/broken");
+        assert_eq!(got["failures"][0]["new"], true);
+        let subject = fs::read_to_string(temp.path().join("summary.json.subject")).unwrap();
+        assert_eq!(subject.trim(), summary.subject());
+
+        assert!(run_command("exit 1", &summary).is_err());
+    }
+}
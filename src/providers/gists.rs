@@ -0,0 +1,203 @@
+use super::github::{self, GitHubConfig};
+use super::Provider;
+use crate::git::{GitRepo, Metadata};
+use chrono::{DateTime, Utc};
+use failure::{Error, ResultExt};
+use futures::sync::mpsc;
+use futures::Stream;
+use serde_json::Value;
+use slog::Logger;
+use std::path::Path;
+use std::thread;
+
+/// The longest a slugified gist description is allowed to be, so we don't
+/// end up with ridiculously long directory names.
+const MAX_SLUG_LENGTH: usize = 50;
+
+/// Retrieve the authenticated user's gists (and optionally the gists they
+/// have starred) from GitHub.
+#[derive(Debug, Clone)]
+pub struct Gists {
+    cfg: GitHubConfig,
+    logger: Logger,
+}
+
+impl Gists {
+    pub fn new(cfg: GitHubConfig, logger: Logger) -> Gists {
+        Gists { cfg, logger }
+    }
+
+    /// Does the config ask for any gists to be backed up?
+    pub fn wanted(cfg: &GitHubConfig) -> bool {
+        cfg.gists || cfg.starred_gists
+    }
+}
+
+impl Provider for Gists {
+    fn repositories(&mut self) -> Box<dyn Stream<Item = GitRepo, Error = Error>> {
+        let (tx, rx) = mpsc::unbounded();
+        let cfg = self.cfg.clone();
+        let logger = self.logger.clone();
+
+        thread::spawn(move || {
+            fetch_gists(&cfg, &tx, &logger);
+            debug!(logger, "Finished fetching gists");
+        });
+
+        Box::new(
+            rx.map_err(|_| failure::err_msg("Unable to read from the channel"))
+                .and_then(|item| item),
+        )
+    }
+}
+
+fn fetch_gists(
+    cfg: &GitHubConfig,
+    tx: &mpsc::UnboundedSender<Result<GitRepo, Error>>,
+    logger: &Logger,
+) {
+    let mut endpoints = Vec::new();
+    if cfg.gists {
+        endpoints.push("gists");
+    }
+    if cfg.starred_gists {
+        endpoints.push("gists/starred");
+    }
+
+    for endpoint in endpoints {
+        debug!(logger, "Fetching gists"; "endpoint" => endpoint);
+
+        let got = github::api_client(cfg).and_then(|client| {
+            let url = format!("{}/{}?per_page=100", github::API_ROOT, endpoint);
+            client
+                .get_all(&url)
+                .context("Unable to fetch the list of gists")
+                .map_err(Error::from)
+        });
+
+        let gists = match got {
+            Ok(gists) => gists,
+            Err(e) => {
+                let _ = tx.unbounded_send(Err(e));
+                return;
+            }
+        };
+
+        for gist in gists.iter().filter_map(gist_to_repo) {
+            trace!(logger, "Found gist"; "dest-dir" => gist.dest_dir.display());
+
+            if tx.unbounded_send(Ok(gist)).is_err() {
+                // the receiver was dropped so there's no point continuing...
+                return;
+            }
+        }
+    }
+}
+
+fn gist_to_repo(gist: &Value) -> Option<GitRepo> {
+    let id = gist["id"].as_str()?;
+    let owner = gist["owner"]["login"].as_str()?;
+    let description = gist["description"]
+        .as_str()
+        .filter(|d| !d.trim().is_empty())
+        .map(String::from);
+    let slug = slugify(description.as_deref().unwrap_or_default());
+
+    let name = if slug.is_empty() {
+        id.to_string()
+    } else {
+        format!("{}-{}", id, slug)
+    };
+
+    let last_push = gist["updated_at"]
+        .as_str()
+        .and_then(|s| DateTime::parse_from_rfc3339(s).ok())
+        .map(|when| when.with_timezone(&Utc));
+
+    Some(GitRepo {
+        provider: GitHubConfig::KEY.to_string(),
+        dest_dir: Path::new("gist.github.com").join(owner).join(name),
+        ssh_url: format!("git@gist.github.com:{}.git", id),
+        wiki: false,
+        metadata: Metadata {
+            private: gist["public"].as_bool().map(|public| !public),
+            description,
+            // the description can change, but the id never does
+            stable_name: Some(id.to_string()),
+            last_push,
+            ..Default::default()
+        },
+    })
+}
+
+/// Turn a gist's description into something which can be used in a
+/// directory name (e.g. "My *awesome* script!" becomes "my-awesome-script").
+fn slugify(description: &str) -> String {
+    let mut slug = String::new();
+
+    for c in description.chars() {
+        if c.is_alphanumeric() {
+            slug.extend(c.to_lowercase());
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+
+        if slug.chars().count() >= MAX_SLUG_LENGTH {
+            break;
+        }
+    }
+
+    slug.trim_end_matches('-').to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn slugify_gist_descriptions() {
+        let inputs = vec![
+            ("", ""),
+            ("script", "script"),
+            ("My *awesome* script!", "my-awesome-script"),
+            ("  leading and trailing  ", "leading-and-trailing"),
+        ];
+
+        for (input, should_be) in inputs {
+            assert_eq!(slugify(input), should_be, "{:?}", input);
+        }
+
+        let long = "a ".repeat(100);
+        assert!(slugify(&long).chars().count() <= MAX_SLUG_LENGTH);
+    }
+
+    #[test]
+    fn convert_a_gist_to_a_repo() {
+        let gist = json!({
+            "id": "aa5a315d61ae9438b18d",
+            "description": "Hello World Examples",
+            "public": true,
+            "owner": { "login": "octocat" },
+            "updated_at": "2010-04-14T02:15:15Z",
+        });
+
+        let got = gist_to_repo(&gist).unwrap();
+
+        assert_eq!(
+            got.dest_dir,
+            Path::new("gist.github.com/octocat/aa5a315d61ae9438b18d-hello-world-examples")
+        );
+        assert_eq!(got.ssh_url, "git@gist.github.com:aa5a315d61ae9438b18d.git");
+        assert_eq!(got.metadata.private, Some(false));
+        assert_eq!(
+            got.metadata.description.as_ref().unwrap(),
+            "Hello World Examples"
+        );
+        assert_eq!(
+            got.metadata.stable_name.as_deref(),
+            Some("aa5a315d61ae9438b18d")
+        );
+        assert!(got.metadata.last_push.is_some());
+    }
+}
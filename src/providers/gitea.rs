@@ -0,0 +1,115 @@
+use super::Destination;
+use crate::config::{Config, Documented, Fields};
+use crate::metadata::JsonClient;
+use failure::{Error, ResultExt};
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::json;
+
+/// Pushes repositories to a Gitea (or Forgejo) instance, creating
+/// organisations and repositories as they're needed.
+#[derive(Debug, Clone)]
+pub(crate) struct GiteaDestination {
+    base: String,
+    client: JsonClient,
+}
+
+impl GiteaDestination {
+    pub fn from_config(cfg: &Config) -> Result<GiteaDestination, Error> {
+        let gitea_config: GiteaConfig = cfg
+            .get_deserialized(GiteaConfig::KEY)
+            .context("Unable to read the [gitea] section")?;
+
+        let mut headers = HeaderMap::new();
+        let token = HeaderValue::from_str(&format!("token {}", gitea_config.api_key))
+            .context("Invalid Gitea API key")?;
+        headers.insert(AUTHORIZATION, token);
+
+        Ok(GiteaDestination {
+            base: format!("{}/api/v1", gitea_config.url.trim_end_matches('/')),
+            client: JsonClient::new(headers)?,
+        })
+    }
+
+    /// Make sure an organisation exists, unless `owner` is the current user.
+    /// Returns the endpoint new repositories should be created with.
+    fn ensure_owner(&self, owner: &str, private: bool) -> Result<String, Error> {
+        let user = self.client.get(&format!("{}/user", self.base))?;
+        let login = user["login"].as_str().unwrap_or_default();
+
+        if login.eq_ignore_ascii_case(owner) {
+            return Ok(format!("{}/user/repos", self.base));
+        }
+
+        let org_url = format!("{}/orgs/{}", self.base, owner);
+
+        if self.client.get_optional(&org_url)?.is_none() {
+            let visibility = if private { "private" } else { "public" };
+            let body = json!({ "username": owner, "visibility": visibility });
+            self.client
+                .post(&format!("{}/orgs", self.base), &body)
+                .with_context(|_| format!("Unable to create the \"{}\" organisation", owner))?;
+        }
+
+        Ok(format!("{}/repos", org_url))
+    }
+}
+
+impl Destination for GiteaDestination {
+    fn ensure_project(&self, owner: &str, name: &str, private: bool) -> Result<String, Error> {
+        // organisations can't be nested (e.g. GitLab subgroups)
+        let owner = owner.replace('/', "-");
+        let url = format!("{}/repos/{}/{}", self.base, owner, name);
+
+        let repo = match self.client.get_optional(&url)? {
+            Some(repo) => repo,
+            None => {
+                let endpoint = self.ensure_owner(&owner, private)?;
+                self.client
+                    .post(&endpoint, &json!({ "name": name, "private": private }))
+                    .with_context(|_| format!("Unable to create {}/{}", owner, name))?
+            }
+        };
+
+        repo["ssh_url"]
+            .as_str()
+            .map(String::from)
+            .ok_or_else(|| failure::err_msg("Gitea didn't say where to push to"))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct GiteaConfig {
+    /// The Gitea instance's base URL (e.g. `https://gitea.example.com`).
+    pub url: String,
+    pub api_key: String,
+}
+
+impl GiteaConfig {
+    pub const KEY: &'static str = "gitea";
+}
+
+impl Documented for GiteaConfig {
+    const DESCRIPTION: &'static str =
+        "A Gitea or Forgejo instance repositories can be restored or migrated to.";
+    const FIELDS: Fields = &[
+        (
+            "url",
+            "The instance's base URL, e.g. \"https://gitea.example.com\".",
+        ),
+        (
+            "api-key",
+            "An access token which can create organisations and repositories.",
+        ),
+    ];
+}
+
+impl Default for GiteaConfig {
+    fn default() -> GiteaConfig {
+        GiteaConfig {
+            url: String::from("http://localhost:3000"),
+            api_key: String::new(),
+        }
+    }
+}
@@ -1,22 +1,52 @@
-use super::Provider;
-use crate::config::Config;
+use super::{Destination, Downloads, DownloadsReceiver, Provider};
+use crate::config::{Config, Documented, Fields};
+use crate::filter::FilterConfig;
+use crate::metadata::{self, Asset, JsonClient, Since};
 use crate::GitRepo;
-use failure::{Error, SyncFailure};
+use chrono::Utc;
+use failure::{Error, ResultExt, SyncFailure};
+use futures::future::{self, Either};
 use futures::stream::{self, Stream};
 use futures::Future;
+use hubcaps::repositories::Repo;
 use hubcaps::Credentials;
+use reqwest::header::{HeaderMap, HeaderValue, ACCEPT, AUTHORIZATION, USER_AGENT};
+use reqwest::r#async;
+use serde_json::json;
 use slog::Logger;
+use std::path::Path;
+use std::thread;
+
+pub(crate) const API_ROOT: &str = "https://api.github.com";
+/// Topics are only included when asking for this preview media type.
+const TOPICS_PREVIEW: &str = "application/vnd.github.mercy-preview+json";
+/// How many topic lookups can be in flight at once.
+const TOPIC_REQUESTS: usize = 8;
 
 /// Retrieve GitHub repositories.
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub struct GitHub {
     cfg: GitHubConfig,
     logger: Logger,
+    downloads: Downloads,
+    topics: bool,
 }
 
 impl GitHub {
     pub fn new(cfg: GitHubConfig, logger: Logger) -> GitHub {
-        GitHub { cfg, logger }
+        GitHub {
+            cfg,
+            logger,
+            downloads: Downloads::default(),
+            topics: false,
+        }
+    }
+
+    /// Look up each repository's topics. This costs an extra request per
+    /// repository, so only do it when something filters on topics.
+    pub fn with_topics(mut self, topics: bool) -> GitHub {
+        self.topics = topics;
+        self
     }
 
     pub fn from_config(cfg: &Config, logger: &Logger) -> Result<GitHub, Error> {
@@ -26,26 +56,343 @@ impl GitHub {
 }
 
 impl Provider for GitHub {
-    fn repositories(&self) -> Box<dyn Stream<Item = GitRepo, Error = Error>> {
+    fn repositories(&mut self) -> Box<dyn Stream<Item = GitRepo, Error = Error>> {
+        if self.cfg.metadata || self.cfg.releases {
+            let (downloads, rx) = super::downloads();
+            self.downloads = downloads;
+
+            let cfg = self.cfg.clone();
+            let logger = self.logger.clone();
+            thread::spawn(move || export_metadata(&cfg, &rx, &logger));
+        }
+
         debug!(self.logger, "Creating the GitHub client");
         let client = hubcaps::Github::new(self.cfg.agent.clone(), self.cfg.credentials.clone());
 
-        let user_repos = client.repos().iter(&Default::default()).map(GitRepo::from);
+        let user_repos = client.repos().iter(&Default::default());
+        let wikis = self.cfg.wikis;
+
+        let repos: Box<dyn Stream<Item = Repo, Error = hubcaps::Error>> = if self.cfg.orgs {
+            Box::new(user_repos.select(org_repos(&client)))
+        } else {
+            Box::new(user_repos)
+        };
 
-        if self.cfg.orgs {
-            Box::new(
-                user_repos
-                    .select(org_repos(&client))
-                    .map_err(SyncFailure::new)
-                    .map_err(Error::from),
-            )
+        let topics = if self.topics {
+            match topics_client(&self.cfg) {
+                Ok(client) => Some(client),
+                Err(e) => return Box::new(stream::once(Err(e))),
+            }
         } else {
-            Box::new(user_repos.map_err(SyncFailure::new).map_err(Error::from))
+            None
+        };
+        let logger = self.logger.clone();
+
+        Box::new(
+            repos
+                .map_err(SyncFailure::new)
+                .map_err(Error::from)
+                .map(move |repo| {
+                    let has_wiki = wikis && repo.has_wiki;
+                    let repo = GitRepo::from(repo);
+
+                    let repo = match topics {
+                        Some(ref client) => Either::A(fetch_topics(client, repo, &logger)),
+                        None => Either::B(future::ok(repo)),
+                    };
+                    repo.map(move |repo| stream::iter_ok(with_wiki(repo, has_wiki)))
+                })
+                .buffered(TOPIC_REQUESTS)
+                .flatten(),
+        )
+    }
+
+    fn downloaded(&mut self, repo: &GitRepo, dir: &Path) {
+        self.downloads.send(repo, dir);
+    }
+
+    fn finish(&mut self) -> Box<dyn Future<Item = (), Error = Error>> {
+        self.downloads.finish()
+    }
+}
+
+/// The repository, followed by its wiki (if it has one and we want wikis).
+fn with_wiki(repo: GitRepo, has_wiki: bool) -> Vec<GitRepo> {
+    if has_wiki {
+        let wiki = repo.wiki();
+        vec![repo, wiki]
+    } else {
+        vec![repo]
+    }
+}
+
+/// Fill in the repository's topics. If they can't be retrieved the topics
+/// are left unknown, so topic filters won't skip the repository.
+fn fetch_topics(
+    client: &r#async::Client,
+    mut repo: GitRepo,
+    logger: &Logger,
+) -> impl Future<Item = GitRepo, Error = Error> {
+    let url = format!(
+        "{}/repos/{}/topics",
+        API_ROOT,
+        full_name(&repo).unwrap_or_default()
+    );
+    let logger = logger.clone();
+
+    client
+        .get(&url)
+        .header(ACCEPT, TOPICS_PREVIEW)
+        .send()
+        .and_then(|response| response.error_for_status())
+        .and_then(|mut response| response.json::<Topics>())
+        .then(move |topics| {
+            match topics {
+                Ok(topics) => repo.metadata.topics = Some(topics.names),
+                Err(e) => warn!(logger, "Unable to get the repository's topics";
+                    "repo" => repo.dest_dir.display(),
+                    "error" => e.to_string()),
+            }
+
+            Ok(repo)
+        })
+}
+
+#[derive(Debug, Deserialize)]
+struct Topics {
+    names: Vec<String>,
+}
+
+/// The repository's `owner/name`.
+fn full_name(repo: &GitRepo) -> Option<String> {
+    repo.dest_dir
+        .strip_prefix("github.com")
+        .ok()
+        .map(|name| name.to_string_lossy().replace('\\', "/"))
+}
+
+/// Export the issues, pull requests, etc. for each downloaded repository.
+fn export_metadata(cfg: &GitHubConfig, downloads: &DownloadsReceiver, logger: &Logger) {
+    let client = match api_client(cfg) {
+        Ok(c) => c,
+        Err(e) => {
+            warn!(logger, "Unable to create the GitHub API client"; "error" => e.to_string());
+            return;
+        }
+    };
+
+    for (repo, dir) in downloads.iter().filter(|(repo, _)| !repo.is_wiki()) {
+        let full_name = match full_name(&repo) {
+            Some(name) => name,
+            None => continue,
+        };
+        let meta_dir = metadata::meta_dir(&dir);
+
+        debug!(logger, "Exporting GitHub metadata";
+            "repo" => &full_name,
+            "dest-dir" => meta_dir.display());
+
+        if let Err(e) = export_repo(cfg, &client, &full_name, &meta_dir) {
+            warn!(logger, "Unable to export the repository's metadata";
+                "repo" => &full_name,
+                "error" => e.to_string());
+
+            for cause in e.iter_causes() {
+                warn!(logger, "Caused By"; "cause" => cause.to_string());
+            }
+        }
+    }
+
+    debug!(logger, "Finished exporting GitHub metadata");
+}
+
+pub(crate) fn api_client(cfg: &GitHubConfig) -> Result<JsonClient, Error> {
+    JsonClient::new(headers(cfg)?)
+}
+
+fn topics_client(cfg: &GitHubConfig) -> Result<r#async::Client, Error> {
+    let client = r#async::Client::builder()
+        .default_headers(headers(cfg)?)
+        .build()
+        .context("Unable to create the GitHub API client")?;
+
+    Ok(client)
+}
+
+fn headers(cfg: &GitHubConfig) -> Result<HeaderMap, Error> {
+    let mut headers = HeaderMap::new();
+    headers.insert(USER_AGENT, HeaderValue::from_str(&cfg.agent)?);
+
+    if let Credentials::Token(ref token) = cfg.credentials {
+        let value =
+            HeaderValue::from_str(&format!("token {}", token)).context("Invalid GitHub token")?;
+        headers.insert(AUTHORIZATION, value);
+    }
+
+    Ok(headers)
+}
+
+/// Pushes repositories to GitHub, creating them (under your account or an
+/// organisation) if they don't exist.
+#[derive(Debug, Clone)]
+pub(crate) struct GitHubDestination {
+    client: JsonClient,
+}
+
+impl GitHubDestination {
+    pub fn from_config(cfg: &Config) -> Result<GitHubDestination, Error> {
+        let gh_config: GitHubConfig = cfg
+            .get_deserialized(GitHubConfig::KEY)
+            .context("Unable to read the [github] section")?;
+
+        match gh_config.credentials {
+            Credentials::Token(ref token) if !token.is_empty() => {}
+            _ => {
+                return Err(failure::err_msg(
+                    "Creating GitHub repositories requires an API token",
+                ))
+            }
         }
+
+        Ok(GitHubDestination {
+            client: api_client(&gh_config)?,
+        })
     }
 }
 
-fn org_repos<T>(client: &hubcaps::Github<T>) -> impl Stream<Item = GitRepo, Error = hubcaps::Error>
+impl Destination for GitHubDestination {
+    fn ensure_project(&self, owner: &str, name: &str, private: bool) -> Result<String, Error> {
+        let url = format!("{}/repos/{}/{}", API_ROOT, owner, name);
+
+        let repo = match self.client.get_optional(&url)? {
+            Some(repo) => repo,
+            None => {
+                let user = self.client.get(&format!("{}/user", API_ROOT))?;
+                let login = user["login"].as_str().unwrap_or_default();
+                let is_me = login.eq_ignore_ascii_case(owner);
+
+                let endpoint = if is_me {
+                    format!("{}/user/repos", API_ROOT)
+                } else {
+                    format!("{}/orgs/{}/repos", API_ROOT, owner)
+                };
+
+                self.client
+                    .post(&endpoint, &json!({ "name": name, "private": private }))
+                    .with_context(|_| format!("Unable to create {}/{}", owner, name))?
+            }
+        };
+
+        repo["ssh_url"]
+            .as_str()
+            .map(String::from)
+            .ok_or_else(|| failure::err_msg("GitHub didn't say where to push to"))
+    }
+}
+
+fn export_repo(
+    cfg: &GitHubConfig,
+    client: &JsonClient,
+    full_name: &str,
+    meta_dir: &Path,
+) -> Result<(), Error> {
+    let base = format!("{}/repos/{}", API_ROOT, full_name);
+
+    if cfg.metadata {
+        export_repo_metadata(client, &base, meta_dir)?;
+    }
+    if cfg.releases {
+        export_releases(client, &base, meta_dir)?;
+    }
+
+    Ok(())
+}
+
+fn export_repo_metadata(client: &JsonClient, base: &str, meta_dir: &Path) -> Result<(), Error> {
+    let mut since = Since::load(meta_dir)?;
+
+    // Issues (which includes pull requests) and comments can be filtered by
+    // when they were last updated
+    for &(kind, endpoint, key) in &[
+        ("issues", "issues?state=all", "number"),
+        ("comments", "issues/comments?sort=updated", "id"),
+        ("review-comments", "pulls/comments?sort=updated", "id"),
+    ] {
+        let started = Utc::now();
+        let url = format!(
+            "{}/{}&per_page=100{}",
+            base,
+            endpoint,
+            since.query(kind, "since")
+        );
+
+        let items = client.get_all(&url)?;
+        metadata::write_each(&meta_dir.join(kind), &items, key)?;
+        since.update(kind, started)?;
+    }
+
+    // pull requests can't, so we ask for the most recently updated first and
+    // stop once we reach one we've already seen
+    let started = Utc::now();
+    let last_time = since.get("pulls");
+    let url = format!(
+        "{}/pulls?state=all&sort=updated&direction=desc&per_page=100",
+        base
+    );
+    client.for_each_page(&url, |page| {
+        let changed: Vec<_> = page
+            .into_iter()
+            .take_while(|pr| metadata::updated_since(pr, last_time))
+            .collect();
+        let keep_going = changed.len() == 100;
+
+        metadata::write_each(&meta_dir.join("pulls"), &changed, "number")?;
+        Ok(keep_going)
+    })?;
+    since.update("pulls", started)?;
+
+    // labels and milestones are small enough to just grab everything
+    let labels = client.get_all(&format!("{}/labels?per_page=100", base))?;
+    metadata::write_json(&meta_dir.join("labels.json"), &labels.into())?;
+    let milestones = client.get_all(&format!("{}/milestones?state=all&per_page=100", base))?;
+    metadata::write_json(&meta_dir.join("milestones.json"), &milestones.into())?;
+
+    Ok(())
+}
+
+/// Save each release and download its assets into `releases/<tag>/`.
+fn export_releases(client: &JsonClient, base: &str, meta_dir: &Path) -> Result<(), Error> {
+    let releases = client.get_all(&format!("{}/releases?per_page=100", base))?;
+
+    for release in &releases {
+        let tag = match release["tag_name"].as_str() {
+            Some(tag) => tag,
+            None => continue,
+        };
+        let dir = meta_dir.join("releases").join(metadata::safe_name(tag));
+        metadata::write_json(&dir.join("release.json"), release)?;
+
+        // use the API URL instead of "browser_download_url" so assets from
+        // private repositories can be downloaded too
+        let assets: Vec<_> = release["assets"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|asset| {
+                Some(Asset {
+                    name: asset["name"].as_str()?.to_string(),
+                    url: asset["url"].as_str()?.to_string(),
+                    size: asset["size"].as_u64(),
+                })
+            })
+            .collect();
+        metadata::download_assets(client, &assets, &dir)?;
+    }
+
+    Ok(())
+}
+
+fn org_repos<T>(client: &hubcaps::Github<T>) -> impl Stream<Item = Repo, Error = hubcaps::Error>
 where
     T: Clone + hyper::client::connect::Connect,
 {
@@ -57,10 +404,10 @@ where
         .flatten_stream()
         .map(move |org| c2.org_repos(org).iter(&Default::default()))
         .flatten()
-        .map(GitRepo::from)
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct GitHubConfig {
     /// The user-agent to use.
     #[serde(default)]
@@ -71,8 +418,26 @@ pub struct GitHubConfig {
     /// Should we include repositories from organisations you belong to?
     #[serde(default)]
     pub orgs: bool,
+    /// Should we export issues, pull requests, comments, labels and
+    /// milestones as JSON?
+    #[serde(default)]
+    pub metadata: bool,
+    /// Should we download each repository's releases and their assets?
+    #[serde(default)]
+    pub releases: bool,
+    /// Should we also back up each repository's wiki?
+    #[serde(default)]
+    pub wikis: bool,
+    /// Should we back up your gists?
+    #[serde(default)]
+    pub gists: bool,
+    /// Should we back up the gists you have starred?
+    #[serde(default)]
+    pub starred_gists: bool,
     #[serde(with = "cred_serde_shim")]
     pub credentials: Credentials,
+    #[serde(default)]
+    pub filter: FilterConfig,
 }
 
 impl GitHubConfig {
@@ -80,6 +445,46 @@ impl GitHubConfig {
     pub const DEFAULT_AGENT: &'static str = "repo-backup";
 }
 
+impl Documented for GitHubConfig {
+    const DESCRIPTION: &'static str = "Back up repositories from GitHub.";
+    const FIELDS: Fields = &[
+        (
+            "credentials",
+            "A personal access token with the \"public_repo\" (or \"repo\", for \
+             private repositories) permission.",
+        ),
+        ("agent", "The user-agent to use."),
+        ("starred", "Should we include starred repositories?"),
+        (
+            "orgs",
+            "Should we include repositories from organisations you belong to?",
+        ),
+        (
+            "metadata",
+            "Export issues, pull requests, comments, review comments, labels and \
+             milestones as JSON into a \"<repo>.meta/\" directory next to each \
+             repository. Reruns only fetch what changed.",
+        ),
+        (
+            "releases",
+            "Download each release and its assets into \"<repo>.meta/releases/<tag>/\". \
+             Assets which were already downloaded are skipped.",
+        ),
+        (
+            "wikis",
+            "Also back up the wiki of every repository which has one, saving it \
+             to a \"<repo>.wiki\" directory next to the repository.",
+        ),
+        (
+            "gists",
+            "Back up your gists to \"gist.github.com/<user>/<id>\".",
+        ),
+        ("starred_gists", "Also back up the gists you have starred."),
+        ("filter", FilterConfig::DESCRIPTION),
+    ];
+    const NESTED: &'static [(&'static str, Fields)] = &[("filter", FilterConfig::FIELDS)];
+}
+
 impl Default for GitHubConfig {
     fn default() -> GitHubConfig {
         GitHubConfig {
@@ -87,6 +492,12 @@ impl Default for GitHubConfig {
             credentials: Credentials::Token(String::new()),
             starred: true,
             orgs: true,
+            metadata: false,
+            releases: false,
+            wikis: false,
+            gists: false,
+            starred_gists: false,
+            filter: FilterConfig::default(),
         }
     }
 }
@@ -108,3 +519,37 @@ mod cred_serde_shim {
         Ok(Credentials::Token(api_key))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::{Decision, Filters};
+    use std::path::PathBuf;
+
+    #[test]
+    fn filter_repositories_by_their_topics() {
+        let mut cfg = Config::default();
+        cfg.rest.insert(
+            String::from(GitHubConfig::KEY),
+            toml::from_str("[filter]\nonly_topics = ['backup']").unwrap(),
+        );
+        let filters = Filters::from_config(&cfg).unwrap();
+        let topics: Topics = serde_json::from_str(r#"{"names": ["backup", "rust"]}"#).unwrap();
+        let mut wanted = GitRepo {
+            provider: String::from(GitHubConfig::KEY),
+            dest_dir: PathBuf::from("github.com/me/wanted"),
+            ssh_url: String::from("git@github.com:me/wanted.git"),
+            wiki: false,
+            metadata: Default::default(),
+        };
+        wanted.metadata.topics = Some(topics.names);
+        let mut untagged = wanted.clone();
+        untagged.metadata.topics = Some(Vec::new());
+
+        assert!(filters.wants_topics(GitHubConfig::KEY));
+        assert!(!filters.wants_topics("gitlab"));
+        assert_eq!(full_name(&wanted).unwrap(), "me/wanted");
+        assert_eq!(filters.check(&wanted), Decision::Include);
+        assert!(filters.check(&untagged) != Decision::Include);
+    }
+}
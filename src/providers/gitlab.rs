@@ -1,37 +1,68 @@
-use crate::git::GitRepo;
-use crate::providers::Provider;
-use failure::{Error, Fail, SyncFailure};
+use crate::config::{Config, Documented, Fields};
+use crate::filter::FilterConfig;
+use crate::git::{GitRepo, Metadata};
+use crate::metadata::{self, Asset, JsonClient, Since};
+use crate::providers::{Destination, Downloads, DownloadsReceiver, Provider};
+use chrono::Utc;
+use failure::{Error, Fail, ResultExt, SyncFailure};
 use futures::sync::mpsc;
-use futures::Stream;
+use futures::{Future, Stream};
+use reqwest::header::{HeaderMap, HeaderValue};
 use serde_derive::{Deserialize, Serialize};
+use serde_json::{json, Value};
 use slog::Logger;
-use std::path::Path;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use std::thread;
 
 const DEFAULT_HOSTNAME: &str = "gitlab.com";
 
 /// The GitLab provider.
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub struct GitLab {
     cfg: GitLabConfig,
     logger: Logger,
+    downloads: Downloads,
+    languages: bool,
 }
 
 impl GitLab {
     pub fn new(cfg: GitLabConfig, logger: Logger) -> GitLab {
-        GitLab { cfg, logger }
+        GitLab {
+            cfg,
+            logger,
+            downloads: Downloads::default(),
+            languages: false,
+        }
+    }
+
+    /// Look up each project's primary language. This costs an extra request
+    /// per project, so only do it when something filters on languages.
+    pub fn with_languages(mut self, languages: bool) -> GitLab {
+        self.languages = languages;
+        self
     }
 }
 
 impl Provider for GitLab {
-    fn repositories(&self) -> Box<dyn Stream<Item = GitRepo, Error = Error>> {
+    fn repositories(&mut self) -> Box<dyn Stream<Item = GitRepo, Error = Error>> {
         let (tx, rx) = mpsc::unbounded();
         let cfg = self.cfg.clone();
         let logger = self.logger.clone();
+        let languages = self.languages;
+
+        let downloads = if cfg.metadata || cfg.releases {
+            let (downloads, rx) = super::downloads();
+            self.downloads = downloads;
+            Some(rx)
+        } else {
+            None
+        };
 
         thread::spawn(move || {
-            spawn_client(cfg, tx, &logger);
-            debug!(logger, "Finished fetching GitLab repos");
+            spawn_client(cfg, tx, downloads, languages, &logger);
+            debug!(logger, "Finished with the GitLab client");
         });
 
         Box::new(
@@ -39,16 +70,26 @@ impl Provider for GitLab {
                 .and_then(|item| item),
         )
     }
+
+    fn downloaded(&mut self, repo: &GitRepo, dir: &Path) {
+        self.downloads.send(repo, dir);
+    }
+
+    fn finish(&mut self) -> Box<dyn Future<Item = (), Error = Error>> {
+        self.downloads.finish()
+    }
 }
 
 fn spawn_client(
     cfg: GitLabConfig,
     tx: mpsc::UnboundedSender<Result<GitRepo, Error>>,
+    downloads: Option<DownloadsReceiver>,
+    languages: bool,
     logger: &Logger,
 ) {
     debug!(logger, "Creating the GitLab client");
 
-    let client = match gitlab::Gitlab::new(cfg.hostname, cfg.api_key) {
+    let client = match gitlab::Gitlab::new(cfg.hostname.clone(), cfg.api_key.clone()) {
         Ok(c) => c,
         Err(e) => {
             let err = SyncFailure::new(e).context("Unable to create the GitLab client");
@@ -73,6 +114,19 @@ fn spawn_client(
     debug!(logger, "Retreived the project list";
         "project-count" => projects.len());
 
+    let mut project_ids = HashMap::new();
+    let languages = if languages {
+        match api_client(&cfg) {
+            Ok(client) => Some(client),
+            Err(e) => {
+                let _ = tx.unbounded_send(Err(e));
+                return;
+            }
+        }
+    } else {
+        None
+    };
+
     for project in projects {
         trace!(logger, "Found project";
             "name" => &project.name_with_namespace,
@@ -82,20 +136,450 @@ fn spawn_client(
             "forks" => project.forks_count,
             "stats" => project.statistics.as_ref().map(|stats| format!("{:?}", stats)));
 
-        let repo = project_to_repo(project);
-        if tx.unbounded_send(Ok(repo)).is_err() {
-            // the receiver was dropped so there's no point continuing...
+        let id = project.id.value();
+        let has_wiki = cfg.wikis && project.wiki_enabled;
+        let mut repo = project_to_repo(project);
+        project_ids.insert(repo.dest_dir.clone(), id);
+
+        if let Some(ref client) = languages {
+            let url = format!("https://{}/api/v4/projects/{}/languages", cfg.hostname, id);
+
+            // leave the language unknown so filters won't skip the project
+            match client.get(&url) {
+                Ok(languages) => repo.metadata.language = primary_language(&languages),
+                Err(e) => warn!(logger, "Unable to get the project's languages";
+                    "dest-dir" => repo.dest_dir.display(),
+                    "error" => e.to_string()),
+            }
+        }
+
+        let wiki = if has_wiki { Some(repo.wiki()) } else { None };
+
+        for repo in Some(repo).into_iter().chain(wiki) {
+            if tx.unbounded_send(Ok(repo)).is_err() {
+                // the receiver was dropped so there's no point continuing...
+                return;
+            }
+        }
+    }
+
+    // let the driver know we've found every project
+    drop(tx);
+    debug!(logger, "Finished fetching GitLab repos");
+
+    if let Some(downloads) = downloads {
+        export_metadata(&cfg, &project_ids, &downloads, logger);
+    }
+}
+
+/// Export the issues, merge requests, etc. for each downloaded repository.
+fn export_metadata(
+    cfg: &GitLabConfig,
+    project_ids: &HashMap<PathBuf, u64>,
+    downloads: &DownloadsReceiver,
+    logger: &Logger,
+) {
+    let client = match api_client(cfg) {
+        Ok(c) => c,
+        Err(e) => {
+            warn!(logger, "Unable to create the GitLab API client"; "error" => e.to_string());
             return;
         }
+    };
+
+    for (repo, dir) in downloads.iter() {
+        let id = match project_ids.get(&repo.dest_dir) {
+            Some(id) => *id,
+            None => continue,
+        };
+        let base = format!("https://{}/api/v4/projects/{}", cfg.hostname, id);
+        let meta_dir = metadata::meta_dir(&dir);
+
+        debug!(logger, "Exporting GitLab metadata";
+            "project-id" => id,
+            "dest-dir" => meta_dir.display());
+
+        if let Err(e) = export_project(cfg, &client, &base, &meta_dir, logger) {
+            warn!(logger, "Unable to export the project's metadata";
+                "dest-dir" => repo.dest_dir.display(),
+                "error" => e.to_string());
+
+            for cause in e.iter_causes() {
+                warn!(logger, "Caused By"; "cause" => cause.to_string());
+            }
+        }
+    }
+
+    debug!(logger, "Finished exporting GitLab metadata");
+}
+
+fn api_client(cfg: &GitLabConfig) -> Result<JsonClient, Error> {
+    let mut headers = HeaderMap::new();
+    let token = HeaderValue::from_str(&cfg.api_key).context("Invalid GitLab API key")?;
+    headers.insert("PRIVATE-TOKEN", token);
+
+    JsonClient::new(headers)
+}
+
+/// Pushes repositories to GitLab, creating projects (and their groups) if
+/// they don't exist.
+#[derive(Debug, Clone)]
+pub(crate) struct GitLabDestination {
+    base: String,
+    client: JsonClient,
+}
+
+impl GitLabDestination {
+    pub fn from_config(cfg: &Config) -> Result<GitLabDestination, Error> {
+        let gl_config: GitLabConfig = cfg
+            .get_deserialized(GitLabConfig::KEY)
+            .context("Unable to read the [gitlab] section")?;
+
+        Ok(GitLabDestination {
+            base: format!("https://{}/api/v4", gl_config.hostname),
+            client: api_client(&gl_config)?,
+        })
+    }
+}
+
+impl GitLabDestination {
+    /// Get a namespace, creating it (and any parent groups) as a group if it
+    /// doesn't exist.
+    fn ensure_namespace(&self, path: &str, private: bool) -> Result<Value, Error> {
+        let url = format!("{}/namespaces/{}", self.base, encode_path(path));
+
+        if let Some(namespace) = self.client.get_optional(&url)? {
+            return Ok(namespace);
+        }
+
+        let (parent, name) = match path.rfind('/') {
+            Some(ix) => (Some(&path[..ix]), &path[ix + 1..]),
+            None => (None, path),
+        };
+        let visibility = if private { "private" } else { "public" };
+        let mut body = json!({ "name": name, "path": name, "visibility": visibility });
+
+        if let Some(parent) = parent {
+            body["parent_id"] = self.ensure_namespace(parent, private)?["id"].clone();
+        }
+
+        let group = self
+            .client
+            .post(&format!("{}/groups", self.base), &body)
+            .with_context(|_| format!("Unable to create the \"{}\" group", path))?;
+
+        Ok(group)
+    }
+}
+
+impl Destination for GitLabDestination {
+    fn ensure_project(&self, owner: &str, name: &str, private: bool) -> Result<String, Error> {
+        let full_path = format!("{}/{}", owner, name);
+        let url = format!("{}/projects/{}", self.base, encode_path(&full_path));
+
+        let project = match self.client.get_optional(&url)? {
+            Some(project) => project,
+            None => {
+                let namespace = self.ensure_namespace(owner, private)?;
+                let visibility = if private { "private" } else { "public" };
+
+                let body = json!({
+                    "name": name,
+                    "path": name,
+                    "namespace_id": namespace["id"],
+                    "visibility": visibility,
+                });
+                self.client
+                    .post(&format!("{}/projects", self.base), &body)
+                    .with_context(|_| format!("Unable to create {}", full_path))?
+            }
+        };
+
+        project["ssh_url_to_repo"]
+            .as_str()
+            .map(String::from)
+            .ok_or_else(|| failure::err_msg("GitLab didn't say where to push to"))
+    }
+}
+
+/// GitLab lets you use a URL-encoded path instead of a project or namespace
+/// ID.
+fn encode_path(path: &str) -> String {
+    path.replace('/', "%2F")
+}
+
+fn export_project(
+    cfg: &GitLabConfig,
+    client: &JsonClient,
+    base: &str,
+    meta_dir: &Path,
+    logger: &Logger,
+) -> Result<(), Error> {
+    if cfg.metadata {
+        export_project_metadata(client, base, meta_dir, logger)?;
+    }
+    if cfg.releases {
+        export_releases(cfg, client, base, meta_dir, logger)?;
+    }
+
+    Ok(())
+}
+
+fn export_project_metadata(
+    client: &JsonClient,
+    base: &str,
+    meta_dir: &Path,
+    logger: &Logger,
+) -> Result<(), Error> {
+    let mut since = Since::load(meta_dir)?;
+
+    let started = Utc::now();
+    let url = format!(
+        "{}/issues?scope=all&per_page=100{}",
+        base,
+        since.query("issues", "updated_after")
+    );
+    let issues = client.get_all(&url)?;
+    metadata::write_each(&meta_dir.join("issues"), &issues, "iid")?;
+    since.update("issues", started)?;
+
+    let started = Utc::now();
+    let url = format!(
+        "{}/merge_requests?scope=all&state=all&per_page=100{}",
+        base,
+        since.query("merge-requests", "updated_after")
+    );
+    let merge_requests = client.get_all(&url)?;
+    let mr_dir = meta_dir.join("merge-requests");
+    metadata::write_each(&mr_dir, &merge_requests, "iid")?;
+
+    for iid in merge_requests.iter().filter_map(|mr| mr["iid"].as_u64()) {
+        let discussions = client.get_all(&format!(
+            "{}/merge_requests/{}/discussions?per_page=100",
+            base, iid
+        ))?;
+        metadata::write_json(
+            &mr_dir.join(iid.to_string()).join("discussions.json"),
+            &discussions.into(),
+        )?;
+
+        // approvals aren't available on every GitLab edition
+        match client.get(&format!("{}/merge_requests/{}/approvals", base, iid)) {
+            Ok(approvals) => metadata::write_json(
+                &mr_dir.join(iid.to_string()).join("approvals.json"),
+                &approvals,
+            )?,
+            Err(e) => trace!(logger, "Unable to get merge request approvals";
+                "iid" => iid,
+                "error" => e.to_string()),
+        }
+    }
+    since.update("merge-requests", started)?;
+
+    // snippets can't be filtered by the API, so skip the ones which haven't
+    // changed ourselves
+    let started = Utc::now();
+    let last_export = since.get("snippets");
+    let snippets: Vec<_> = client
+        .get_all(&format!("{}/snippets?per_page=100", base))?
+        .into_iter()
+        .filter(|snippet| metadata::updated_since(snippet, last_export))
+        .collect();
+    let snippet_dir = meta_dir.join("snippets");
+    metadata::write_each(&snippet_dir, &snippets, "id")?;
+
+    for id in snippets.iter().filter_map(|s| s["id"].as_u64()) {
+        client.download(
+            &format!("{}/snippets/{}/raw", base, id),
+            &snippet_dir.join(format!("{}.raw", id)),
+        )?;
+    }
+    since.update("snippets", started)?;
+
+    let labels = client.get_all(&format!("{}/labels?per_page=100", base))?;
+    metadata::write_json(&meta_dir.join("labels.json"), &labels.into())?;
+
+    Ok(())
+}
+
+/// Save each release and download its asset links into `releases/<tag>/`,
+/// then do the same for each of the project's packages.
+fn export_releases(
+    cfg: &GitLabConfig,
+    client: &JsonClient,
+    base: &str,
+    meta_dir: &Path,
+    logger: &Logger,
+) -> Result<(), Error> {
+    let releases = client.get_all(&format!("{}/releases?per_page=100", base))?;
+    // links can point anywhere, and we don't want to send our API key to
+    // some random website
+    let instance = format!("https://{}/", cfg.hostname);
+
+    for release in &releases {
+        let tag = match release["tag_name"].as_str() {
+            Some(tag) => tag,
+            None => continue,
+        };
+        let dir = meta_dir.join("releases").join(metadata::safe_name(tag));
+        metadata::write_json(&dir.join("release.json"), release)?;
+
+        let (assets, elsewhere): (Vec<_>, Vec<_>) = release["assets"]["links"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|link| {
+                Some(Asset {
+                    name: link["name"].as_str()?.to_string(),
+                    url: link["url"].as_str()?.to_string(),
+                    size: None,
+                })
+            })
+            .partition(|asset| asset.url.starts_with(&instance));
+
+        for asset in &elsewhere {
+            info!(logger, "Skipping a release asset which isn't hosted by GitLab";
+                "tag" => tag,
+                "name" => &asset.name,
+                "url" => &asset.url);
+        }
+
+        metadata::download_assets(client, &assets, &dir)?;
+    }
+
+    // the package registry may be disabled for this project
+    let packages = match client.get_all(&format!("{}/packages?per_page=100", base)) {
+        Ok(packages) => packages,
+        Err(e) => {
+            trace!(logger, "Unable to get the project's packages";
+                "error" => e.to_string());
+            return Ok(());
+        }
+    };
+    metadata::write_json(&meta_dir.join("packages.json"), &packages.clone().into())?;
+
+    for package in &packages {
+        // one broken package shouldn't stop the rest from being saved
+        if let Err(e) = export_package(cfg, client, base, package, &meta_dir.join("packages")) {
+            warn!(logger, "Unable to download a package";
+                "name" => package["name"].as_str().unwrap_or_default(),
+                "version" => package["version"].as_str().unwrap_or_default(),
+                "error" => e.to_string());
+        }
+    }
+
+    Ok(())
+}
+
+/// Save a package and download its files into
+/// `packages/<name>-<version>/`.
+fn export_package(
+    cfg: &GitLabConfig,
+    client: &JsonClient,
+    base: &str,
+    package: &Value,
+    packages_dir: &Path,
+) -> Result<(), Error> {
+    let id = package["id"]
+        .as_u64()
+        .ok_or_else(|| failure::err_msg("GitLab didn't say what the package's id is"))?;
+    let name = format!(
+        "{}-{}",
+        package["name"].as_str().unwrap_or_default(),
+        package["version"].as_str().unwrap_or_default()
+    );
+    let dir = packages_dir.join(metadata::safe_name(&name));
+    metadata::write_json(&dir.join("package.json"), package)?;
+
+    let files = client.get_all(&format!(
+        "{}/packages/{}/package_files?per_page=100",
+        base, id
+    ))?;
+    let assets: Vec<_> = files
+        .iter()
+        .filter_map(|file| {
+            Some(Asset {
+                name: file["file_name"].as_str()?.to_string(),
+                url: package_file_url(cfg, base, package, file)?,
+                size: file["size"].as_u64(),
+            })
+        })
+        .collect();
+
+    metadata::download_assets(client, &assets, &dir)?;
+
+    Ok(())
+}
+
+/// Where a package file can be downloaded from. Generic packages have their
+/// own API, every other kind is downloaded the same way GitLab's web
+/// interface does it.
+fn package_file_url(
+    cfg: &GitLabConfig,
+    base: &str,
+    package: &Value,
+    file: &Value,
+) -> Option<String> {
+    let file_name = file["file_name"].as_str()?;
+
+    if package["package_type"].as_str() == Some("generic") {
+        return Some(format!(
+            "{}/packages/generic/{}/{}/{}",
+            base,
+            package["name"].as_str()?,
+            package["version"].as_str()?,
+            file_name
+        ));
     }
+
+    // e.g. "/group/project/-/packages/42"
+    let web_path = package["_links"]["web_path"].as_str()?;
+    let project = &web_path[..web_path.find("/-/packages")?];
+
+    Some(format!(
+        "https://{}{}/-/package_files/{}/download",
+        cfg.hostname,
+        project,
+        file["id"].as_u64()?
+    ))
+}
+
+/// The language making up the largest share of a project, as reported by
+/// GitLab's `/projects/:id/languages` endpoint (e.g. `{"Rust": 92.5}`).
+fn primary_language(languages: &Value) -> Option<String> {
+    languages
+        .as_object()?
+        .iter()
+        .filter_map(|(name, share)| Some((name, share.as_f64()?)))
+        .max_by(|left, right| left.1.partial_cmp(&right.1).unwrap_or(Ordering::Equal))
+        .map(|(name, _)| name.clone())
 }
 
 fn project_to_repo(project: gitlab::Project) -> GitRepo {
+    let metadata = Metadata {
+        fork: Some(project.forked_from_project.is_some()),
+        archived: Some(project.archived),
+        private: Some(project.visibility == gitlab::VisibilityLevel::Private),
+        size_kb: project
+            .statistics
+            .as_ref()
+            .map(|stats| stats.repository_size / 1024),
+        // filled in separately, when needed
+        language: None,
+        topics: Some(project.tag_list),
+        description: None,
+        stable_name: None,
+        last_push: Some(project.last_activity_at),
+    };
+
     GitRepo {
+        provider: GitLabConfig::KEY.to_string(),
         dest_dir: Path::new("gitlab.com")
             .join(project.namespace.path)
             .join(project.path),
         ssh_url: project.ssh_url_to_repo,
+        wiki: false,
+        metadata,
     }
 }
 
@@ -105,6 +589,49 @@ pub struct GitLabConfig {
     #[serde(default = "default_hostname")]
     pub hostname: String,
     pub api_key: String,
+    /// Should we export issues, merge requests, snippets and labels as JSON?
+    #[serde(default)]
+    pub metadata: bool,
+    /// Should we download each project's releases and their assets?
+    #[serde(default)]
+    pub releases: bool,
+    /// Should we also back up each project's wiki?
+    #[serde(default)]
+    pub wikis: bool,
+    #[serde(default)]
+    pub filter: FilterConfig,
+}
+
+impl GitLabConfig {
+    pub const KEY: &'static str = "gitlab";
+}
+
+impl Documented for GitLabConfig {
+    const DESCRIPTION: &'static str = "Back up repositories from GitLab.";
+    const FIELDS: Fields = &[
+        ("api-key", "A personal access token with the \"api\" scope."),
+        ("hostname", "The GitLab instance to use."),
+        (
+            "metadata",
+            "Export issues, merge requests (with their discussions and \
+             approvals), snippets and labels as JSON into a \"<repo>.meta/\" \
+             directory next to each repository. Reruns only fetch what changed.",
+        ),
+        (
+            "releases",
+            "Download each release and the files it links to into \
+             \"<repo>.meta/releases/<tag>/\", and each package's files into \
+             \"<repo>.meta/packages/<name>-<version>/\". Files which were \
+             already downloaded are skipped.",
+        ),
+        (
+            "wikis",
+            "Also back up the wiki of every project which has one enabled, \
+             saving it to a \"<repo>.wiki\" directory next to the repository.",
+        ),
+        ("filter", FilterConfig::DESCRIPTION),
+    ];
+    const NESTED: &'static [(&'static str, Fields)] = &[("filter", FilterConfig::FIELDS)];
 }
 
 fn default_hostname() -> String {
@@ -116,6 +643,216 @@ impl Default for GitLabConfig {
         GitLabConfig {
             hostname: default_hostname(),
             api_key: String::new(),
+            metadata: false,
+            releases: false,
+            wikis: false,
+            filter: FilterConfig::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use slog::Discard;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+
+    /// Pretend to be a GitLab instance, answering each request with the body
+    /// registered for its path (ignoring the query string) or a 404. Returns
+    /// the base URL and every URL which was requested.
+    fn serve(routes: Vec<(&'static str, &'static str)>) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!(
+            "http://{}/api/v4/projects/1",
+            listener.local_addr().unwrap()
+        );
+        let requested = Arc::new(Mutex::new(Vec::new()));
+        let log = Arc::clone(&requested);
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(&stream);
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+
+                // skip the headers
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 2 {
+                    line.clear();
+                }
+
+                let url = request_line.split(' ').nth(1).unwrap_or("").to_string();
+                let path = url.split('?').next().unwrap().to_string();
+                let response = match routes.iter().find(|route| route.0 == path) {
+                    Some((_, body)) => format!(
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        body.len(),
+                        body
+                    ),
+                    None => String::from(
+                        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    ),
+                };
+
+                log.lock().unwrap().push(url);
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+
+        (base, requested)
+    }
+
+    fn project() -> Vec<(&'static str, &'static str)> {
+        vec![
+            (
+                "/api/v4/projects/1/issues",
+                r#"[{"iid": 1, "updated_at": "2019-01-01T00:00:00Z"}]"#,
+            ),
+            (
+                "/api/v4/projects/1/merge_requests",
+                r#"[{"iid": 2, "updated_at": "2019-01-01T00:00:00Z"}]"#,
+            ),
+            ("/api/v4/projects/1/merge_requests/2/discussions", "[]"),
+            (
+                "/api/v4/projects/1/snippets",
+                r#"[{"id": 3, "updated_at": "2019-01-01T00:00:00Z"}]"#,
+            ),
+            ("/api/v4/projects/1/snippets/3/raw", "echo 'Hello, World!'"),
+            ("/api/v4/projects/1/labels", r#"[{"name": "bug"}]"#),
+        ]
+    }
+
+    fn export(base: &str, meta_dir: &Path) {
+        let cfg = GitLabConfig {
+            metadata: true,
+            ..Default::default()
+        };
+        let client = JsonClient::new(HeaderMap::new()).unwrap();
+        let logger = Logger::root(Discard, o!());
+
+        export_project(&cfg, &client, base, meta_dir, &logger).unwrap();
+    }
+
+    #[test]
+    fn export_everything_about_a_project() {
+        let temp = tempfile::tempdir().unwrap();
+        let (base, _) = serve(project());
+
+        export(&base, temp.path());
+
+        let expected = [
+            "issues/1.json",
+            "merge-requests/2.json",
+            "merge-requests/2/discussions.json",
+            "snippets/3.json",
+            "snippets/3.raw",
+            "labels.json",
+        ];
+        for file in &expected {
+            assert!(temp.path().join(file).exists(), "{} is missing", file);
         }
+        // approvals are optional
+        assert!(!temp.path().join("merge-requests/2/approvals.json").exists());
+    }
+
+    #[test]
+    fn only_fetch_what_changed_since_the_last_export() {
+        let temp = tempfile::tempdir().unwrap();
+        let (base, requested) = serve(project());
+        export(&base, temp.path());
+        requested.lock().unwrap().clear();
+
+        export(&base, temp.path());
+
+        let requested = requested.lock().unwrap();
+        let issues = requested
+            .iter()
+            .find(|url| url.contains("/issues?"))
+            .unwrap();
+        assert!(issues.contains("updated_after="), "{}", issues);
+        let merge_requests = requested
+            .iter()
+            .find(|url| url.contains("/merge_requests?"))
+            .unwrap();
+        assert!(merge_requests.contains("updated_after="));
+        // the snippet hasn't changed, so don't download it again
+        assert!(!requested.iter().any(|url| url.contains("/snippets/3")));
+    }
+
+    #[test]
+    fn download_each_package_file() {
+        let temp = tempfile::tempdir().unwrap();
+        let (base, _) = serve(vec![
+            ("/api/v4/projects/1/releases", "[]"),
+            (
+                "/api/v4/projects/1/packages",
+                r#"[{"id": 4, "name": "tool", "version": "1.0", "package_type": "generic"}]"#,
+            ),
+            (
+                "/api/v4/projects/1/packages/4/package_files",
+                r#"[{"id": 5, "file_name": "tool.tar.gz", "size": 11}]"#,
+            ),
+            (
+                "/api/v4/projects/1/packages/generic/tool/1.0/tool.tar.gz",
+                "hello world",
+            ),
+        ]);
+        let cfg = GitLabConfig {
+            releases: true,
+            ..Default::default()
+        };
+        let client = JsonClient::new(HeaderMap::new()).unwrap();
+
+        export_project(
+            &cfg,
+            &client,
+            &base,
+            temp.path(),
+            &Logger::root(Discard, o!()),
+        )
+        .unwrap();
+
+        let package = temp.path().join("packages/tool-1.0");
+        assert!(package.join("package.json").exists());
+        assert_eq!(
+            std::fs::read_to_string(package.join("tool.tar.gz")).unwrap(),
+            "hello world"
+        );
+    }
+
+    #[test]
+    fn other_packages_are_downloaded_like_the_web_interface_does() {
+        let cfg = GitLabConfig {
+            hostname: String::from("gitlab.com"),
+            ..Default::default()
+        };
+        let package = json!({
+            "package_type": "npm",
+            "_links": { "web_path": "/group/project/-/packages/4" },
+        });
+        let file = json!({ "id": 5, "file_name": "tool-1.0.tgz" });
+
+        let got = package_file_url(
+            &cfg,
+            "https://gitlab.com/api/v4/projects/1",
+            &package,
+            &file,
+        );
+
+        assert_eq!(
+            got.unwrap(),
+            "https://gitlab.com/group/project/-/package_files/5/download"
+        );
+    }
+
+    #[test]
+    fn the_primary_language_has_the_largest_share() {
+        let languages = json!({ "Shell": 2.5, "Rust": 92.5, "Python": 5.0 });
+
+        assert_eq!(primary_language(&languages).unwrap(), "Rust");
+        assert_eq!(primary_language(&json!({})), None);
     }
 }
@@ -1,13 +1,122 @@
+use crate::config::{Config, Section};
 use crate::git::GitRepo;
 use failure::Error;
+use futures::future::{self, Future};
+use futures::sync::oneshot;
 use futures::Stream;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+mod gists;
+mod gitea;
 mod github;
 mod gitlab;
 
+pub use self::gists::Gists;
+pub use self::gitea::GiteaConfig;
 pub use self::github::{GitHub, GitHubConfig};
 pub use self::gitlab::{GitLab, GitLabConfig};
 
 /// Something which can retrieve the repositories we want to backup.
 pub trait Provider {
-    fn repositories(&self) -> Box<dyn Stream<Item = GitRepo, Error = Error>>;
+    fn repositories(&mut self) -> Box<dyn Stream<Item = GitRepo, Error = Error>>;
+
+    /// Let the provider know one of its repositories was successfully
+    /// downloaded to `dir`, so it can back up anything else associated with
+    /// the repository (issues, releases, etc.).
+    fn downloaded(&mut self, _repo: &GitRepo, _dir: &Path) {}
+
+    /// Wait for any outstanding work to be completed.
+    fn finish(&mut self) -> Box<dyn Future<Item = (), Error = Error>> {
+        Box::new(future::ok(()))
+    }
+}
+
+/// Somewhere backed up repositories can be pushed to.
+pub trait Destination: Send + Sync {
+    /// Make sure the project `owner/name` exists (creating it if necessary)
+    /// and return the URL to push to.
+    fn ensure_project(&self, owner: &str, name: &str, private: bool) -> Result<String, Error>;
+}
+
+/// Get the `Destination` for the provider with the config section `key`.
+pub(crate) fn destination(cfg: &Config, key: &str) -> Result<Box<dyn Destination>, Error> {
+    match key {
+        GitHubConfig::KEY => Ok(Box::new(github::GitHubDestination::from_config(cfg)?)),
+        GitLabConfig::KEY => Ok(Box::new(gitlab::GitLabDestination::from_config(cfg)?)),
+        GiteaConfig::KEY => Ok(Box::new(gitea::GiteaDestination::from_config(cfg)?)),
+        other => Err(failure::format_err!(
+            "Don't know how to create projects on \"{}\"",
+            other
+        )),
+    }
+}
+
+/// The config sections used by each of the known `Provider`s (and
+/// `Destination`s).
+pub(crate) fn sections() -> Vec<Section> {
+    vec![
+        Section::new::<GitHubConfig>(GitHubConfig::KEY),
+        Section::new::<GitLabConfig>(GitLabConfig::KEY),
+        Section::new::<GiteaConfig>(GiteaConfig::KEY),
+    ]
+}
+
+/// Create a channel for passing downloaded repositories to a `Provider`'s
+/// background thread.
+pub(crate) fn downloads() -> (Downloads, DownloadsReceiver) {
+    let (tx, rx) = mpsc::channel();
+    let (finished_tx, finished_rx) = oneshot::channel();
+
+    let downloads = Downloads {
+        tx: Some(tx),
+        finished: Some(finished_rx),
+    };
+    let receiver = DownloadsReceiver {
+        rx,
+        _finished: finished_tx,
+    };
+
+    (downloads, receiver)
+}
+
+/// The sending half of a `downloads()` channel.
+#[derive(Debug, Default)]
+pub(crate) struct Downloads {
+    tx: Option<mpsc::Sender<(GitRepo, PathBuf)>>,
+    finished: Option<oneshot::Receiver<()>>,
+}
+
+impl Downloads {
+    pub fn send(&self, repo: &GitRepo, dir: &Path) {
+        if let Some(ref tx) = self.tx {
+            let _ = tx.send((repo.clone(), dir.to_path_buf()));
+        }
+    }
+
+    /// Close the channel and wait for the background thread to finish.
+    pub fn finish(&mut self) -> Box<dyn Future<Item = (), Error = Error>> {
+        self.tx = None;
+
+        match self.finished.take() {
+            // the background thread drops its oneshot::Sender when it exits
+            Some(finished) => Box::new(finished.then(|_| Ok(()))),
+            None => Box::new(future::ok(())),
+        }
+    }
+}
+
+/// The receiving half of a `downloads()` channel, to be moved to the
+/// background thread.
+#[derive(Debug)]
+pub(crate) struct DownloadsReceiver {
+    rx: mpsc::Receiver<(GitRepo, PathBuf)>,
+    _finished: oneshot::Sender<()>,
+}
+
+impl DownloadsReceiver {
+    /// Iterate over each downloaded repository and the directory it was saved
+    /// to, until the channel is closed.
+    pub fn iter(&self) -> impl Iterator<Item = (GitRepo, PathBuf)> + '_ {
+        self.rx.iter()
+    }
 }
@@ -0,0 +1,309 @@
+//! Pushing backed up repositories to a new remote, optionally creating the
+//! project through a provider's API first.
+//!
+//! Only branches (as they were upstream), tags and any Git LFS objects which
+//! were downloaded are pushed.
+
+use crate::config::Config;
+use crate::filter::{self, Pattern};
+use crate::git;
+use crate::providers::{self, Destination};
+use crate::state::RepoState;
+use failure::{Error, ResultExt};
+use slog::Logger;
+use std::path::{Path, PathBuf};
+
+/// What to restore, and where to.
+#[derive(Debug, Clone, Default)]
+pub struct RestoreOptions {
+    /// Only restore repositories whose path (relative to the backup root)
+    /// matches one of these. Everything is restored when this is empty.
+    pub repos: Vec<Pattern>,
+    /// Where to push each repository, with `{path}`, `{host}`, `{owner}` and
+    /// `{name}` placeholders (e.g. `git@example.com:{owner}/{name}.git`).
+    pub remote: Option<String>,
+    /// The provider (e.g. `"github"`) to create missing projects on.
+    pub create: Option<String>,
+    /// Push to this owner (user, organisation or group) instead of the
+    /// repository's original one.
+    pub owner: Option<String>,
+    /// Should newly created projects be private?
+    pub private: bool,
+    /// Delete branches and tags which only exist on the remote.
+    pub prune: bool,
+    /// Figure out where everything would go without pushing anything.
+    pub dry_run: bool,
+}
+
+/// A repository which was restored (or would have been, for a dry run).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Restored {
+    /// The repository's path relative to the backup root.
+    pub repo: PathBuf,
+    pub url: String,
+}
+
+/// Restore every backed up repository matching `opts.repos`.
+pub fn restore(
+    cfg: &Config,
+    opts: &RestoreOptions,
+    logger: &Logger,
+) -> Result<Vec<Restored>, Error> {
+    if opts.remote.is_none() && opts.create.is_none() {
+        return Err(failure::err_msg(
+            "Restoring needs a remote to push to or a provider to create projects on",
+        ));
+    }
+
+    let destination = match opts.create {
+        Some(ref key) => Some(providers::destination(cfg, key)?),
+        None => None,
+    };
+
+    let root = &cfg.general.root;
+    let mut repos = Vec::new();
+
+    for repo in git::find_repositories(root)? {
+        if !filter::matches_any(&opts.repos, &repo) {
+            continue;
+        }
+
+        // forges keep wikis alongside the project, not as their own project
+        if RepoState::load(root, &repo)?.wiki {
+            debug!(logger, "Skipping a wiki"; "repo" => repo.display());
+            continue;
+        }
+
+        repos.push(repo);
+    }
+
+    if repos.is_empty() {
+        return Err(failure::err_msg("No backed up repositories were selected"));
+    }
+
+    let total = repos.len();
+    let mut restored = Vec::new();
+
+    for repo in repos {
+        debug!(logger, "Restoring a repository"; "repo" => repo.display());
+
+        match restore_one(root, &repo, opts, destination.as_deref()) {
+            Ok(url) => {
+                info!(logger, "Restored a repository";
+                    "repo" => repo.display(),
+                    "url" => &url,
+                    "dry-run" => opts.dry_run);
+                restored.push(Restored { repo, url });
+            }
+            Err(e) => {
+                warn!(logger, "Unable to restore a repository";
+                    "repo" => repo.display(),
+                    "error" => e.to_string());
+
+                for cause in e.iter_causes() {
+                    warn!(logger, "Caused By"; "cause" => cause.to_string());
+                }
+            }
+        }
+    }
+
+    if restored.len() < total {
+        return Err(failure::format_err!(
+            "Unable to restore {} of {} repositories",
+            total - restored.len(),
+            total
+        ));
+    }
+
+    Ok(restored)
+}
+
+/// Push a single repository, returning the URL it was pushed to.
+pub(crate) fn restore_one(
+    root: &Path,
+    repo: &Path,
+    opts: &RestoreOptions,
+    destination: Option<&dyn Destination>,
+) -> Result<String, Error> {
+    let target = Target::new(repo, opts.owner.as_deref())?;
+
+    let created = match destination {
+        Some(dest) if !opts.dry_run => Some(
+            dest.ensure_project(&target.owner, &target.name, opts.private)
+                .context("Unable to create the destination project")?,
+        ),
+        _ => None,
+    };
+
+    let url = match (&opts.remote, created) {
+        (Some(template), _) => target.expand(template),
+        (None, Some(url)) => url,
+        // a dry run which would have created the project
+        (None, None) => format!(
+            "{}:{}/{}",
+            opts.create.as_deref().unwrap_or_default(),
+            target.owner,
+            target.name
+        ),
+    };
+
+    let repo_dir = root.join(repo);
+
+    if opts.dry_run || git::refs(&repo_dir)?.is_empty() {
+        return Ok(url);
+    }
+
+    git::push_mirror(&repo_dir, &url, opts.prune)?;
+
+    if git::uses_lfs(&repo_dir) {
+        git::push_lfs(&repo_dir, &url)?;
+    }
+
+    Ok(url)
+}
+
+/// The parts of a repository's path which can be used in a remote's URL.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Target {
+    path: String,
+    host: String,
+    owner: String,
+    name: String,
+}
+
+impl Target {
+    /// Figure out where a repository goes, optionally moving it to another
+    /// owner.
+    ///
+    /// Repositories moved to another owner keep their original owner in
+    /// their name (e.g. `alice/dotfiles` becomes `backups/alice-dotfiles`) so
+    /// repositories with the same name from different owners don't collide.
+    pub(crate) fn new(repo: &Path, owner: Option<&str>) -> Result<Target, Error> {
+        let path = filter::normalized(repo);
+        let parts: Vec<&str> = path.split('/').collect();
+
+        if parts.len() < 3 {
+            return Err(failure::format_err!(
+                "\"{}\" doesn't look like \"<host>/<owner>/<name>\"",
+                path
+            ));
+        }
+
+        let original_owner = &parts[1..parts.len() - 1];
+        let name = parts[parts.len() - 1];
+
+        let (owner, name) = match owner {
+            Some(owner) => (
+                owner.to_string(),
+                format!("{}-{}", original_owner.join("-"), name),
+            ),
+            None => (original_owner.join("/"), name.to_string()),
+        };
+
+        Ok(Target {
+            host: parts[0].to_string(),
+            name,
+            owner,
+            path: path.clone(),
+        })
+    }
+
+    /// The `<owner>/<name>` this repository is pushed to.
+    pub(crate) fn full_name(&self) -> String {
+        format!("{}/{}", self.owner, self.name)
+    }
+
+    fn expand(&self, template: &str) -> String {
+        template
+            .replace("{path}", &self.path)
+            .replace("{host}", &self.host)
+            .replace("{owner}", &self.owner)
+            .replace("{name}", &self.name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use slog::Discard;
+    use std::process::Command;
+
+    #[test]
+    fn fill_in_the_remote_template() {
+        let target = Target::new(Path::new("gitlab.com/group/sub/project"), None).unwrap();
+
+        assert_eq!(
+            target.expand("git@example.com:{owner}/{name}.git"),
+            "git@example.com:group/sub/project.git"
+        );
+        assert_eq!(
+            target.expand("/mnt/{path}"),
+            "/mnt/gitlab.com/group/sub/project"
+        );
+
+        let moved = Target::new(Path::new("github.com/foo/bar"), Some("backups")).unwrap();
+        assert_eq!(
+            moved.expand("{host}/{owner}/{name}"),
+            "github.com/backups/foo-bar"
+        );
+        assert_eq!(moved.full_name(), "backups/foo-bar");
+        let nested = Target::new(Path::new("gitlab.com/group/sub/project"), Some("backups"));
+        assert_eq!(nested.unwrap().full_name(), "backups/group-sub-project");
+
+        assert!(Target::new(Path::new("github.com/foo"), None).is_err());
+    }
+
+    #[test]
+    fn push_every_branch_and_tag_to_the_new_remote() {
+        require_program!("git");
+
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path().join("root");
+        let repo = Path::new("github.com/foo/bar");
+        git::do_clone(&root.join(repo), env!("CARGO_MANIFEST_DIR"), None).unwrap();
+        // wikis aren't projects of their own, so they shouldn't be restored
+        let wiki = Path::new("github.com/foo/bar.wiki");
+        git::do_clone(&root.join(wiki), env!("CARGO_MANIFEST_DIR"), None).unwrap();
+        let state = RepoState {
+            wiki: true,
+            ..Default::default()
+        };
+        state.save(&root, wiki).unwrap();
+        let remote = temp.path().join("remote").join("bar.git");
+        std::fs::create_dir_all(&remote).unwrap();
+        git::init_bare(&remote).unwrap();
+
+        let mut cfg = Config::default();
+        cfg.general.root = root.clone();
+        let opts = RestoreOptions {
+            remote: Some(format!("{}/remote/{{name}}.git", temp.path().display())),
+            ..Default::default()
+        };
+
+        let got = restore(&cfg, &opts, &Logger::root(Discard, o!())).unwrap();
+
+        assert_eq!(got.len(), 1);
+        assert_eq!(got[0].repo, repo);
+        let branches = git::ref_names(&remote, "refs/heads/").unwrap();
+        let upstream = git::ref_names(&root.join(repo), "refs/remotes/origin/").unwrap();
+        assert!(!branches.is_empty());
+        assert_eq!(branches.len(), upstream.len() - 1);
+
+        // branches which were deleted upstream get deleted when pruning
+        let status = Command::new("git")
+            .args(&["update-ref", "refs/heads/deleted-upstream"])
+            .arg(&branches[0])
+            .current_dir(&remote)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let opts = RestoreOptions {
+            prune: true,
+            ..opts
+        };
+
+        restore(&cfg, &opts, &Logger::root(Discard, o!())).unwrap();
+
+        assert_eq!(git::ref_names(&remote, "refs/heads/").unwrap(), branches);
+    }
+}
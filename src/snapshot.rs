@@ -0,0 +1,344 @@
+//! Dated snapshots of each repository (saved as git bundles), pruned using a
+//! daily/weekly/monthly retention policy.
+//!
+//! The working copies are overwritten every time a backup runs, so without
+//! snapshots a bad upstream state (e.g. a force-push) ends up replacing our
+//! only copy.
+
+use crate::bundle;
+use crate::git;
+use chrono::{Datelike, NaiveDate};
+use failure::{Error, ResultExt};
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const DATE_FORMAT: &str = "%Y-%m-%d";
+const EXTENSION: &str = "bundle";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetentionConfig {
+    /// Should a snapshot of each repository be taken every day?
+    pub enabled: bool,
+    /// Where snapshots are saved, relative to the backup root.
+    pub dir: PathBuf,
+    /// How many daily snapshots to keep.
+    pub daily: usize,
+    /// How many weekly snapshots to keep.
+    pub weekly: usize,
+    /// How many monthly snapshots to keep.
+    pub monthly: usize,
+}
+
+impl RetentionConfig {
+    pub const DESCRIPTION: &'static str =
+        "Keep dated snapshots (as git bundles) of each repository, pruning old ones.";
+    pub const FIELDS: &'static [(&'static str, &'static str)] = &[
+        (
+            "enabled",
+            "Should a snapshot of each repository be taken (at most once a day)?",
+        ),
+        (
+            "dir",
+            "Where snapshots are saved (relative to the root), as \
+             \"<dir>/<repo>/<date>.bundle\".",
+        ),
+        (
+            "daily",
+            "How many of the most recent daily snapshots to keep.",
+        ),
+        (
+            "weekly",
+            "How many weekly snapshots to keep, using the newest snapshot from each week.",
+        ),
+        (
+            "monthly",
+            "How many monthly snapshots to keep, using the newest snapshot from each month.",
+        ),
+    ];
+}
+
+impl Default for RetentionConfig {
+    fn default() -> RetentionConfig {
+        RetentionConfig {
+            enabled: false,
+            dir: PathBuf::from("snapshots"),
+            daily: 7,
+            weekly: 8,
+            monthly: 12,
+        }
+    }
+}
+
+/// A snapshot of a repository.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    /// The repository's path relative to the backup root.
+    pub repo: PathBuf,
+    pub date: NaiveDate,
+    pub path: PathBuf,
+    /// The snapshot's size in bytes.
+    pub size: u64,
+}
+
+fn snapshot_dir(cfg: &RetentionConfig, root: &Path, dest_dir: &Path) -> PathBuf {
+    root.join(&cfg.dir).join(dest_dir)
+}
+
+/// Take today's snapshot of the repository at `dest_dir` (if there isn't one
+/// already) and prune any snapshots which are no longer needed. Returns the
+/// new snapshot, if one was taken.
+pub(crate) fn take(
+    cfg: &RetentionConfig,
+    root: &Path,
+    dest_dir: &Path,
+    today: NaiveDate,
+) -> Result<Option<PathBuf>, Error> {
+    let dir = bundle::absolute(&snapshot_dir(cfg, root, dest_dir))?;
+    let snapshot = dir.join(format!("{}.{}", today.format(DATE_FORMAT), EXTENSION));
+    let repo_dir = root.join(dest_dir);
+
+    let existing = repo_snapshots(&dir)?;
+    let refs = git::refs(&repo_dir)?;
+
+    if snapshot.exists() || refs.is_empty() {
+        return Ok(None);
+    }
+
+    fs::create_dir_all(&dir).with_context(|_| format!("Unable to create \"{}\"", dir.display()))?;
+
+    match existing.last() {
+        // nothing changed, so share the previous snapshot's data
+        Some((_, latest)) if git::bundle_refs(&repo_dir, latest)? == refs => {
+            link_or_copy(latest, &snapshot)?
+        }
+        _ => {
+            let mut partial = snapshot.clone().into_os_string();
+            partial.push(".part");
+            let partial = PathBuf::from(partial);
+
+            git::create_bundle(&repo_dir, &partial)?;
+            if let Err(e) = git::verify_bundle(&repo_dir, &partial) {
+                let _ = fs::remove_file(&partial);
+                return Err(e);
+            }
+            fs::rename(&partial, &snapshot)
+                .with_context(|_| format!("Unable to save \"{}\"", snapshot.display()))?;
+        }
+    }
+
+    prune(cfg, &dir)?;
+
+    Ok(Some(snapshot))
+}
+
+fn link_or_copy(src: &Path, dest: &Path) -> Result<(), Error> {
+    if fs::hard_link(src, dest).is_err() {
+        fs::copy(src, dest).with_context(|_| {
+            format!(
+                "Unable to copy \"{}\" to \"{}\"",
+                src.display(),
+                dest.display()
+            )
+        })?;
+    }
+
+    Ok(())
+}
+
+/// Delete the snapshots in `dir` which the retention policy doesn't want to
+/// keep.
+fn prune(cfg: &RetentionConfig, dir: &Path) -> Result<(), Error> {
+    let snapshots = repo_snapshots(dir)?;
+    let dates: Vec<_> = snapshots.iter().map(|(date, _)| *date).collect();
+    let keep = to_keep(cfg, &dates);
+
+    for (date, path) in snapshots {
+        if !keep.contains(&date) {
+            fs::remove_file(&path)
+                .with_context(|_| format!("Unable to remove \"{}\"", path.display()))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Figure out which snapshots to keep. Going from newest to oldest, the first
+/// snapshot from each day, week and month is kept until we have enough of
+/// each.
+pub(crate) fn to_keep(cfg: &RetentionConfig, dates: &[NaiveDate]) -> BTreeSet<NaiveDate> {
+    let mut newest_first = dates.to_vec();
+    newest_first.sort();
+    newest_first.reverse();
+
+    let mut keep = BTreeSet::new();
+
+    let periods: [(usize, fn(&NaiveDate) -> (i32, u32)); 3] = [
+        (cfg.daily, |d| (d.year(), d.ordinal())),
+        (cfg.weekly, |d| (d.iso_week().year(), d.iso_week().week())),
+        (cfg.monthly, |d| (d.year(), d.month())),
+    ];
+
+    for &(limit, period) in &periods {
+        let mut seen = BTreeSet::new();
+
+        for date in &newest_first {
+            if seen.len() >= limit {
+                break;
+            }
+            if seen.insert(period(date)) {
+                keep.insert(*date);
+            }
+        }
+    }
+
+    keep
+}
+
+/// Find the snapshots in a repository's snapshot directory, oldest first.
+fn repo_snapshots(dir: &Path) -> Result<Vec<(NaiveDate, PathBuf)>, Error> {
+    let mut snapshots = Vec::new();
+
+    if !dir.is_dir() {
+        return Ok(snapshots);
+    }
+
+    for entry in
+        fs::read_dir(dir).with_context(|_| format!("Unable to read \"{}\"", dir.display()))?
+    {
+        let path = entry?.path();
+        if let Some(date) = snapshot_date(&path) {
+            snapshots.push((date, path));
+        }
+    }
+
+    snapshots.sort();
+    Ok(snapshots)
+}
+
+fn snapshot_date(path: &Path) -> Option<NaiveDate> {
+    if path.extension()? != EXTENSION || !path.is_file() {
+        return None;
+    }
+
+    let stem = path.file_stem()?.to_str()?;
+    NaiveDate::parse_from_str(stem, DATE_FORMAT).ok()
+}
+
+/// List every snapshot, sorted by repository and then date.
+pub fn list(cfg: &RetentionConfig, root: &Path) -> Result<Vec<Snapshot>, Error> {
+    let base = root.join(&cfg.dir);
+    let mut snapshots = Vec::new();
+
+    if !base.is_dir() {
+        return Ok(snapshots);
+    }
+
+    let mut pending = vec![base.clone()];
+
+    while let Some(dir) = pending.pop() {
+        for (date, path) in repo_snapshots(&dir)? {
+            let size = fs::metadata(&path)?.len();
+            let repo = dir.strip_prefix(&base).unwrap_or(&dir).to_path_buf();
+            snapshots.push(Snapshot {
+                repo,
+                date,
+                path,
+                size,
+            });
+        }
+
+        for entry in
+            fs::read_dir(&dir).with_context(|_| format!("Unable to read \"{}\"", dir.display()))?
+        {
+            let path = entry?.path();
+            if path.is_dir() {
+                pending.push(path);
+            }
+        }
+    }
+
+    snapshots.sort_by(|left, right| (&left.repo, left.date).cmp(&(&right.repo, right.date)));
+    Ok(snapshots)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(s: &str) -> NaiveDate {
+        NaiveDate::parse_from_str(s, DATE_FORMAT).unwrap()
+    }
+
+    #[test]
+    fn keep_the_most_recent_daily_snapshots() {
+        let cfg = RetentionConfig {
+            daily: 3,
+            weekly: 0,
+            monthly: 0,
+            ..Default::default()
+        };
+        let dates = vec![
+            date("2019-05-01"),
+            date("2019-05-02"),
+            date("2019-05-03"),
+            date("2019-05-04"),
+        ];
+
+        let got = to_keep(&cfg, &dates);
+
+        let should_be: BTreeSet<_> = dates[1..].iter().cloned().collect();
+        assert_eq!(got, should_be);
+    }
+
+    #[test]
+    fn keep_the_newest_snapshot_from_each_week_and_month() {
+        let cfg = RetentionConfig {
+            daily: 1,
+            weekly: 2,
+            monthly: 2,
+            ..Default::default()
+        };
+        let dates = vec![
+            date("2019-03-30"),
+            date("2019-04-25"),
+            date("2019-04-26"),
+            date("2019-05-01"),
+            date("2019-05-08"),
+            date("2019-05-09"),
+        ];
+
+        let got = to_keep(&cfg, &dates);
+
+        let should_be: BTreeSet<_> = vec![
+            // daily, weekly and monthly
+            date("2019-05-09"),
+            // weekly
+            date("2019-05-01"),
+            // monthly
+            date("2019-04-26"),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(got, should_be);
+    }
+
+    #[test]
+    fn list_snapshots() {
+        let temp = tempfile::tempdir().unwrap();
+        let cfg = RetentionConfig::default();
+        let dir = temp.path().join("snapshots/github.com/foo/bar");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("2019-05-01.bundle"), "first").unwrap();
+        fs::write(dir.join("2019-05-02.bundle"), "second!").unwrap();
+        fs::write(dir.join("not-a-snapshot.txt"), "").unwrap();
+
+        let got = list(&cfg, temp.path()).unwrap();
+
+        assert_eq!(got.len(), 2);
+        assert_eq!(got[0].repo, Path::new("github.com/foo/bar"));
+        assert_eq!(got[0].date, date("2019-05-01"));
+        assert_eq!(got[1].size, 7);
+    }
+}
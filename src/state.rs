@@ -0,0 +1,191 @@
+//! Information about each repository which needs to be remembered between
+//! runs, saved as JSON under `<root>/.repo-backup/state/`.
+
+use crate::bundle::Refs;
+use chrono::{DateTime, Utc};
+use failure::{Error, ResultExt};
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+/// The directory (relative to the backup root) state is kept in.
+pub(crate) const STATE_DIR: &str = ".repo-backup/state";
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct RepoState {
+    /// The objects uploaded for this repository, keyed by the object's key.
+    pub uploads: BTreeMap<String, Upload>,
+    /// When the repository was last garbage collected or repacked.
+    pub last_maintenance: Option<DateTime<Utc>>,
+    /// The provider the repository was last backed up from.
+    pub provider: Option<String>,
+    /// The description the provider gave the repository, if any.
+    pub description: Option<String>,
+    /// Is this the wiki belonging to another repository?
+    pub wiki: bool,
+    /// When the repository was last backed up, successfully or not.
+    pub last_attempt: Option<DateTime<Utc>>,
+    /// When the repository was last backed up successfully.
+    pub last_success: Option<DateTime<Utc>>,
+    /// Why the last backup failed, if it did.
+    pub last_error: Option<String>,
+    /// When the repository started failing to back up.
+    pub failing_since: Option<DateTime<Utc>>,
+    /// Has a notification already mentioned the repository's current
+    /// failure?
+    pub failure_notified: bool,
+    /// How long the last successful fetch (or clone) took, in seconds.
+    pub fetch_seconds: Option<f64>,
+    /// How many bytes of objects the last successful fetch added.
+    pub fetch_bytes: Option<u64>,
+    /// The repository's refs when its encrypted archive was last created.
+    pub archived_refs: Refs,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Upload {
+    /// The ETag the server gave the object.
+    pub etag: String,
+    /// The MD5 hash of the file which was uploaded.
+    pub md5: String,
+}
+
+impl RepoState {
+    /// Load the state for the repository at `dest_dir`, starting fresh if
+    /// nothing has been saved yet.
+    pub fn load(root: &Path, dest_dir: &Path) -> Result<RepoState, Error> {
+        let path = state_path(root, dest_dir);
+
+        if !path.exists() {
+            return Ok(RepoState::default());
+        }
+
+        let f =
+            File::open(&path).with_context(|_| format!("Unable to open \"{}\"", path.display()))?;
+        let state = serde_json::from_reader(f)
+            .with_context(|_| format!("Unable to parse \"{}\"", path.display()))?;
+
+        Ok(state)
+    }
+
+    pub fn save(&self, root: &Path, dest_dir: &Path) -> Result<(), Error> {
+        let path = state_path(root, dest_dir);
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|_| format!("Unable to create \"{}\"", parent.display()))?;
+        }
+
+        // write to a temporary file first so a crash can't leave us with
+        // half a state file
+        let mut partial = path.clone().into_os_string();
+        partial.push(".part");
+        let partial = PathBuf::from(partial);
+
+        let f = File::create(&partial)
+            .with_context(|_| format!("Unable to create \"{}\"", partial.display()))?;
+        serde_json::to_writer_pretty(f, self)
+            .with_context(|_| format!("Unable to write to \"{}\"", partial.display()))?;
+        fs::rename(&partial, &path)
+            .with_context(|_| format!("Unable to save \"{}\"", path.display()))?;
+
+        Ok(())
+    }
+}
+
+/// Find every repository which has saved state, returning each repository's
+/// path relative to the backup root.
+pub(crate) fn saved(root: &Path) -> Result<Vec<PathBuf>, Error> {
+    let state_dir = root.join(STATE_DIR);
+    let mut repos = Vec::new();
+
+    if !state_dir.exists() {
+        return Ok(repos);
+    }
+
+    let mut pending = vec![state_dir.clone()];
+
+    while let Some(dir) = pending.pop() {
+        for entry in
+            fs::read_dir(&dir).with_context(|_| format!("Unable to read \"{}\"", dir.display()))?
+        {
+            let path = entry?.path();
+
+            if path.is_dir() {
+                pending.push(path);
+            } else if path.extension() == Some(OsStr::new("json")) {
+                let repo = path.with_extension("");
+                repos.push(repo.strip_prefix(&state_dir).unwrap_or(&repo).to_path_buf());
+            }
+        }
+    }
+
+    repos.sort();
+    Ok(repos)
+}
+
+/// Move the state saved for a repository whose backup was moved.
+pub(crate) fn rename(root: &Path, from: &Path, to: &Path) -> Result<(), Error> {
+    let from = state_path(root, from);
+
+    if !from.exists() {
+        return Ok(());
+    }
+
+    let to = state_path(root, to);
+
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|_| format!("Unable to create \"{}\"", parent.display()))?;
+    }
+
+    fs::rename(&from, &to).with_context(|_| format!("Unable to move \"{}\"", from.display()))?;
+
+    Ok(())
+}
+
+fn state_path(root: &Path, dest_dir: &Path) -> PathBuf {
+    let state = root.join(STATE_DIR).join(dest_dir);
+
+    let mut name = state
+        .file_name()
+        .map(|n| n.to_os_string())
+        .unwrap_or_default();
+    name.push(".json");
+
+    state.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn save_and_reload_state() {
+        let temp = tempfile::tempdir().unwrap();
+        let dest_dir = Path::new("github.com/foo/bar");
+        assert_eq!(
+            RepoState::load(temp.path(), dest_dir).unwrap(),
+            RepoState::default()
+        );
+
+        let mut state = RepoState::default();
+        let upload = Upload {
+            etag: String::from("abcd"),
+            md5: String::from("abcd"),
+        };
+        state
+            .uploads
+            .insert(String::from("bundles/bar.bundle"), upload);
+        state.save(temp.path(), dest_dir).unwrap();
+
+        assert!(temp
+            .path()
+            .join(".repo-backup/state/github.com/foo/bar.json")
+            .exists());
+        assert_eq!(RepoState::load(temp.path(), dest_dir).unwrap(), state);
+        assert_eq!(saved(temp.path()).unwrap(), vec![dest_dir.to_path_buf()]);
+    }
+}
@@ -0,0 +1,214 @@
+//! Answering "is this repository backed up?" from the backup root and the
+//! state saved after each backup, without digging through old logs.
+
+use crate::config::Config;
+use crate::git;
+use crate::state::{self, RepoState};
+use chrono::{DateTime, Duration, Utc};
+use failure::Error;
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// What we know about a single backed up repository.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RepoStatus {
+    /// The repository's path relative to the backup root.
+    pub repo: PathBuf,
+    pub provider: Option<String>,
+    /// When the repository was last backed up successfully.
+    pub last_success: Option<DateTime<Utc>>,
+    /// When the repository was last backed up, successfully or not.
+    pub last_attempt: Option<DateTime<Utc>>,
+    /// Why the last backup failed, if it did.
+    pub last_error: Option<String>,
+    /// How much space the repository takes up on disk, in bytes.
+    pub size: u64,
+}
+
+impl RepoStatus {
+    /// Did the most recent backup fail?
+    pub fn failed(&self) -> bool {
+        self.last_error.is_some()
+    }
+
+    /// How long it's been since the last successful backup.
+    pub fn age(&self, now: DateTime<Utc>) -> Option<Duration> {
+        self.last_success
+            .map(|when| now.signed_duration_since(when))
+    }
+}
+
+/// Which repositories to show.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StatusFilter {
+    /// Only show repositories whose last backup failed.
+    pub failed: bool,
+    /// Only show repositories which haven't been backed up successfully in
+    /// more than this many days.
+    pub stale_days: Option<u64>,
+    /// Only show repositories from this provider.
+    pub provider: Option<String>,
+}
+
+impl StatusFilter {
+    fn matches(&self, status: &RepoStatus, now: DateTime<Utc>) -> bool {
+        if self.failed && !status.failed() {
+            return false;
+        }
+
+        if let Some(days) = self.stale_days {
+            let threshold = Duration::days(days as i64);
+            // never being backed up is as stale as it gets
+            let stale = status.age(now).map(|age| age > threshold).unwrap_or(true);
+
+            if !stale {
+                return false;
+            }
+        }
+
+        match self.provider {
+            Some(ref provider) => status.provider.as_ref() == Some(provider),
+            None => true,
+        }
+    }
+}
+
+/// Get the status of every repository under `general.root` (or which has
+/// tried to be backed up there) matching the filter.
+pub fn status(
+    cfg: &Config,
+    filter: &StatusFilter,
+    now: DateTime<Utc>,
+) -> Result<Vec<RepoStatus>, Error> {
+    let root = &cfg.general.root;
+    // a repository which failed to clone only has state
+    let repos: BTreeSet<PathBuf> = git::find_repositories(root)?
+        .into_iter()
+        .chain(state::saved(root)?)
+        .collect();
+
+    let mut statuses = Vec::new();
+
+    for repo in repos {
+        let state = RepoState::load(root, &repo)?;
+        let status = RepoStatus {
+            size: disk_usage(&root.join(&repo)),
+            provider: state.provider,
+            last_success: state.last_success,
+            last_attempt: state.last_attempt,
+            last_error: state.last_error,
+            repo,
+        };
+
+        if filter.matches(&status, now) {
+            statuses.push(status);
+        }
+    }
+
+    Ok(statuses)
+}
+
+/// The total size of every file under a directory, skipping anything which
+/// can't be read.
+fn disk_usage(path: &Path) -> u64 {
+    let mut total = 0;
+    let mut pending = vec![path.to_path_buf()];
+
+    while let Some(dir) = pending.pop() {
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+
+        for entry in entries.filter_map(|e| e.ok()) {
+            match entry.metadata() {
+                Ok(ref meta) if meta.is_dir() => pending.push(entry.path()),
+                Ok(meta) => total += meta.len(),
+                Err(_) => {}
+            }
+        }
+    }
+
+    total
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nothing_to_report_before_the_first_backup() {
+        let temp = tempfile::tempdir().unwrap();
+        let mut cfg = Config::default();
+        cfg.general.root = temp.path().join("backups");
+
+        let got = status(&cfg, &StatusFilter::default(), Utc::now()).unwrap();
+
+        assert!(got.is_empty());
+    }
+
+    #[test]
+    fn filter_repositories_by_status() {
+        let temp = tempfile::tempdir().unwrap();
+        let now = Utc::now();
+        let mut cfg = Config::default();
+        cfg.general.root = temp.path().to_path_buf();
+
+        let healthy = Path::new("github.com/foo/healthy");
+        fs::create_dir_all(temp.path().join(healthy).join(".git")).unwrap();
+        fs::write(temp.path().join(healthy).join("README.md"), "Hello!").unwrap();
+        RepoState {
+            provider: Some(String::from("github")),
+            last_attempt: Some(now),
+            last_success: Some(now),
+            ..Default::default()
+        }
+        .save(temp.path(), healthy)
+        .unwrap();
+
+        // a repository which has never been cloned successfully
+        let broken = Path::new("gitlab.com/foo/broken");
+        RepoState {
+            provider: Some(String::from("gitlab")),
+            last_attempt: Some(now),
+            last_error: Some(String::from("Repository not found")),
+            ..Default::default()
+        }
+        .save(temp.path(), broken)
+        .unwrap();
+
+        // backed up before we started keeping track
+        let old = Path::new("github.com/foo/old");
+        fs::create_dir_all(temp.path().join(old).join(".git")).unwrap();
+
+        let got = status(&cfg, &StatusFilter::default(), now).unwrap();
+        let repos: Vec<_> = got.iter().map(|s| s.repo.as_path()).collect();
+        assert_eq!(repos, vec![healthy, old, broken]);
+        assert_eq!(got[0].size, 6);
+
+        let failed = StatusFilter {
+            failed: true,
+            ..Default::default()
+        };
+        let got = status(&cfg, &failed, now).unwrap();
+        assert_eq!(got.len(), 1);
+        assert_eq!(got[0].repo, broken);
+
+        let stale = StatusFilter {
+            stale_days: Some(7),
+            ..Default::default()
+        };
+        let got = status(&cfg, &stale, now + Duration::days(1)).unwrap();
+        let repos: Vec<_> = got.iter().map(|s| s.repo.as_path()).collect();
+        assert_eq!(repos, vec![old, broken]);
+
+        let github = StatusFilter {
+            provider: Some(String::from("github")),
+            ..Default::default()
+        };
+        let got = status(&cfg, &github, now).unwrap();
+        assert_eq!(got.len(), 1);
+        assert_eq!(got[0].repo, healthy);
+    }
+}
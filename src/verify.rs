@@ -0,0 +1,281 @@
+//! Checking the integrity of every backed up repository with `git fsck`
+//! (and `git lfs fsck`), so silently rotting disks get noticed before the
+//! backup is needed.
+
+use crate::config::Config;
+use crate::filter::{self, Pattern};
+use crate::git::{self, GitClone, VerifyRepo};
+use actix::{SyncArbiter, System};
+use failure::{Error, ResultExt};
+use futures::future::{self, Future};
+use slog::Logger;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// The problems found in a single repository.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Report {
+    /// The repository's path relative to the backup root.
+    pub repo: PathBuf,
+    /// Objects which are damaged, or which `git fsck` otherwise complained
+    /// about.
+    pub corrupt: Vec<String>,
+    /// Objects which are referenced but don't exist.
+    pub missing: Vec<String>,
+    /// Objects nothing refers to. These are harmless.
+    pub dangling: Vec<String>,
+    /// Objects which are intact but were written by a sloppy tool (e.g. a
+    /// commit with an invalid time zone). These are harmless too, and common
+    /// in old repositories.
+    pub warnings: Vec<String>,
+    /// Problems with Git LFS objects.
+    pub lfs: Vec<String>,
+    /// Why the repository couldn't be checked at all.
+    pub error: Option<String>,
+}
+
+impl Report {
+    /// Is the repository complete and undamaged?
+    pub fn is_healthy(&self) -> bool {
+        self.corrupt.is_empty()
+            && self.missing.is_empty()
+            && self.lfs.is_empty()
+            && self.error.is_none()
+    }
+}
+
+/// Check every backed up repository matching `repos` (or everything, if
+/// `repos` is empty) on the same thread pool used for downloading.
+pub fn verify(cfg: &Config, repos: &[Pattern], logger: &Logger) -> Result<Vec<Report>, Error> {
+    let root = &cfg.general.root;
+    let selected: Vec<_> = git::find_repositories(root)?
+        .into_iter()
+        .filter(|repo| filter::matches_any(repos, repo))
+        .collect();
+
+    info!(logger, "Verifying repositories";
+        "root" => root.display(),
+        "repo-count" => selected.len(),
+        "threads" => cfg.general.threads);
+
+    let mut sys = System::new("repo-backup-verify");
+
+    let general = cfg.general.clone();
+    let l2 = logger.clone();
+    let pool = SyncArbiter::start(cfg.general.threads, move || {
+        GitClone::new(general.clone(), None, l2.clone())
+    });
+
+    let pending = selected.into_iter().map(move |repo| {
+        pool.send(VerifyRepo(repo.clone()))
+            .map_err(|e| failure::format_err!("{}", e))
+            .and_then(|outcome| outcome)
+            .then(move |outcome| {
+                Ok::<_, Error>(outcome.unwrap_or_else(|e| Report {
+                    repo,
+                    error: Some(e.to_string()),
+                    ..Default::default()
+                }))
+            })
+    });
+
+    let reports = sys.block_on(future::join_all(pending))?;
+
+    for report in reports.iter().filter(|r| !r.is_healthy()) {
+        warn!(logger, "Found problems with a repository";
+            "repo" => report.repo.display(),
+            "corrupt" => report.corrupt.len(),
+            "missing" => report.missing.len(),
+            "lfs" => report.lfs.len(),
+            "error" => report.error.as_ref());
+    }
+
+    Ok(reports)
+}
+
+/// Check a single repository.
+pub(crate) fn check(root: &Path, dest_dir: &Path) -> Result<Report, Error> {
+    let repo_dir = root.join(dest_dir);
+    let mut report = Report {
+        repo: dest_dir.to_path_buf(),
+        ..Default::default()
+    };
+
+    let output = Command::new("git")
+        .args(&["fsck", "--full", "--no-progress"])
+        .current_dir(&repo_dir)
+        .output()
+        .context("Unable to execute the command")?;
+    let found = parse_fsck(&String::from_utf8_lossy(&output.stdout), &mut report)
+        + parse_fsck(&String::from_utf8_lossy(&output.stderr), &mut report);
+
+    if !output.status.success() && found == 0 {
+        report.error = Some(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+
+    if git::uses_lfs(&repo_dir) {
+        check_lfs(&repo_dir, &mut report);
+    }
+
+    Ok(report)
+}
+
+/// Problems `git fsck` reports as errors which don't mean anything was lost,
+/// only that an object was written by a sloppy tool.
+const HARMLESS: &[&str] = &[": badTimezone: ", ": zeroPaddedFilemode: "];
+
+/// Sort the lines `git fsck` printed into their categories, returning how
+/// many lines were sorted.
+fn parse_fsck(output: &str, report: &mut Report) -> usize {
+    let mut found = 0;
+
+    for line in output.lines().map(|l| l.trim()).filter(|l| !l.is_empty()) {
+        // "broken link from <object>" is followed by a "to <object>" line
+        if line.starts_with("to ") {
+            if let Some(broken) = report.corrupt.last_mut() {
+                if broken.starts_with("broken link from ") {
+                    broken.push(' ');
+                    broken.push_str(line);
+                    continue;
+                }
+            }
+        }
+
+        let category = if line.starts_with("dangling ") {
+            &mut report.dangling
+        } else if line.starts_with("warning")
+            || HARMLESS.iter().any(|harmless| line.contains(harmless))
+        {
+            &mut report.warnings
+        } else if line.starts_with("missing ") {
+            &mut report.missing
+        } else if line.starts_with("Checking ") || line.starts_with("notice:") {
+            // progress messages and other chatter
+            continue;
+        } else {
+            &mut report.corrupt
+        };
+
+        category.push(line.to_string());
+        found += 1;
+    }
+
+    found
+}
+
+fn check_lfs(repo_dir: &Path, report: &mut Report) {
+    let output = Command::new("git")
+        .args(&["lfs", "fsck"])
+        .current_dir(repo_dir)
+        .output();
+
+    match output {
+        Ok(ref output) if output.status.success() => {}
+        Ok(output) => {
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            let stderr = String::from_utf8_lossy(&output.stderr);
+
+            report.lfs.extend(
+                stdout
+                    .lines()
+                    .chain(stderr.lines())
+                    .map(|l| l.trim())
+                    .filter(|l| !l.is_empty())
+                    .map(String::from),
+            );
+        }
+        Err(e) => report.lfs.push(format!("Unable to run git-lfs: {}", e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn sort_fsck_output_into_categories() {
+        let output = "Checking object directories\n\
+                      dangling commit b74d7aefe61c6a679eb3b871a794c4810faef694\n\
+                      missing blob 45b983be36b73c0788dc9cbcb76cbb80fc7bb057\n\
+                      error: sha1 mismatch for .git/objects/12/34 (expected 1234)\n";
+        let mut report = Report::default();
+
+        let found = parse_fsck(output, &mut report);
+
+        assert_eq!(found, 3);
+        assert_eq!(report.dangling.len(), 1);
+        assert_eq!(report.missing.len(), 1);
+        assert_eq!(report.corrupt.len(), 1);
+        assert!(!report.is_healthy());
+    }
+
+    #[test]
+    fn warnings_and_broken_links() {
+        let output = "error in commit 0c064cc8e8a15d390cebd71339762b2a4c6bcc51: badTimezone: invalid author/committer line - bad time zone\n\
+                      warning in tree 2ab04b24f0d3c8a6d8d31f8357c4b5a3d4adb425: zeroPaddedFilemode: contains zero-padded file modes\n\
+                      notice: HEAD points to an unborn branch (master)\n\
+                      broken link from    tree 2ab04b24f0d3c8a6d8d31f8357c4b5a3d4adb425\n              to    blob 45b983be36b73c0788dc9cbcb76cbb80fc7bb057\n\
+                      missing blob 45b983be36b73c0788dc9cbcb76cbb80fc7bb057\n";
+        let mut report = Report::default();
+
+        let found = parse_fsck(output, &mut report);
+
+        assert_eq!(found, 4);
+        assert_eq!(report.warnings.len(), 2);
+        assert_eq!(
+            report.corrupt,
+            vec![String::from(
+                "broken link from    tree 2ab04b24f0d3c8a6d8d31f8357c4b5a3d4adb425 \
+                 to    blob 45b983be36b73c0788dc9cbcb76cbb80fc7bb057"
+            )]
+        );
+        assert_eq!(report.missing.len(), 1);
+
+        let mut warnings_only = Report::default();
+        parse_fsck(
+            &output.lines().take(2).collect::<Vec<_>>().join("\n"),
+            &mut warnings_only,
+        );
+        assert_eq!(warnings_only.warnings.len(), 2);
+        assert!(warnings_only.is_healthy());
+    }
+
+    #[test]
+    fn detect_missing_objects() {
+        require_program!("git");
+
+        let temp = tempfile::tempdir().unwrap();
+        let dest_dir = Path::new("example.com/foo/bar");
+        let repo_dir = temp.path().join(dest_dir);
+        fs::create_dir_all(&repo_dir).unwrap();
+        fs::write(repo_dir.join("README.md"), "Hello, World!").unwrap();
+        git::test_git(&repo_dir, &["init", "--quiet"]);
+        git::test_git(&repo_dir, &["add", "README.md"]);
+        git::test_git(
+            &repo_dir,
+            &["commit", "--quiet", "--message", "Initial commit"],
+        );
+
+        assert!(check(temp.path(), dest_dir).unwrap().is_healthy());
+
+        // the blob for README.md is the only loose blob
+        let output = Command::new("git")
+            .args(&["rev-parse", "HEAD:README.md"])
+            .current_dir(&repo_dir)
+            .output()
+            .unwrap();
+        let blob = String::from_utf8(output.stdout).unwrap();
+        let blob = blob.trim();
+        let object = repo_dir
+            .join(".git/objects")
+            .join(&blob[..2])
+            .join(&blob[2..]);
+        fs::remove_file(object).unwrap();
+
+        let report = check(temp.path(), dest_dir).unwrap();
+
+        assert!(!report.is_healthy());
+        assert_eq!(report.missing, vec![format!("missing blob {}", blob)]);
+    }
+}
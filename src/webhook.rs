@@ -0,0 +1,652 @@
+//! Receiving push webhooks from GitHub, GitLab and Gitea while running as a
+//! daemon, so a repository is backed up as soon as it changes instead of
+//! waiting for the next scheduled backup.
+
+use crate::config::Config;
+use crate::filter::{Decision, Filters};
+use crate::git::{Cancellation, DownloadRepo, GitClone, GitRepo, Metadata};
+use crate::metrics;
+use crate::providers::{GitHubConfig, GitLabConfig, GiteaConfig};
+use actix::{Addr, Arbiter};
+use failure::{Error, ResultExt};
+use futures::future::{self, Future};
+use futures::stream::Stream;
+use hmac::{Hmac, Mac};
+use hyper::header::CONTENT_LENGTH;
+use hyper::service::service_fn;
+use hyper::{Body, HeaderMap, Method, Request, Response, Server, StatusCode};
+use sha2::Sha256;
+use slog::Logger;
+use std::fmt::{self, Display, Formatter};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+
+/// The largest payload we'll accept (GitHub caps them at 25 MB too).
+const MAX_PAYLOAD: usize = 25 * 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebhookConfig {
+    /// Should the daemon listen for webhooks?
+    pub enabled: bool,
+    /// The address to listen on.
+    pub address: String,
+    /// The secret GitHub signs its payloads with.
+    pub github_secret: Option<String>,
+    /// The token GitLab sends with each payload.
+    pub gitlab_token: Option<String>,
+    /// The secret Gitea signs its payloads with.
+    pub gitea_secret: Option<String>,
+}
+
+impl WebhookConfig {
+    pub const DESCRIPTION: &'static str =
+        "Back up a repository as soon as it's pushed to, using webhooks received by \
+         `repo-backup daemon`.";
+    pub const FIELDS: &'static [(&'static str, &'static str)] = &[
+        (
+            "enabled",
+            "Should the daemon listen for push webhooks? GitHub should send them \
+             to /webhook/github, GitLab to /webhook/gitlab and Gitea to \
+             /webhook/gitea.",
+        ),
+        ("address", "The address and port to listen on."),
+        (
+            "github_secret",
+            "The secret GitHub webhooks are signed with. GitHub webhooks are \
+             rejected when this isn't set.",
+        ),
+        (
+            "gitlab_token",
+            "The secret token GitLab webhooks are sent with. GitLab webhooks are \
+             rejected when this isn't set.",
+        ),
+        (
+            "gitea_secret",
+            "The secret Gitea webhooks are signed with. Gitea webhooks are \
+             rejected when this isn't set.",
+        ),
+    ];
+}
+
+impl Default for WebhookConfig {
+    fn default() -> WebhookConfig {
+        WebhookConfig {
+            enabled: false,
+            address: String::from("127.0.0.1:8080"),
+            github_secret: None,
+            gitlab_token: None,
+            gitea_secret: None,
+        }
+    }
+}
+
+/// Where a webhook came from.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) enum Forge {
+    GitHub,
+    GitLab,
+    Gitea,
+}
+
+impl Forge {
+    fn from_path(path: &str) -> Option<Forge> {
+        match path.trim_end_matches('/') {
+            "/webhook/github" => Some(Forge::GitHub),
+            "/webhook/gitlab" => Some(Forge::GitLab),
+            "/webhook/gitea" => Some(Forge::Gitea),
+            _ => None,
+        }
+    }
+}
+
+/// Why a webhook was turned away.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Rejected {
+    Unauthorized(&'static str),
+    BadRequest(String),
+}
+
+impl Rejected {
+    fn status(&self) -> StatusCode {
+        match *self {
+            Rejected::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            Rejected::BadRequest(_) => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+impl Display for Rejected {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match *self {
+            Rejected::Unauthorized(reason) => f.write_str(reason),
+            Rejected::BadRequest(ref reason) => f.write_str(reason),
+        }
+    }
+}
+
+/// Start listening for webhooks on the current `System`, handing pushed
+/// repositories to `gits` and refreshing `metrics` once they're backed up.
+pub(crate) fn listen(
+    cfg: &Config,
+    gits: Addr<GitClone>,
+    metrics: Option<metrics::Cache>,
+    logger: &Logger,
+) -> Result<(), Error> {
+    let webhook = &cfg.general.webhook;
+    let addr: SocketAddr = webhook
+        .address
+        .parse()
+        .with_context(|_| format!("Invalid webhook address, \"{}\"", webhook.address))?;
+
+    let state = Arc::new(Listener {
+        webhook: webhook.clone(),
+        filters: Filters::from_config(cfg)?,
+        gits,
+        metrics,
+        logger: logger.clone(),
+    });
+
+    let l2 = logger.clone();
+    let server = Server::try_bind(&addr)
+        .context("Unable to start the webhook server")?
+        .serve(move || {
+            let state = Arc::clone(&state);
+            service_fn(move |req| respond(&state, req))
+        })
+        .map_err(move |e| {
+            error!(l2, "The webhook server stopped"; "error" => e.to_string());
+        });
+
+    Arbiter::spawn(server);
+    info!(logger, "Listening for webhooks"; "address" => addr.to_string());
+
+    Ok(())
+}
+
+type ResponseFuture = Box<dyn Future<Item = Response<Body>, Error = hyper::Error> + Send>;
+
+struct Listener {
+    webhook: WebhookConfig,
+    filters: Filters,
+    gits: Addr<GitClone>,
+    metrics: Option<metrics::Cache>,
+    logger: Logger,
+}
+
+fn respond(listener: &Arc<Listener>, req: Request<Body>) -> ResponseFuture {
+    let forge = match Forge::from_path(req.uri().path()) {
+        Some(forge) => forge,
+        None => return Box::new(future::ok(reply(StatusCode::NOT_FOUND, "Not Found"))),
+    };
+
+    if req.method() != Method::POST {
+        return Box::new(future::ok(reply(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "Method Not Allowed",
+        )));
+    }
+
+    let too_large = req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok())
+        .and_then(|len| len.parse::<usize>().ok())
+        .map(|len| len > MAX_PAYLOAD)
+        .unwrap_or(false);
+    if too_large {
+        return Box::new(future::ok(reply(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "Payload Too Large",
+        )));
+    }
+
+    let (parts, body) = req.into_parts();
+    let listener = Arc::clone(listener);
+
+    // stop buffering once the payload gets too big, in case the sender
+    // lied about its length
+    let payload = body.fold(Some(Vec::new()), |buffer, chunk| {
+        let buffer = buffer.filter(|b| b.len() + chunk.len() <= MAX_PAYLOAD);
+        Ok::<_, hyper::Error>(buffer.map(|mut b| {
+            b.extend_from_slice(&chunk);
+            b
+        }))
+    });
+
+    Box::new(payload.map(move |payload| match payload {
+        Some(payload) => listener.handle(forge, &parts.headers, &payload),
+        None => reply(StatusCode::PAYLOAD_TOO_LARGE, "Payload Too Large"),
+    }))
+}
+
+impl Listener {
+    fn handle(&self, forge: Forge, headers: &HeaderMap, payload: &[u8]) -> Response<Body> {
+        let repo = match receive(&self.webhook, forge, headers, payload) {
+            Ok(Some(repo)) => repo,
+            Ok(None) => return reply(StatusCode::OK, "Ignored"),
+            Err(rejected) => {
+                warn!(self.logger, "Rejected a webhook";
+                    "forge" => format_args!("{:?}", forge),
+                    "reason" => rejected.to_string());
+                return reply(rejected.status(), &rejected.to_string());
+            }
+        };
+
+        if let Decision::Skip(reason) = self.filters.check(&repo) {
+            info!(self.logger, "Ignoring a pushed repo";
+                "dest-dir" => repo.dest_dir.display(),
+                "reason" => &reason);
+            return reply(StatusCode::OK, &format!("Ignored ({})", reason));
+        }
+
+        info!(self.logger, "Backing up a pushed repo";
+            "dest-dir" => repo.dest_dir.display(),
+            "provider" => &repo.provider);
+
+        let logger = self.logger.clone();
+        let metrics = self.metrics.clone();
+        let dest_dir = repo.dest_dir.clone();
+        Arbiter::spawn(
+            self.gits
+                .send(DownloadRepo(repo, Cancellation::default()))
+                .map_err(|e| failure::format_err!("{}", e))
+                .and_then(|outcome| outcome)
+                .then(move |outcome| {
+                    match outcome {
+                        Ok(downloaded) => {
+                            info!(logger, "Backed up a pushed repo";
+                                "dest-dir" => dest_dir.display());
+
+                            if let Some(reason) = downloaded.mirror_failure {
+                                warn!(logger, "Unable to mirror a pushed repo";
+                                    "dest-dir" => dest_dir.display(),
+                                    "error" => reason);
+                            }
+                        }
+                        Err(e) => warn!(logger, "Unable to back up a pushed repo";
+                            "dest-dir" => dest_dir.display(),
+                            "error" => e.to_string()),
+                    }
+
+                    if let Some(metrics) = metrics {
+                        metrics.refresh();
+                    }
+                    Ok(())
+                }),
+        );
+
+        reply(StatusCode::ACCEPTED, "Queued")
+    }
+}
+
+fn reply(status: StatusCode, message: &str) -> Response<Body> {
+    let mut response = Response::new(Body::from(format!("{}\n", message)));
+    *response.status_mut() = status;
+    response
+}
+
+/// Check a webhook is genuine, returning the repository which was pushed to
+/// (or `None` for events we don't care about).
+pub(crate) fn receive(
+    cfg: &WebhookConfig,
+    forge: Forge,
+    headers: &HeaderMap,
+    payload: &[u8],
+) -> Result<Option<GitRepo>, Rejected> {
+    authenticate(cfg, forge, headers, payload)?;
+
+    let (event, wanted): (_, &[&str]) = match forge {
+        Forge::GitHub => ("x-github-event", &["push"]),
+        Forge::GitLab => ("x-gitlab-event", &["Push Hook", "Tag Push Hook"]),
+        Forge::Gitea => ("x-gitea-event", &["push"]),
+    };
+
+    match header(headers, event) {
+        Some(event) if wanted.contains(&event) => {}
+        // pings and everything else are acknowledged and ignored
+        Some(_) => return Ok(None),
+        None => return Err(Rejected::BadRequest(format!("No {} header", event))),
+    }
+
+    let repo = match forge {
+        Forge::GitHub => serde_json::from_slice::<Push>(payload)
+            .map(|push| push.repository.into_repo("github.com", GitHubConfig::KEY)),
+        Forge::GitLab => serde_json::from_slice::<GitLabPush>(payload).map(GitRepo::from),
+        Forge::Gitea => serde_json::from_slice::<Push>(payload).and_then(|push| {
+            let host = push
+                .repository
+                .host()
+                .ok_or_else(|| serde::de::Error::custom("Unable to find the repository's host"))?;
+            Ok(push.repository.into_repo(&host, GiteaConfig::KEY))
+        }),
+    };
+
+    repo.map(Some)
+        .map_err(|e| Rejected::BadRequest(format!("Invalid payload: {}", e)))
+}
+
+fn authenticate(
+    cfg: &WebhookConfig,
+    forge: Forge,
+    headers: &HeaderMap,
+    payload: &[u8],
+) -> Result<(), Rejected> {
+    let (secret, valid) = match forge {
+        Forge::GitHub => (
+            cfg.github_secret.as_ref(),
+            header(headers, "x-hub-signature-256")
+                .filter(|sig| sig.starts_with("sha256="))
+                .map(|sig| &sig["sha256=".len()..]),
+        ),
+        Forge::GitLab => (cfg.gitlab_token.as_ref(), header(headers, "x-gitlab-token")),
+        Forge::Gitea => (
+            cfg.gitea_secret.as_ref(),
+            header(headers, "x-gitea-signature"),
+        ),
+    };
+
+    let secret = secret.ok_or(Rejected::Unauthorized("Webhooks from here aren't enabled"))?;
+    let valid = valid.ok_or(Rejected::Unauthorized("Missing signature"))?;
+
+    let genuine = match forge {
+        // GitLab sends the token as-is
+        Forge::GitLab => constant_time_eq(secret.as_bytes(), valid.as_bytes()),
+        Forge::GitHub | Forge::Gitea => signed_by(secret, payload, valid),
+    };
+
+    if genuine {
+        Ok(())
+    } else {
+        Err(Rejected::Unauthorized("Invalid signature"))
+    }
+}
+
+/// Is `signature` the hex-encoded HMAC-SHA256 of `payload`?
+fn signed_by(secret: &str, payload: &[u8], signature: &str) -> bool {
+    let signature = match hex::decode(signature) {
+        Ok(signature) => signature,
+        Err(_) => return false,
+    };
+
+    let mut mac =
+        Hmac::<Sha256>::new_varkey(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.input(payload);
+    mac.verify(&signature).is_ok()
+}
+
+fn constant_time_eq(left: &[u8], right: &[u8]) -> bool {
+    left.len() == right.len() && left.iter().zip(right).fold(0, |acc, (l, r)| acc | (l ^ r)) == 0
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+/// The parts of a GitHub or Gitea push we care about.
+#[derive(Debug, Deserialize)]
+struct Push {
+    repository: PushedRepository,
+}
+
+#[derive(Debug, Deserialize)]
+struct PushedRepository {
+    full_name: String,
+    ssh_url: String,
+    html_url: Option<String>,
+    fork: Option<bool>,
+    private: Option<bool>,
+    archived: Option<bool>,
+    size: Option<u64>,
+    language: Option<String>,
+    topics: Option<Vec<String>>,
+}
+
+impl PushedRepository {
+    fn host(&self) -> Option<String> {
+        let url = reqwest::Url::parse(self.html_url.as_ref()?).ok()?;
+        url.host_str().map(String::from)
+    }
+
+    fn into_repo(self, host: &str, provider: &str) -> GitRepo {
+        GitRepo {
+            provider: provider.to_string(),
+            dest_dir: Path::new(host).join(self.full_name),
+            ssh_url: self.ssh_url,
+            wiki: false,
+            metadata: Metadata {
+                fork: self.fork,
+                archived: self.archived,
+                private: self.private,
+                size_kb: self.size,
+                language: self.language,
+                topics: self.topics,
+                description: None,
+                stable_name: None,
+                last_push: None,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct GitLabPush {
+    project: GitLabProject,
+}
+
+#[derive(Debug, Deserialize)]
+struct GitLabProject {
+    path_with_namespace: String,
+    git_ssh_url: String,
+    visibility_level: Option<u32>,
+}
+
+impl From<GitLabPush> for GitRepo {
+    fn from(push: GitLabPush) -> GitRepo {
+        let project = push.project;
+        // the gitlab provider only uses the innermost group, so the push
+        // needs to land in the same place
+        let mut parts = project.path_with_namespace.rsplit('/');
+        let name = parts.next().unwrap_or_default();
+        let namespace = parts.next().unwrap_or_default();
+
+        GitRepo {
+            provider: GitLabConfig::KEY.to_string(),
+            dest_dir: Path::new("gitlab.com").join(namespace).join(name),
+            ssh_url: project.git_ssh_url,
+            wiki: false,
+            metadata: Metadata {
+                private: project.visibility_level.map(|level| level == 0),
+                ..Default::default()
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::{HeaderName, HeaderValue};
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+
+        for &(name, value) in pairs {
+            headers.insert(
+                HeaderName::from_static(name),
+                HeaderValue::from_str(value).unwrap(),
+            );
+        }
+
+        headers
+    }
+
+    fn sign(secret: &str, payload: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_varkey(secret.as_bytes()).unwrap();
+        mac.input(payload);
+        hex::encode(mac.result().code())
+    }
+
+    fn cfg() -> WebhookConfig {
+        WebhookConfig {
+            enabled: true,
+            github_secret: Some(String::from("github-secret")),
+            gitlab_token: Some(String::from("gitlab-token")),
+            gitea_secret: Some(String::from("gitea-secret")),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn accept_signed_github_pushes() {
+        let payload = br#"{
+            "ref": "refs/heads/master",
+            "repository": {
+                "full_name": "Michael-F-Bryan/repo-backup",
+                "ssh_url": "git@github.com:Michael-F-Bryan/repo-backup.git",
+                "html_url": "https://github.com/Michael-F-Bryan/repo-backup",
+                "fork": false,
+                "private": false,
+                "language": "Rust"
+            }
+        }"#;
+        let signature = format!("sha256={}", sign("github-secret", payload));
+
+        let got = receive(
+            &cfg(),
+            Forge::GitHub,
+            &headers(&[
+                ("x-github-event", "push"),
+                ("x-hub-signature-256", &signature),
+            ]),
+            payload,
+        )
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(got.provider, "github");
+        assert_eq!(
+            got.dest_dir,
+            Path::new("github.com/Michael-F-Bryan/repo-backup")
+        );
+        assert_eq!(
+            got.ssh_url,
+            "git@github.com:Michael-F-Bryan/repo-backup.git"
+        );
+        assert_eq!(got.metadata.language.as_ref().unwrap(), "Rust");
+
+        // pings are fine, but don't trigger anything
+        let ping = receive(
+            &cfg(),
+            Forge::GitHub,
+            &headers(&[
+                ("x-github-event", "ping"),
+                ("x-hub-signature-256", &signature),
+            ]),
+            payload,
+        );
+        assert_eq!(ping, Ok(None));
+    }
+
+    #[test]
+    fn reject_webhooks_which_arent_genuine() {
+        let payload = br#"{"repository": {"full_name": "foo/bar", "ssh_url": "git@example.com:foo/bar.git"}}"#;
+        let forged = format!("sha256={}", sign("wrong-secret", payload));
+
+        let got = receive(
+            &cfg(),
+            Forge::GitHub,
+            &headers(&[("x-github-event", "push"), ("x-hub-signature-256", &forged)]),
+            payload,
+        );
+        assert_eq!(got, Err(Rejected::Unauthorized("Invalid signature")));
+
+        let got = receive(
+            &cfg(),
+            Forge::Gitea,
+            &headers(&[("x-gitea-event", "push")]),
+            payload,
+        );
+        assert_eq!(got, Err(Rejected::Unauthorized("Missing signature")));
+
+        let got = receive(
+            &cfg(),
+            Forge::GitLab,
+            &headers(&[
+                ("x-gitlab-event", "Push Hook"),
+                ("x-gitlab-token", "gitlab-tokem"),
+            ]),
+            payload,
+        );
+        assert_eq!(got, Err(Rejected::Unauthorized("Invalid signature")));
+
+        // forges without a secret can't send webhooks at all
+        let no_secrets = WebhookConfig::default();
+        let signature = sign("gitea-secret", payload);
+        let got = receive(
+            &no_secrets,
+            Forge::Gitea,
+            &headers(&[("x-gitea-event", "push"), ("x-gitea-signature", &signature)]),
+            payload,
+        );
+        assert!(got.is_err());
+    }
+
+    #[test]
+    fn parse_gitlab_and_gitea_pushes() {
+        let payload = br#"{
+            "object_kind": "push",
+            "project": {
+                "path_with_namespace": "group/subgroup/project",
+                "git_ssh_url": "git@gitlab.com:group/subgroup/project.git",
+                "visibility_level": 0
+            }
+        }"#;
+
+        let got = receive(
+            &cfg(),
+            Forge::GitLab,
+            &headers(&[
+                ("x-gitlab-event", "Push Hook"),
+                ("x-gitlab-token", "gitlab-token"),
+            ]),
+            payload,
+        )
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(got.provider, "gitlab");
+        assert_eq!(got.dest_dir, Path::new("gitlab.com/subgroup/project"));
+        assert_eq!(got.metadata.private, Some(true));
+
+        let payload = br#"{
+            "repository": {
+                "full_name": "foo/bar",
+                "ssh_url": "git@gitea.example.com:foo/bar.git",
+                "html_url": "https://gitea.example.com/foo/bar"
+            }
+        }"#;
+        let signature = sign("gitea-secret", payload);
+
+        let got = receive(
+            &cfg(),
+            Forge::Gitea,
+            &headers(&[("x-gitea-event", "push"), ("x-gitea-signature", &signature)]),
+            payload,
+        )
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(got.provider, "gitea");
+        assert_eq!(got.dest_dir, Path::new("gitea.example.com/foo/bar"));
+    }
+
+    #[test]
+    fn route_webhooks_by_path() {
+        assert_eq!(Forge::from_path("/webhook/github"), Some(Forge::GitHub));
+        assert_eq!(Forge::from_path("/webhook/gitlab/"), Some(Forge::GitLab));
+        assert_eq!(Forge::from_path("/webhook/gitea"), Some(Forge::Gitea));
+        assert_eq!(Forge::from_path("/"), None);
+    }
+}